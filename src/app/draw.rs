@@ -1,17 +1,22 @@
 mod clickable_progress_bar;
+mod playlists;
+
+use std::{fs, path::Path};
 
 use crate::{
-  app::DecomposerApp,
-  emoji,
-  model::{MsgUiToThread, PlayingState},
+  app::{queue::QueueAction, DecomposerApp},
+  audio, emoji,
+  library::{is_audio_file, ScanProgress},
+  loudness::AnalysisJob,
+  model::{MsgUiToThread, PlayingState, RepeatMode, ShuffleMode, TrackMeta},
   util,
 };
 
 use eframe::{
   egui::{
-    self, Button, CentralPanel, ImageButton, Label, Layout, ProgressBar,
-    RichText, ScrollArea, Slider, TextStyle, TopBottomPanel, Visuals,
-    WidgetText,
+    self, Button, CentralPanel, ComboBox, ImageButton, Key, Label, Layout,
+    Modifiers, ProgressBar, RichText, ScrollArea, Sense, SidePanel, Slider,
+    TextStyle, TopBottomPanel, Vec2, Visuals, WidgetText,
   },
   emath::Align,
   epaint::{vec2, Pos2},
@@ -19,6 +24,15 @@ use eframe::{
 
 use self::clickable_progress_bar::TrackProgressBar;
 
+/// The usual speaker setups, in WAVE order.
+const OUTPUT_CHANNEL_CHOICES: &[(u16, &str)] = &[
+  (1, "Mono"),
+  (2, "Stereo"),
+  (4, "Quad"),
+  (6, "5.1"),
+  (8, "7.1"),
+];
+
 impl DecomposerApp {
   /// Pull this function out into its own file because i like doing that
   pub fn draw(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
@@ -39,6 +53,13 @@ impl DecomposerApp {
         ui.vertical(|ui| self.draw_bottom_bar(ui));
       });
 
+    SidePanel::left("playlists")
+      .resizable(true)
+      .default_width(200.0)
+      .show(ctx, |ui| {
+        self.draw_playlists(ui);
+      });
+
     CentralPanel::default().show(ctx, |ui| {
       self.draw_queue(ui);
     });
@@ -51,6 +72,135 @@ impl DecomposerApp {
     egui::widgets::global_dark_light_mode_switch(ui);
 
     ui.label(concat!("Decomposer v", env!("CARGO_PKG_VERSION")));
+    ui.separator();
+
+    self.draw_output_picker(ui);
+    ui.separator();
+
+    self.draw_scan_status(ui);
+    ui.separator();
+
+    if ui.button("Analyze library loudness").clicked() {
+      for root in self.config.library_roots() {
+        if root.enabled {
+          let files = self.library.files(&root.path);
+          self.analysis.submit(AnalysisJob::Library(files));
+        }
+      }
+    }
+    if let Some(status) = &self.analysis_status {
+      ui.spinner();
+      ui.label(format!(
+        "Analyzing {}/{}: {}",
+        status.index + 1,
+        status.of,
+        status.file_name
+      ));
+      ui.add(
+        ProgressBar::new(status.progress)
+          .desired_width(96.0)
+          .show_percentage(),
+      );
+    }
+  }
+
+  fn draw_scan_status(&mut self, ui: &mut eframe::egui::Ui) {
+    let Some(scan) = &self.library_scan else {
+      if ui.button("Rescan library").clicked() {
+        self.library_scan = Some(
+          self
+            .library
+            .start_scan(self.config.library_roots(), self.config.scan()),
+        );
+      }
+      return;
+    };
+
+    let ScanProgress {
+      seen,
+      parsed,
+      errors,
+    } = scan.progress;
+    ui.spinner();
+    ui.label(format!(
+      "Scanning library: {} found, {} read, {} errors",
+      seen, parsed, errors
+    ));
+    if scan.is_cancelled() {
+      ui.add_enabled(false, Button::new("Cancelling…"));
+    } else if ui.button("Cancel").clicked() {
+      scan.cancel();
+    }
+  }
+
+  fn draw_output_picker(&mut self, ui: &mut eframe::egui::Ui) {
+    let selected_text = match &self.output_device {
+      Some(device) => format!("{} ({}ch)", &device.name, self.output_channels),
+      None => "No output".to_owned(),
+    };
+    let mut picked = None;
+    let mut picked_channels = None;
+    ComboBox::from_id_source("output device")
+      .selected_text(selected_text)
+      .width(160.0)
+      .show_ui(ui, |ui| {
+        if ui
+          .selectable_label(self.config.output_device().is_none(), "Default")
+          .clicked()
+        {
+          picked = Some(None);
+        }
+        for device in &self.output_devices {
+          let text = format!("{} ({})", &device.name, &device.host);
+          let selected = self.config.output_device() == Some(device);
+          if ui.selectable_label(selected, text).clicked() {
+            picked = Some(Some(device.clone()));
+          }
+        }
+      });
+    ComboBox::from_id_source("output channels")
+      .selected_text(match self.config.output_channels() {
+        Some(channels) => format!("{} channels", channels),
+        None => "Device channels".to_owned(),
+      })
+      .show_ui(ui, |ui| {
+        let current = self.config.output_channels();
+        if ui
+          .selectable_label(current.is_none(), "Device channels")
+          .clicked()
+        {
+          picked_channels = Some(None);
+        }
+        for (channels, name) in OUTPUT_CHANNEL_CHOICES {
+          if ui
+            .selectable_label(current == Some(*channels), *name)
+            .clicked()
+          {
+            picked_channels = Some(Some(*channels));
+          }
+        }
+      });
+    if ui
+      .button(emoji::REFRESH)
+      .on_hover_text("Look for output devices again")
+      .clicked()
+    {
+      self.output_devices = audio::list_output_devices();
+    }
+
+    let changed = picked.is_some() || picked_channels.is_some();
+    if let Some(channels) = picked_channels {
+      self.config.set_output_channels(channels);
+    }
+    if let Some(device) = picked {
+      self.config.set_output_device(device);
+    }
+    if changed {
+      self.rebuild_output();
+    }
+    if let Some(error) = &self.output_error {
+      ui.colored_label(ui.visuals().error_fg_color, error);
+    }
   }
 
   // In a vert layout
@@ -58,8 +208,25 @@ impl DecomposerApp {
     ui.add_space(ui.spacing().item_spacing.y * 2.0);
 
     ui.horizontal(|ui| {
-      ui.button(emoji::REPEAT);
-      ui.button(emoji::SHUFFLE);
+      let repeat = self.config.repeat();
+      let res = ui
+        .selectable_label(repeat != RepeatMode::Off, repeat.icon())
+        .on_hover_text(repeat.describe());
+      if res.clicked() {
+        self.set_repeat(repeat.cycle());
+      }
+      let shuffle = self.config.shuffle();
+      let res = ui
+        .selectable_label(shuffle != ShuffleMode::Off, emoji::SHUFFLE)
+        .on_hover_text(match &self.shuffle {
+          Some(state) => {
+            format!("{} (seed {})", shuffle.describe(), state.seed)
+          }
+          None => shuffle.describe().to_owned(),
+        });
+      if res.clicked() {
+        self.set_shuffle(shuffle.cycle());
+      }
       ui.separator();
 
       let (wind_enabled, playpause_label) = match self.now_playing {
@@ -68,8 +235,7 @@ impl DecomposerApp {
         PlayingState::Selected { playing: false, .. } => (true, emoji::PLAYING),
       };
 
-      // Currently no-op
-      ui.add_enabled(wind_enabled, Button::new(emoji::WIND_LEFT));
+      let back = ui.add_enabled(wind_enabled, Button::new(emoji::WIND_LEFT));
 
       if ui.button(playpause_label).clicked() {
         match self.now_playing {
@@ -90,7 +256,14 @@ impl DecomposerApp {
         }
       }
 
-      ui.add_enabled(wind_enabled, Button::new(emoji::WIND_RIGHT));
+      let forward =
+        ui.add_enabled(wind_enabled, Button::new(emoji::WIND_RIGHT));
+      self.wind(
+        back.is_pointer_button_down_on(),
+        forward.is_pointer_button_down_on(),
+        back.clicked(),
+        forward.clicked(),
+      );
 
       // We want the progress bar to just take whatever's remaining in the center
       // so lay out right to left.
@@ -112,12 +285,11 @@ impl DecomposerApp {
         if let PlayingState::Selected { ref track, .. } = self.now_playing {
           let time_base = track.file_info.params.codec_params.time_base;
 
-          let progress =
-            track.playhead as f32 / track.file_info.num_frames as f32;
+          let progress = track.playhead as f32 / track.bounds.len() as f32;
 
           let text = if let Some(timesize) = time_base {
             let here = timesize.calc_time(track.playhead as u64);
-            let end = timesize.calc_time(track.file_info.num_frames as u64);
+            let end = timesize.calc_time(track.bounds.len() as u64);
 
             format!(
               "{}/{}",
@@ -136,8 +308,7 @@ impl DecomposerApp {
             let bar_span = res.rect;
             let x_prop = (mousepos.x - bar_span.left()) / bar_span.width();
 
-            let frames_in =
-              (x_prop * track.file_info.num_frames as f32) as usize;
+            let frames_in = (x_prop * track.bounds.len() as f32) as usize;
             let mouse_time = timesize.calc_time(frames_in as u64);
             let hover = util::format_symphonia_time(mouse_time);
 
@@ -177,12 +348,16 @@ impl DecomposerApp {
         PlayingState::Selected { track, playing } => {
           ui.label("Now playing:");
 
-          ui.label(
-            RichText::new(
-              track.track.path.file_name().unwrap().to_string_lossy(),
-            )
-            .strong(),
-          );
+          let meta = &track.track.meta;
+          ui.label(RichText::new(&meta.title).strong());
+          if let Some(artist) = meta.display_artist() {
+            ui.label("by");
+            ui.label(RichText::new(artist).strong());
+          }
+          if let Some(album) = &meta.album {
+            ui.label("from");
+            ui.label(RichText::new(album).strong());
+          }
         }
       }
 
@@ -193,8 +368,89 @@ impl DecomposerApp {
   }
 
   fn draw_queue(&mut self, ui: &mut eframe::egui::Ui) {
-    let row_count = self.queue.len();
+    let mut action = None;
+    let mut analysis_job = None;
+    let mut add_to_playlist = None;
 
+    ui.horizontal(|ui| {
+      if ui
+        .add_enabled(self.can_undo_queue(), Button::new("Undo"))
+        .clicked()
+      {
+        action = Some(QueueAction::Undo);
+      }
+      if ui
+        .add_enabled(self.can_redo_queue(), Button::new("Redo"))
+        .clicked()
+      {
+        action = Some(QueueAction::Redo);
+      }
+      ui.separator();
+      ui.label(format!("{} queued", self.queue.len()));
+      let selected = self.queue_editor.selected.len();
+      if selected > 0 {
+        ui.label(format!("{} selected", selected));
+      }
+    });
+
+    if !self.history.is_empty() {
+      ui.collapsing(format!("Played ({})", self.history.len()), |ui| {
+        ScrollArea::vertical()
+          .id_source("played")
+          .max_height(160.0)
+          .stick_to_bottom(true)
+          .show_rows(
+            ui,
+            ui.text_style_height(&TextStyle::Body),
+            self.history.len(),
+            |ui, range| {
+              for i in range {
+                let track = &self.history[i];
+                ui.label(RichText::new(queue_row_text(&track.meta)).weak())
+                  .on_hover_text(track.path.display().to_string())
+                  .context_menu(|ui| {
+                    if ui.button("Play again next").clicked() {
+                      action = Some(QueueAction::PlayAgain(i));
+                      ui.close_menu();
+                    }
+                    if ui.button("Clear played").clicked() {
+                      action = Some(QueueAction::ClearPlayed);
+                      ui.close_menu();
+                    }
+                  });
+              }
+            },
+          );
+      });
+    }
+    ui.separator();
+
+    // Nobody wants ctrl-Z in the playlist name box to mess with the queue
+    if !ui.ctx().wants_keyboard_input() {
+      ui.input_mut(|i| {
+        if i.consume_key(Modifiers::COMMAND, Key::Z) {
+          action = Some(QueueAction::Undo);
+        } else if i.consume_key(Modifiers::COMMAND | Modifiers::SHIFT, Key::Z)
+          || i.consume_key(Modifiers::COMMAND, Key::Y)
+        {
+          action = Some(QueueAction::Redo);
+        } else if i.consume_key(Modifiers::NONE, Key::Delete) {
+          action = Some(QueueAction::Remove);
+        }
+      });
+    }
+
+    let modifiers = ui.input(|i| i.modifiers);
+    let pointer = ui.input(|i| i.pointer.hover_pos());
+    let released = ui.input(|i| i.pointer.any_released());
+    if self.queue_editor.dragging.is_some()
+      && ui.input(|i| i.pointer.any_down() && i.pointer.delta() != Vec2::ZERO)
+    {
+      self.queue_editor.drag_moved = true;
+    }
+    let mut drop_at = None;
+
+    let row_count = self.queue.len();
     ScrollArea::vertical()
       .auto_shrink([false, false]) // Add padding inside
       .show_rows(
@@ -210,7 +466,117 @@ impl DecomposerApp {
               let col = ui.style().visuals.faint_bg_color;
               ui.style_mut().visuals.panel_fill = col;
             }
-            ui.label(format!("{}", track.path.display()));
+            let available = self.library.is_available(&track.path);
+            let text = if available {
+              RichText::new(queue_row_text(&track.meta))
+            } else {
+              RichText::new(format!(
+                "{} (unavailable)",
+                queue_row_text(&track.meta)
+              ))
+              .weak()
+            };
+            let selected = self.queue_editor.selected.contains(&i);
+            let res = ui
+              .selectable_label(selected, text)
+              .interact(Sense::drag())
+              .on_hover_text(track.path.display().to_string());
+
+            if res.clicked() {
+              self.queue_editor.click(i, modifiers);
+            }
+            if res.double_clicked() {
+              self.queue_editor.grab(i);
+              action = Some(QueueAction::PlayNow);
+            }
+            if res.secondary_clicked() {
+              self.queue_editor.grab(i);
+            }
+            if res.drag_started() {
+              self.queue_editor.dragging = Some(i);
+              self.queue_editor.drag_moved = false;
+            }
+
+            // Work out where a dragged row would land, and show it
+            if let (Some(_), true, Some(pos)) = (
+              self.queue_editor.dragging,
+              self.queue_editor.drag_moved,
+              pointer,
+            ) {
+              let gap = ui.spacing().item_spacing.y;
+              let rect = res.rect.expand2(vec2(0.0, gap));
+              if rect.y_range().contains(&pos.y) {
+                let (at, y) = if pos.y < res.rect.center().y {
+                  (i, res.rect.top() - gap / 2.0)
+                } else {
+                  (i + 1, res.rect.bottom() + gap / 2.0)
+                };
+                drop_at = Some(at);
+                ui.painter().hline(
+                  ui.max_rect().x_range(),
+                  y,
+                  ui.visuals().selection.stroke,
+                );
+              }
+            }
+
+            res.context_menu(|ui| {
+              if ui.button("Play now").clicked() {
+                action = Some(QueueAction::PlayNow);
+                ui.close_menu();
+              }
+              if ui.button("Play next").clicked() {
+                action = Some(QueueAction::PlayNext);
+                ui.close_menu();
+              }
+              if ui.button("Remove").clicked() {
+                action = Some(QueueAction::Remove);
+                ui.close_menu();
+              }
+              if ui.button("Move to top").clicked() {
+                action = Some(QueueAction::MoveToTop);
+                ui.close_menu();
+              }
+              if ui.button("Move to bottom").clicked() {
+                action = Some(QueueAction::MoveToBottom);
+                ui.close_menu();
+              }
+              ui.separator();
+              if ui.button("Clear queue").clicked() {
+                action = Some(QueueAction::Clear);
+                ui.close_menu();
+              }
+              if ui.button("Clear played").clicked() {
+                action = Some(QueueAction::ClearPlayed);
+                ui.close_menu();
+              }
+              ui.separator();
+              if ui.button("Analyze loudness").clicked() {
+                analysis_job = Some(AnalysisJob::Track(track.path.clone()));
+                ui.close_menu();
+              }
+              if ui.button("Analyze album loudness").clicked() {
+                analysis_job = track.path.parent().map(album_job);
+                ui.close_menu();
+              }
+              ui.separator();
+              ui.menu_button("Add to playlist", |ui| {
+                for (p, playlist) in self.playlists.iter().enumerate() {
+                  if ui.button(&playlist.name).clicked() {
+                    add_to_playlist = Some((p, track.path.clone(), track.span));
+                    ui.close_menu();
+                  }
+                }
+                if ui.button("New playlist").clicked() {
+                  add_to_playlist = Some((
+                    self.playlists.len(),
+                    track.path.clone(),
+                    track.span,
+                  ));
+                  ui.close_menu();
+                }
+              });
+            });
 
             if i != end - 1 {
               ui.separator();
@@ -218,5 +584,63 @@ impl DecomposerApp {
           }
         },
       );
+
+    if released {
+      if let Some(from) = self.queue_editor.dragging.take() {
+        if let (true, Some(to)) = (self.queue_editor.drag_moved, drop_at) {
+          self.queue_editor.grab(from);
+          action = Some(QueueAction::MoveTo(to));
+        }
+      }
+    }
+
+    if let Some(action) = action {
+      self.queue_action(action);
+    }
+    if let Some(job) = analysis_job {
+      self.analysis.submit(job);
+    }
+    if let Some((mut idx, path, span)) = add_to_playlist {
+      if idx >= self.playlists.len() {
+        idx = self.playlists.create("New playlist");
+      }
+      let entry = self.library.playlist_entry(&path, span);
+      if let Some(playlist) = self.playlists.get_mut(idx) {
+        playlist.add([entry]);
+      }
+    }
+  }
+}
+
+/// `3. Title — Artist · Album (4:21)`, leaving out whatever we don't know.
+fn queue_row_text(meta: &TrackMeta) -> String {
+  let mut text = String::new();
+  if let Some(number) = meta.track_number {
+    text.push_str(&format!("{}. ", number));
+  }
+  text.push_str(&meta.title);
+  if let Some(artist) = meta.display_artist() {
+    text.push_str(&format!(" \u{2014} {}", artist));
   }
+  if let Some(album) = &meta.album {
+    text.push_str(&format!(" \u{00B7} {}", album));
+  }
+  if let Some(duration) = meta.duration() {
+    text.push_str(&format!(" ({})", util::format_symphonia_time(duration)));
+  }
+  text
+}
+
+/// Everything directly in the folder counts as the album.
+fn album_job(folder: &Path) -> AnalysisJob {
+  // Same as the library has it
+  let folder = folder.canonicalize().unwrap_or_else(|_| folder.to_owned());
+  let mut tracks = fs::read_dir(&folder)
+    .into_iter()
+    .flatten()
+    .filter_map(|entry| Some(entry.ok()?.path()))
+    .filter(|path| path.is_file() && is_audio_file(path))
+    .collect::<Vec<_>>();
+  tracks.sort();
+  AnalysisJob::Album { folder, tracks }
 }
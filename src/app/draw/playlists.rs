@@ -0,0 +1,200 @@
+//! The sidebar for picking and editing playlists.
+
+use std::path::PathBuf;
+
+use eframe::egui::{self, Button, Layout, ScrollArea, TextEdit};
+use eframe::emath::Align;
+
+use crate::{app::DecomposerApp, playlist_io};
+
+/// Something done to the open playlist, saved up until we're done drawing
+/// it.
+enum PlaylistEdit {
+  MoveUp(usize),
+  MoveDown(usize),
+  Remove(usize),
+}
+
+impl DecomposerApp {
+  pub(super) fn draw_playlists(&mut self, ui: &mut egui::Ui) {
+    ui.horizontal(|ui| {
+      ui.heading("Playlists");
+      ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+        if ui.button("New").clicked() {
+          let idx = self.playlists.create("New playlist");
+          self.select_playlist(Some(idx));
+        }
+      });
+    });
+    ui.separator();
+
+    let mut picked = None;
+    for (i, playlist) in self.playlists.iter().enumerate() {
+      let selected = self.selected_playlist == Some(i);
+      let text = format!("{} ({})", &playlist.name, playlist.tracks.len());
+      if ui.selectable_label(selected, text).clicked() {
+        picked = Some(if selected { None } else { Some(i) });
+      }
+    }
+    if let Some(picked) = picked {
+      self.select_playlist(picked);
+    }
+
+    ui.separator();
+    self.draw_playlist_io(ui);
+
+    let Some(idx) = self
+      .selected_playlist
+      .filter(|idx| *idx < self.playlists.len())
+    else {
+      return;
+    };
+    ui.separator();
+
+    let res = ui.add(TextEdit::singleline(&mut self.playlist_name));
+    if res.lost_focus() {
+      self.playlists.rename(idx, self.playlist_name.trim());
+      if let Some(playlist) = self.playlists.get(idx) {
+        self.playlist_name = playlist.name.clone();
+      }
+    }
+
+    ui.horizontal(|ui| {
+      if ui.button("Replace queue").clicked() {
+        self.load_playlist(idx, true);
+      }
+      if ui.button("Append to queue").clicked() {
+        self.load_playlist(idx, false);
+      }
+      if ui.button("Delete").clicked() {
+        self.playlists.delete(idx);
+        self.select_playlist(None);
+      }
+    });
+    ui.separator();
+
+    let Some(playlist) = self.playlists.get(idx) else {
+      return;
+    };
+    let count = playlist.tracks.len();
+    let mut edit = None;
+    ScrollArea::vertical()
+      .id_source("playlist tracks")
+      .auto_shrink([false, false])
+      .show(ui, |ui| {
+        for (i, entry) in playlist.tracks.iter().enumerate() {
+          ui.horizontal(|ui| {
+            if ui.add_enabled(i > 0, Button::new("^")).clicked() {
+              edit = Some(PlaylistEdit::MoveUp(i));
+            }
+            if ui.add_enabled(i + 1 < count, Button::new("v")).clicked() {
+              edit = Some(PlaylistEdit::MoveDown(i));
+            }
+            if ui.button("x").clicked() {
+              edit = Some(PlaylistEdit::Remove(i));
+            }
+            let title = match self.library.meta(entry) {
+              Some(meta) => meta.title,
+              None => entry
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            };
+            ui.label(title)
+              .on_hover_text(entry.path.display().to_string());
+          });
+        }
+      });
+
+    if let (Some(edit), Some(playlist)) = (edit, self.playlists.get_mut(idx)) {
+      match edit {
+        PlaylistEdit::MoveUp(i) => playlist.reorder(i, i - 1),
+        PlaylistEdit::MoveDown(i) => playlist.reorder(i, i + 1),
+        PlaylistEdit::Remove(i) => playlist.remove(i),
+      }
+    }
+  }
+
+  /// Importing and exporting other players' playlist files.
+  fn draw_playlist_io(&mut self, ui: &mut egui::Ui) {
+    ui.add(
+      TextEdit::singleline(&mut self.playlist_io_path)
+        .hint_text(".m3u, .m3u8, .pls or .xspf file"),
+    );
+    ui.horizontal(|ui| {
+      if ui.button("Import").clicked() {
+        self.import_playlist();
+      }
+      let can_export = self.selected_playlist.is_some();
+      if ui.add_enabled(can_export, Button::new("Export")).clicked() {
+        self.export_playlist();
+      }
+    });
+
+    if self.playlist_io_report.is_empty() {
+      return;
+    }
+    let mut dismiss = false;
+    ui.horizontal(|ui| {
+      ui.label(&self.playlist_io_report[0]);
+      dismiss = ui.small_button("x").clicked();
+    });
+    if self.playlist_io_report.len() > 1 {
+      egui::CollapsingHeader::new("Not found")
+        .id_source("playlist io unresolved")
+        .show(ui, |ui| {
+          for line in &self.playlist_io_report[1..] {
+            ui.label(line);
+          }
+        });
+    }
+    if dismiss {
+      self.playlist_io_report.clear();
+    }
+  }
+
+  fn import_playlist(&mut self) {
+    let path = PathBuf::from(self.playlist_io_path.trim());
+    self.playlist_io_report = match playlist_io::import(&path, &self.library) {
+      Ok(imported) => {
+        let found = imported.playlist.tracks.len();
+        let name = imported.playlist.name.clone();
+        let idx = self.playlists.insert(imported.playlist);
+        self.select_playlist(Some(idx));
+        let mut report = vec![format!(
+          "Imported {} tracks into {:?}, {} not found",
+          found,
+          name,
+          imported.unresolved.len()
+        )];
+        report.extend(imported.unresolved);
+        report
+      }
+      Err(err) => vec![format!("Import failed: {:#}", err)],
+    };
+  }
+
+  fn export_playlist(&mut self) {
+    let Some(playlist) = self
+      .selected_playlist
+      .and_then(|idx| self.playlists.get(idx))
+    else {
+      return;
+    };
+    let path = PathBuf::from(self.playlist_io_path.trim());
+    let msg = match playlist_io::export(playlist, &path, &self.library) {
+      Ok(()) => format!("Exported {:?} to {:?}", &playlist.name, &path),
+      Err(err) => format!("Export failed: {:#}", err),
+    };
+    self.playlist_io_report = vec![msg];
+  }
+
+  fn select_playlist(&mut self, idx: Option<usize>) {
+    self.selected_playlist = idx;
+    self.playlist_name = idx
+      .and_then(|idx| self.playlists.get(idx))
+      .map(|playlist| playlist.name.clone())
+      .unwrap_or_default();
+  }
+}
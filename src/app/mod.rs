@@ -1,39 +1,114 @@
 mod draw;
+mod queue;
+mod session;
 mod update;
 
-use std::collections::VecDeque;
+use std::{
+  collections::VecDeque,
+  sync::mpsc::{self, Receiver, Sender},
+  time::Instant,
+};
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::StreamError;
 use eframe::{egui, App, CreationContext, Storage};
-use log::error;
+use log::{error, warn};
 use rtrb::{Consumer, Producer, RingBuffer};
 
+use self::queue::QueueEditor;
+
 use crate::{
-  audio::{self, DecomposerAudioDaemont},
+  audio::{self, OutputDeviceChoice},
+  library::{LibraryIndex, LibraryScan, LibraryWatcher},
+  loudness::{LoudnessCache, LoudnessWorker},
   model::{
-    CurrentlyPlayingTrack, MsgThreadToUi, MsgUiToThread, PlayingState, Track,
+    fresh_seed, CurrentlyPlayingTrack, MsgThreadToUi, MsgUiToThread,
+    PlayingState, Playlists, Session, ShuffleState, Track,
+  },
+  settings::{
+    DecomposerConfig, CONFIG_LOCATION_KEY, LIBRARY_INDEX_NAME,
+    LOUDNESS_CACHE_NAME, PLAYLISTS_NAME, SESSION_NAME,
   },
-  settings::{DecomposerConfig, CONFIG_LOCATION_KEY},
-  util,
 };
 
 pub type AppPlayingState = PlayingState<CurrentlyPlayingTrack>;
 
 const BUFFERING_COOLDOWN: u32 = 10;
+/// How many played tracks to remember for going back.
+const HISTORY_LEN: usize = 500;
 
 pub struct DecomposerApp {
   tx_to_thread: Producer<MsgUiToThread>,
   rx_from_thread: Consumer<MsgThreadToUi>,
+  /// None if we couldn't open any device at all
   #[allow(dead_code)]
-  raii_stream: cpal::Stream,
+  raii_stream: Option<cpal::Stream>,
+  /// What we actually ended up playing out of
+  output_device: Option<OutputDeviceChoice>,
+  output_channels: u16,
+  /// Cached so we don't ask the OS every frame
+  output_devices: Vec<OutputDeviceChoice>,
+  output_error: Option<String>,
+  tx_stream_errors: Sender<StreamError>,
+  rx_stream_errors: Receiver<StreamError>,
 
   queue: VecDeque<Track>,
+  /// Set while the queue is shuffled
+  shuffle: Option<ShuffleState>,
+  /// What's been played, the most recent last
+  history: VecDeque<Track>,
+  queue_editor: QueueEditor,
+  /// Set while one of the wind buttons is down
+  wind_hold: Option<WindHold>,
   now_playing: AppPlayingState,
+  /// The front of the queue, already sent to the audio thread to play after
+  /// `now_playing`
+  up_next: Option<CurrentlyPlayingTrack>,
   buffering_cooldown: u32,
 
+  library: LibraryIndex,
+  /// The scan that's going on, if there is one
+  library_scan: Option<LibraryScan>,
+  /// The queue is what was left from last time, so until the first scan is
+  /// done only files it's never seen before get added to it
+  queue_from_session: bool,
+  /// None if watching couldn't be set up, in which case changes are only
+  /// picked up at startup
+  library_watcher: Option<LibraryWatcher>,
+  playlists: Playlists,
+  /// Which one's open in the sidebar
+  selected_playlist: Option<usize>,
+  /// Name of the selected playlist, while it's being edited
+  playlist_name: String,
+  /// Where to import a playlist from or export one to
+  playlist_io_path: String,
+  /// How the last import or export went
+  playlist_io_report: Vec<String>,
+  loudness: LoudnessCache,
+  analysis: LoudnessWorker,
+  analysis_status: Option<AnalysisStatus>,
+
   config: DecomposerConfig,
 }
 
+/// One of the wind buttons being held down.
+struct WindHold {
+  forward: bool,
+  since: Instant,
+  last_frame: Instant,
+  last_seek: Instant,
+  /// Where we've scrubbed to, in frames from the start of the track. It gets
+  /// ahead of the playhead between seeks
+  pos: f64,
+}
+
+/// What the loudness worker is up to, for the top bar.
+struct AnalysisStatus {
+  file_name: String,
+  index: usize,
+  of: usize,
+  progress: f32,
+}
+
 impl DecomposerApp {
   /// Init the app.
   /// Also spin up the audio context.
@@ -55,56 +130,94 @@ impl DecomposerApp {
       storage.get_string(CONFIG_LOCATION_KEY).as_deref(),
     )?;
 
-    let (tx_to_thread, rx_from_ui) = RingBuffer::new(64);
-    let (tx_to_ui, rx_from_thread) = RingBuffer::new(256);
-
-    let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
+    // For now!
 
-    let sample_rate = device.default_output_config().unwrap().sample_rate();
-    let audio_cfg = cpal::StreamConfig {
-      channels: audio::OUTPUT_CHANNEL_COUNT as u16,
-      sample_rate,
-      buffer_size: cpal::BufferSize::Default,
+    let mut library =
+      LibraryIndex::open(&config.sibling_location(LIBRARY_INDEX_NAME));
+    // Start with what we knew last time; the scan fills in the rest as it
+    // goes
+    let library_scan =
+      library.start_scan(config.library_roots(), config.scan());
+    // Whatever was going on last time takes the place of the whole library
+    let session = Session::open(&config.sibling_location(SESSION_NAME))
+      .filter(|it| !it.is_empty());
+    let tracks = match session {
+      Some(_) => Vec::new(),
+      None => library.tracks(config.library_roots()),
     };
+    let (queue, shuffle) =
+      ShuffleState::start(tracks, config.shuffle(), fresh_seed());
+    let queue = VecDeque::from(queue);
 
-    let mut looks_like_youre_going_to_the_shadow_thread_jimbo =
-      DecomposerAudioDaemont::new(tx_to_ui, rx_from_ui, &config);
-
-    let stream = device
-      .build_output_stream(
-        &audio_cfg,
-        move |data: &mut [f32], ci| {
-          looks_like_youre_going_to_the_shadow_thread_jimbo.process(data, ci);
-        },
-        move |err| {
-          error!("{}", err);
-        },
-        None,
-      )
-      .unwrap();
-    stream.play().unwrap();
-
-    // For now!
+    let loudness =
+      LoudnessCache::open(&config.sibling_location(LOUDNESS_CACHE_NAME));
+    let playlists = Playlists::open(&config.sibling_location(PLAYLISTS_NAME));
+    let watched = library_scan.roots().to_vec();
+    let library_watcher = match library.watch(watched, config.scan()) {
+      Ok(it) => Some(it),
+      Err(err) => {
+        warn!("Not watching the library for changes: {:#}", err);
+        None
+      }
+    };
 
-    let queue = util::get_all_children(&config.library_root())
-      .map(|path| Track { path })
-      .collect();
+    let (tx_stream_errors, rx_stream_errors) = mpsc::channel();
+    let (tx_to_thread, rx_from_thread) = disconnected_pipes();
 
-    Ok(DecomposerApp {
+    let mut app = DecomposerApp {
       config,
       queue,
+      shuffle,
+      history: VecDeque::new(),
+      queue_editor: QueueEditor::default(),
+      wind_hold: None,
+      library,
+      library_scan: Some(library_scan),
+      queue_from_session: false,
+      library_watcher,
+      playlists,
+      selected_playlist: None,
+      playlist_name: String::new(),
+      playlist_io_path: String::new(),
+      playlist_io_report: Vec::new(),
+
+      loudness,
+      analysis: LoudnessWorker::spawn()?,
+      analysis_status: None,
 
       tx_to_thread,
       rx_from_thread,
-      raii_stream: stream,
+      raii_stream: None,
+      output_device: None,
+      output_channels: 0,
+      output_devices: audio::list_output_devices(),
+      output_error: None,
+      tx_stream_errors,
+      rx_stream_errors,
 
       now_playing: PlayingState::Stopped,
+      up_next: None,
       buffering_cooldown: 0,
-    })
+    };
+    // Not having anything to play out of isn't fatal, you can still pick
+    // something once it's plugged in
+    app.rebuild_output();
+    if let Some(session) = session {
+      app.restore_session(session);
+    }
+    Ok(app)
   }
 }
 
+/// Pipes with no audio thread on the other end, for when there's no device.
+///
+/// Pushing into these just does nothing once they fill up.
+fn disconnected_pipes() -> (Producer<MsgUiToThread>, Consumer<MsgThreadToUi>) {
+  let (tx, _) = RingBuffer::new(64);
+  let (_, rx) = RingBuffer::new(1);
+  (tx, rx)
+}
+
 impl eframe::App for DecomposerApp {
   fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
     self.update();
@@ -118,6 +231,16 @@ impl eframe::App for DecomposerApp {
     );
 
     self.config.save();
+    self
+      .loudness
+      .save(&self.config.sibling_location(LOUDNESS_CACHE_NAME));
+    self
+      .library
+      .save(&self.config.sibling_location(LIBRARY_INDEX_NAME));
+    self
+      .playlists
+      .save(&self.config.sibling_location(PLAYLISTS_NAME));
+    self.save_session();
   }
 
   fn persist_native_window(&self) -> bool {
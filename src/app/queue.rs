@@ -0,0 +1,447 @@
+//! Editing the queue by hand, with undo for when it goes wrong.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use eframe::egui::Modifiers;
+
+use crate::model::Track;
+
+use super::DecomposerApp;
+
+/// How many edits can be undone.
+const UNDO_LEN: usize = 100;
+
+/// Something to do to the queue from the ui.
+///
+/// Most of these go by whatever's selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueAction {
+  /// Play the first selected one right away, with the rest after it
+  PlayNow,
+  PlayNext,
+  Remove,
+  MoveToTop,
+  MoveToBottom,
+  /// Where in the queue as it is now, so `0` is the top and the queue's
+  /// length is the bottom
+  MoveTo(usize),
+  /// Put a copy of this played track back at the top of the queue
+  PlayAgain(usize),
+  Clear,
+  ClearPlayed,
+  Undo,
+  Redo,
+}
+
+/// One change, with enough in it to go either way.
+#[derive(Debug)]
+enum QueueEdit {
+  Queue {
+    /// Where tracks came out of the queue from before, top to bottom
+    removed: Vec<(usize, Track)>,
+    /// Where tracks went into the queue from after, top to bottom
+    inserted: Vec<(usize, Track)>,
+  },
+  /// The play history from before it got cleared. Empty until it's done
+  ClearPlayed(VecDeque<Track>),
+}
+
+/// What's selected, and what can be undone.
+#[derive(Debug, Default)]
+pub struct QueueEditor {
+  pub selected: BTreeSet<usize>,
+  /// Where shift-clicking selects from
+  anchor: Option<usize>,
+  /// The most recent at the back
+  undo: VecDeque<QueueEdit>,
+  redo: Vec<QueueEdit>,
+  /// The row being dragged, if one is
+  pub dragging: Option<usize>,
+  /// Whether the drag has actually gone anywhere, as opposed to being the
+  /// start of a click
+  pub drag_moved: bool,
+}
+
+impl QueueEdit {
+  fn apply(
+    &mut self,
+    queue: &mut VecDeque<Track>,
+    played: &mut VecDeque<Track>,
+  ) {
+    match self {
+      QueueEdit::Queue { removed, inserted } => {
+        for (i, _) in removed.iter().rev() {
+          queue.remove(*i);
+        }
+        for (i, track) in inserted.iter() {
+          queue.insert(*i, track.clone());
+        }
+      }
+      QueueEdit::ClearPlayed(saved) => *saved = std::mem::take(played),
+    }
+  }
+
+  fn revert(
+    &mut self,
+    queue: &mut VecDeque<Track>,
+    played: &mut VecDeque<Track>,
+  ) {
+    match self {
+      QueueEdit::Queue { removed, inserted } => {
+        for (i, _) in inserted.iter().rev() {
+          queue.remove(*i);
+        }
+        for (i, track) in removed.iter() {
+          queue.insert(*i, track.clone());
+        }
+      }
+      QueueEdit::ClearPlayed(saved) => {
+        // Anything played since goes after what was there before
+        let since = std::mem::take(played);
+        *played = std::mem::take(saved);
+        played.extend(since);
+      }
+    }
+  }
+
+  /// Keep up with the top of the queue going off to be played.
+  ///
+  /// Returns false if this edit had a hand in what was at the top, so it
+  /// can't be undone any more.
+  fn follow_pop_front(&mut self) -> bool {
+    let QueueEdit::Queue { removed, inserted } = self else {
+      return true;
+    };
+    if removed.iter().chain(inserted.iter()).any(|(i, _)| *i == 0) {
+      return false;
+    }
+    for (i, _) in removed.iter_mut().chain(inserted.iter_mut()) {
+      *i -= 1;
+    }
+    true
+  }
+}
+
+impl QueueEditor {
+  /// Do what a click on a row does, going by the modifier keys.
+  pub fn click(&mut self, row: usize, modifiers: Modifiers) {
+    if modifiers.shift {
+      let from = self.anchor.unwrap_or(row);
+      if !modifiers.command {
+        self.selected.clear();
+      }
+      self.selected.extend(from.min(row)..=from.max(row));
+      return;
+    }
+    if modifiers.command {
+      if !self.selected.remove(&row) {
+        self.selected.insert(row);
+      }
+    } else {
+      self.selected.clear();
+      self.selected.insert(row);
+    }
+    self.anchor = Some(row);
+  }
+
+  /// Make sure a row is selected before doing something to it, without
+  /// losing the rest of the selection if it's part of it.
+  pub fn grab(&mut self, row: usize) {
+    if !self.selected.contains(&row) {
+      self.selected.clear();
+      self.selected.insert(row);
+      self.anchor = Some(row);
+    }
+  }
+
+  /// The queue changed in a way that can't be followed, like the library
+  /// changing underneath it.
+  pub fn forget(&mut self) {
+    self.selected.clear();
+    self.anchor = None;
+    self
+      .undo
+      .retain(|edit| matches!(edit, QueueEdit::ClearPlayed(_)));
+    self
+      .redo
+      .retain(|edit| matches!(edit, QueueEdit::ClearPlayed(_)));
+    self.dragging = None;
+  }
+
+  /// Keep an edit that's just been done around to be undone.
+  fn record(&mut self, edit: QueueEdit) {
+    if self.undo.len() >= UNDO_LEN {
+      self.undo.pop_front();
+    }
+    self.undo.push_back(edit);
+    self.redo.clear();
+  }
+
+  fn follow_pop_front(&mut self) {
+    self.selected = self
+      .selected
+      .iter()
+      .filter_map(|i| i.checked_sub(1))
+      .collect();
+    self.anchor = self.anchor.and_then(|i| i.checked_sub(1));
+    self.dragging = self.dragging.and_then(|i| i.checked_sub(1));
+
+    // Anything older than an edit that can't be undone can't be either
+    if let Some(stuck) = self
+      .undo
+      .iter_mut()
+      .rposition(|edit| !edit.follow_pop_front())
+    {
+      self.undo.drain(..=stuck);
+    }
+    if let Some(stuck) = self
+      .redo
+      .iter_mut()
+      .rposition(|edit| !edit.follow_pop_front())
+    {
+      self.redo.drain(..=stuck);
+    }
+  }
+}
+
+impl DecomposerApp {
+  pub fn queue_action(&mut self, action: QueueAction) {
+    let selected = self.queue_editor.selected.iter().copied().collect();
+    match action {
+      QueueAction::PlayNow => {
+        if self.queue_editor.selected.is_empty() {
+          return;
+        }
+        self.move_queue_rows(selected, 0);
+        self.skip_next();
+      }
+      QueueAction::PlayNext | QueueAction::MoveToTop => {
+        self.move_queue_rows(selected, 0)
+      }
+      QueueAction::MoveToBottom => {
+        self.move_queue_rows(selected, self.queue.len())
+      }
+      QueueAction::MoveTo(to) => self.move_queue_rows(selected, to),
+      QueueAction::Remove => self.remove_queue_rows(selected),
+      QueueAction::PlayAgain(idx) => {
+        let Some(track) = self.history.get(idx) else {
+          return;
+        };
+        self.edit_queue(QueueEdit::Queue {
+          removed: Vec::new(),
+          inserted: vec![(0, track.clone())],
+        });
+      }
+      QueueAction::Clear => {
+        self.remove_queue_rows((0..self.queue.len()).collect())
+      }
+      QueueAction::ClearPlayed => {
+        if !self.history.is_empty() {
+          self.edit_queue(QueueEdit::ClearPlayed(VecDeque::new()));
+        }
+      }
+      QueueAction::Undo => {
+        let Some(mut edit) = self.queue_editor.undo.pop_back() else {
+          return;
+        };
+        edit.revert(&mut self.queue, &mut self.history);
+        self.select_edited(&edit, true);
+        self.queue_editor.redo.push(edit);
+        self.refresh_up_next();
+      }
+      QueueAction::Redo => {
+        let Some(mut edit) = self.queue_editor.redo.pop() else {
+          return;
+        };
+        edit.apply(&mut self.queue, &mut self.history);
+        self.select_edited(&edit, false);
+        self.queue_editor.undo.push_back(edit);
+        self.refresh_up_next();
+      }
+    }
+  }
+
+  pub fn can_undo_queue(&self) -> bool {
+    !self.queue_editor.undo.is_empty()
+  }
+
+  pub fn can_redo_queue(&self) -> bool {
+    !self.queue_editor.redo.is_empty()
+  }
+
+  /// Take the top of the queue off to be played, keeping the selection and
+  /// undo history lined up with what's left.
+  pub(super) fn pop_queue_front(&mut self) -> Option<Track> {
+    let track = self.queue.pop_front()?;
+    self.queue_editor.follow_pop_front();
+    Some(track)
+  }
+
+  /// `rows` in order, to go in at `to` in the queue as it is now.
+  fn move_queue_rows(&mut self, rows: Vec<usize>, to: usize) {
+    if rows.is_empty() {
+      return;
+    }
+    // Where `to` ends up once the rows above it are taken out
+    let to = to - rows.iter().filter(|i| **i < to).count();
+    let removed = rows
+      .iter()
+      .map(|i| (*i, self.queue[*i].clone()))
+      .collect::<Vec<_>>();
+    let inserted = removed
+      .iter()
+      .enumerate()
+      .map(|(n, (_, track))| (to + n, track.clone()))
+      .collect::<Vec<_>>();
+    // Dropping a block of rows right back where it was
+    if removed.iter().zip(&inserted).all(|((a, _), (b, _))| a == b) {
+      return;
+    }
+    self.edit_queue(QueueEdit::Queue { removed, inserted });
+  }
+
+  fn remove_queue_rows(&mut self, rows: Vec<usize>) {
+    if rows.is_empty() {
+      return;
+    }
+    let removed = rows
+      .into_iter()
+      .map(|i| (i, self.queue[i].clone()))
+      .collect();
+    self.edit_queue(QueueEdit::Queue {
+      removed,
+      inserted: Vec::new(),
+    });
+  }
+
+  fn edit_queue(&mut self, mut edit: QueueEdit) {
+    edit.apply(&mut self.queue, &mut self.history);
+    self.select_edited(&edit, false);
+
+    self.queue_editor.record(edit);
+    self.refresh_up_next();
+  }
+
+  /// Select whatever an edit just put in, so it's easy to see where things
+  /// went.
+  fn select_edited(&mut self, edit: &QueueEdit, reverted: bool) {
+    let QueueEdit::Queue { removed, inserted } = edit else {
+      return;
+    };
+    let landed = if reverted { removed } else { inserted };
+    let editor = &mut self.queue_editor;
+    editor.selected = landed.iter().map(|(i, _)| *i).collect();
+    editor.anchor = editor.selected.first().copied();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use crate::model::TrackMeta;
+
+  use super::*;
+
+  fn track(name: &str) -> Track {
+    Track {
+      path: PathBuf::from(format!("/music/{}.flac", name)),
+      meta: TrackMeta {
+        title: name.to_owned(),
+        ..Default::default()
+      },
+      span: None,
+    }
+  }
+
+  fn tracks(names: &str) -> VecDeque<Track> {
+    names.split(' ').map(track).collect()
+  }
+
+  fn names(tracks: &VecDeque<Track>) -> String {
+    let names = tracks.iter().map(|t| t.meta.title.as_str());
+    names.collect::<Vec<_>>().join(" ")
+  }
+
+  /// What moving `b` and `d` to the top looks like.
+  fn move_up() -> QueueEdit {
+    QueueEdit::Queue {
+      removed: vec![(1, track("b")), (3, track("d"))],
+      inserted: vec![(0, track("b")), (1, track("d"))],
+    }
+  }
+
+  #[test]
+  fn edits_apply_and_revert() {
+    let mut queue = tracks("a b c d e");
+    let mut played = tracks("x y");
+
+    let mut edit = move_up();
+    edit.apply(&mut queue, &mut played);
+    assert_eq!(names(&queue), "b d a c e");
+    edit.revert(&mut queue, &mut played);
+    assert_eq!(names(&queue), "a b c d e");
+
+    let mut edit = QueueEdit::ClearPlayed(VecDeque::new());
+    edit.apply(&mut queue, &mut played);
+    assert!(played.is_empty());
+    played.push_back(track("z"));
+    edit.revert(&mut queue, &mut played);
+    assert_eq!(names(&played), "x y z");
+    assert_eq!(names(&queue), "a b c d e");
+  }
+
+  #[test]
+  fn undo_only_goes_back_so_far() {
+    let mut editor = QueueEditor::default();
+    for _ in 0..UNDO_LEN + 20 {
+      editor.record(move_up());
+    }
+    assert_eq!(editor.undo.len(), UNDO_LEN);
+
+    // Anything new means the redos don't make sense any more
+    editor.redo.push(editor.undo.pop_back().unwrap());
+    editor.record(move_up());
+    assert!(editor.redo.is_empty());
+    assert_eq!(editor.undo.len(), UNDO_LEN);
+  }
+
+  #[test]
+  fn edits_follow_the_top_being_played() {
+    let mut edit = QueueEdit::Queue {
+      removed: vec![(2, track("c"))],
+      inserted: vec![(4, track("c"))],
+    };
+    assert!(edit.follow_pop_front());
+    let QueueEdit::Queue { removed, inserted } = &edit else {
+      unreachable!();
+    };
+    assert_eq!((removed[0].0, inserted[0].0), (1, 3));
+
+    // It put the one that's playing now there
+    assert!(!move_up().follow_pop_front());
+    assert!(QueueEdit::ClearPlayed(VecDeque::new()).follow_pop_front());
+  }
+
+  #[test]
+  fn editor_drops_edits_that_cant_be_undone() {
+    let mut editor = QueueEditor::default();
+    editor.record(QueueEdit::Queue {
+      removed: vec![(3, track("d"))],
+      inserted: Vec::new(),
+    });
+    editor.record(move_up());
+    editor.record(QueueEdit::Queue {
+      removed: vec![(2, track("c"))],
+      inserted: vec![(4, track("c"))],
+    });
+    editor.selected = BTreeSet::from([0, 2, 3]);
+    editor.anchor = Some(2);
+
+    editor.follow_pop_front();
+    // The one at the top and everything before it are gone
+    assert_eq!(editor.undo.len(), 1);
+    assert_eq!(editor.selected, BTreeSet::from([1, 2]));
+    assert_eq!(editor.anchor, Some(1));
+  }
+}
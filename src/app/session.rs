@@ -0,0 +1,87 @@
+//! Saving what's playing and what's queued, and bringing it back next time.
+
+use log::{info, warn};
+
+use crate::{
+  model::{
+    PlaylistEntry, SavedTrack, Session, ShuffleMode, ShuffleState, Track,
+  },
+  settings::SESSION_NAME,
+};
+
+use super::{AppPlayingState, DecomposerApp};
+
+impl DecomposerApp {
+  pub(super) fn save_session(&self) {
+    let entry =
+      |track: &Track| self.library.playlist_entry(&track.path, track.span);
+    let now_playing = match &self.now_playing {
+      AppPlayingState::Selected { track, playing } => Some(SavedTrack {
+        entry: entry(&track.track),
+        playhead: track.playhead,
+        playing: *playing,
+      }),
+      AppPlayingState::Stopped => None,
+    };
+    let session = Session {
+      queue: self.queue.iter().map(entry).collect(),
+      history: self.history.iter().map(entry).collect(),
+      now_playing,
+      unshuffled: self.shuffle.as_ref().map(|state| {
+        (state.seed, state.original().iter().map(entry).collect())
+      }),
+    };
+    session.save(&self.config.sibling_location(SESSION_NAME));
+  }
+
+  /// Put everything back how it was, but paused.
+  ///
+  /// Tracks that can't be found any more are left out.
+  pub(super) fn restore_session(&mut self, session: Session) {
+    self.queue_from_session = true;
+    self.queue = self.resolve_all(&session.queue).into();
+    self.history = self.resolve_all(&session.history).into();
+    self.shuffle = match session.unshuffled {
+      Some((seed, original)) if self.config.shuffle() != ShuffleMode::Off => {
+        Some(ShuffleState::resume(seed, self.resolve_all(&original)))
+      }
+      _ => None,
+    };
+
+    let Some(saved) = session.now_playing else {
+      return;
+    };
+    let Some(track) = self.resolve_all(&[saved.entry]).pop() else {
+      return;
+    };
+    if !self.library.is_available(&track.path) {
+      // Leave it where it'll get played if it comes back
+      self.queue.push_front(track);
+      return;
+    }
+    if self.raii_stream.is_none() {
+      // Keep it around to play once there's somewhere to play it
+      self.queue.push_front(track);
+      return;
+    }
+    info!(
+      "Picking up {:?} where it was left, {}",
+      &track.path,
+      if saved.playing { "playing" } else { "paused" }
+    );
+    self.start_track_at(track, saved.playhead, false);
+  }
+
+  fn resolve_all(&self, entries: &[PlaylistEntry]) -> Vec<Track> {
+    entries
+      .iter()
+      .filter_map(|entry| {
+        let track = self.library.resolve(entry);
+        if track.is_none() {
+          warn!("Could not find {:?} from last time", &entry.path);
+        }
+        track
+      })
+      .collect()
+  }
+}
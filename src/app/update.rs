@@ -1,12 +1,40 @@
+use std::{
+  collections::{HashSet, VecDeque},
+  time::{Duration, Instant},
+};
+
 use creek::{
   Decoder, ReadDiskStream, ReadStreamOptions, SeekMode, SymphoniaDecoder,
 };
 use log::{debug, error, info, warn};
 use symphonia::core::{formats::FormatReader, meta::MetadataReader};
 
-use crate::model::{CurrentlyPlayingTrack, MsgThreadToUi, MsgUiToThread};
+use cpal::StreamError;
+
+use crate::{
+  audio,
+  library::LibraryChanges,
+  loudness::AnalysisMsg,
+  model::{
+    fresh_seed, CurrentlyPlayingTrack, MsgThreadToUi, MsgUiToThread,
+    RepeatMode, ShuffleMode, ShuffleState, Track, TrackBounds,
+  },
+};
+
+use super::{
+  disconnected_pipes, AnalysisStatus, AppPlayingState, DecomposerApp, WindHold,
+  BUFFERING_COOLDOWN, HISTORY_LEN,
+};
 
-use super::{AppPlayingState, DecomposerApp, BUFFERING_COOLDOWN};
+/// Going back any later than this restarts the track instead.
+const RESTART_WITHIN: Duration = Duration::from_secs(3);
+/// Holding a wind button longer than this scrubs instead of skipping.
+const SCRUB_AFTER: Duration = Duration::from_millis(400);
+/// How many seconds of track go by per second of holding, to start with.
+const SCRUB_SPEED: f64 = 4.0;
+const SCRUB_MAX_SPEED: f64 = 64.0;
+/// Seeking the stream every frame would have it buffering forever.
+const SCRUB_SEEK_EVERY: Duration = Duration::from_millis(100);
 
 impl DecomposerApp {
   pub fn update(&mut self) {
@@ -17,13 +45,183 @@ impl DecomposerApp {
     if self.buffering_cooldown > 0 {
       self.buffering_cooldown -= 1;
     }
+
+    let analysis_msgs = self.analysis.poll().collect::<Vec<_>>();
+    for msg in analysis_msgs {
+      self.take_analysis_message(msg);
+    }
+
+    if let Some(scan) = self.library_scan.as_mut() {
+      let changes = self.library.poll_scan(scan);
+      let finished = scan.is_finished();
+      self.take_library_changes(changes);
+      if finished {
+        self.library_scan = None;
+        self.queue_from_session = false;
+      }
+    }
+
+    let batches = match &self.library_watcher {
+      Some(watcher) => watcher.poll().collect::<Vec<_>>(),
+      None => Vec::new(),
+    };
+    for batch in batches {
+      let changes = self.library.apply(batch);
+      self.take_library_changes(changes);
+    }
+
+    let stream_errors = self.rx_stream_errors.try_iter().collect::<Vec<_>>();
+    let mut lost_device = false;
+    for err in stream_errors {
+      lost_device |= matches!(err, StreamError::DeviceNotAvailable);
+      self.output_error = Some(err.to_string());
+    }
+    if lost_device {
+      // The configured device is gone so this falls back to the default.
+      // The config keeps the old choice so it comes back next time it's
+      // plugged in
+      warn!("Output device went away, trying another one");
+      let error = self.output_error.take();
+      self.rebuild_output();
+      if self.output_error.is_none() {
+        self.output_error = error;
+      }
+    }
+  }
+
+  /// Tear down the audio thread and start it back up on the configured
+  /// output device, picking up the current track where it was.
+  pub fn rebuild_output(&mut self) {
+    let resume = match &self.now_playing {
+      AppPlayingState::Selected { track, playing } => {
+        Some((track.track.clone(), track.playhead, *playing))
+      }
+      AppPlayingState::Stopped => None,
+    };
+    // Drop the old stream first so the device is free in case it's the same
+    // one
+    self.raii_stream = None;
+    self.up_next = None;
+    self.now_playing = AppPlayingState::Stopped;
+    self.buffering_cooldown = 0;
+
+    match audio::open_output(
+      &self.config,
+      self.config.output_device(),
+      self.tx_stream_errors.clone(),
+    ) {
+      Ok(output) => {
+        self.tx_to_thread = output.tx_to_thread;
+        self.rx_from_thread = output.rx_from_thread;
+        self.raii_stream = Some(output.stream);
+        self.output_device = Some(output.device);
+        self.output_channels = output.channels;
+        self.output_error = None;
+      }
+      Err(err) => {
+        error!("Could not open audio output: {:?}", err);
+        (self.tx_to_thread, self.rx_from_thread) = disconnected_pipes();
+        self.output_device = None;
+        self.output_error = Some(err.to_string());
+        // Keep it around to play once there's somewhere to play it
+        if let Some((track, ..)) = resume {
+          self.queue.push_front(track);
+          self.queue_editor.forget();
+        }
+        return;
+      }
+    }
+    // Anything the old thread said is stale now
+    while self.rx_stream_errors.try_recv().is_ok() {}
+    // The new thread starts out not looping
+    let looping = self.config.repeat() == RepeatMode::One;
+    let _ignore = self.tx_to_thread.push(MsgUiToThread::SetLooping(looping));
+
+    if let Some((track, playhead, playing)) = resume {
+      self.start_track_at(track, playhead, playing);
+    }
+  }
+
+  /// Play a track starting from partway in, instead of from the queue.
+  pub(super) fn start_track_at(
+    &mut self,
+    track: Track,
+    playhead: usize,
+    playing: bool,
+  ) {
+    let Some((track, stream)) = open_track(track, playhead) else {
+      return;
+    };
+    let gain = self.gain_for(&track, None, self.queue.front());
+    let _ignore = self.tx_to_thread.push(MsgUiToThread::StartNewTrack {
+      stream,
+      bounds: track.bounds,
+      gain,
+      playing,
+    });
+    self.now_playing = AppPlayingState::Selected { playing, track };
+    self.send_up_next();
+  }
+
+  fn take_analysis_message(&mut self, msg: AnalysisMsg) {
+    match msg {
+      AnalysisMsg::Started { path, index, of } => {
+        self.analysis_status = Some(AnalysisStatus {
+          file_name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+          index,
+          of,
+          progress: 0.0,
+        });
+      }
+      AnalysisMsg::Progress(progress) => {
+        if let Some(status) = self.analysis_status.as_mut() {
+          status.progress = progress;
+        }
+      }
+      AnalysisMsg::TrackDone { path, report } => {
+        self.loudness.insert_track(path, report);
+      }
+      AnalysisMsg::AlbumDone { folder, report } => {
+        self.loudness.insert_album(folder, report);
+      }
+      AnalysisMsg::Failed { path, error } => {
+        warn!("Could not analyze loudness of {:?}: {}", path, error);
+      }
+      AnalysisMsg::Idle => {
+        self.analysis_status = None;
+      }
+    }
   }
 
   fn take_message(&mut self, msg: MsgThreadToUi) {
     debug!("Recv message on ui thread: {:?}", &msg);
     match msg {
       MsgThreadToUi::FinishedTrack => {
-        self.deque_and_send_track();
+        // If something's already on its way over, the audio thread will
+        // start it as soon as it gets it
+        if self.up_next.is_none() {
+          self.finish_current();
+          if !self.deque_and_send_track() {
+            self.now_playing = AppPlayingState::Stopped;
+          }
+        }
+      }
+      MsgThreadToUi::StartedNext => {
+        if let Some(track) = self.up_next.take() {
+          self.finish_current();
+          // This has been sitting at the front of the queue until now
+          self.pop_queue_front();
+          self.now_playing = AppPlayingState::Selected {
+            playing: true,
+            track,
+          };
+        } else {
+          warn!("audio thread started the next track but we never sent one");
+        }
+        self.send_up_next();
       }
       MsgThreadToUi::PlayheadPos(pos) => {
         if let AppPlayingState::Selected { ref mut track, .. } =
@@ -43,54 +241,516 @@ impl DecomposerApp {
     }
   }
 
-  pub fn deque_and_send_track(&mut self) {
-    while let Some(track) = self.queue.pop_front() {
-      // I don't have that functionality so i will just have 1 cache
-      let opts = ReadStreamOptions {
-        num_cache_blocks: 20,
-        num_caches: 1,
-        ..Default::default()
-      };
+  pub fn set_repeat(&mut self, repeat: RepeatMode) {
+    self.config.set_repeat(repeat);
+    let looping = repeat == RepeatMode::One;
+    let _ignore = self.tx_to_thread.push(MsgUiToThread::SetLooping(looping));
+  }
 
-      let mut stream =
-        match ReadDiskStream::<SymphoniaDecoder>::new(&track.path, 0, opts) {
-          Ok(it) => it,
-          Err(err) => {
-            error!("Could not load file at {:?}: {}", &track.path, err);
-            continue;
-          }
-        };
-
-      // Cache frame 0 at cache index 0
-      let _ignore = stream.cache(0, 0);
-      if let Err(ono) = stream.seek(0, SeekMode::Auto) {
-        error!(
-          "Some kind of fascinating seeking error when sending {:?}: {}",
-          &track.path, ono
-        );
-        continue;
+  /// Shuffle the queue a different way, or put it back how it was.
+  pub fn set_shuffle(&mut self, mode: ShuffleMode) {
+    self.config.set_shuffle(mode);
+    let mut queue = self.queue.drain(..).collect::<Vec<_>>();
+    // Going from one kind of shuffle to another starts from the original
+    // order, not from the last shuffle
+    if let Some(state) = self.shuffle.take() {
+      queue = state.restore(queue);
+    }
+    let (queue, state) = ShuffleState::start(queue, mode, fresh_seed());
+    self.queue = queue.into();
+    self.shuffle = state;
+    self.queue_editor.forget();
+    self.refresh_up_next();
+  }
+
+  /// Remember the current track as played, and with repeat-all on, put it
+  /// back on the end of the queue.
+  fn finish_current(&mut self) {
+    let AppPlayingState::Selected { track, .. } = &self.now_playing else {
+      return;
+    };
+    finish_track(
+      &track.track,
+      self.config.repeat(),
+      &mut self.history,
+      &mut self.queue,
+    );
+  }
+
+  /// Go straight on to the next thing in the queue, or stop if there isn't
+  /// anything.
+  pub fn skip_next(&mut self) {
+    self.finish_current();
+    if !self.deque_and_send_track() {
+      let _ignore = self.tx_to_thread.push(MsgUiToThread::Stop);
+      self.now_playing = AppPlayingState::Stopped;
+    }
+  }
+
+  /// Go back to the start of the track, or to the one before if we're
+  /// already near the start.
+  pub fn skip_previous(&mut self) {
+    let AppPlayingState::Selected { track, .. } = &mut self.now_playing else {
+      return;
+    };
+    let secs_in = track
+      .file_info
+      .sample_rate
+      .map_or(0.0, |rate| track.playhead as f64 / rate as f64);
+    let prev = match self.history.pop_back() {
+      Some(prev) if secs_in <= RESTART_WITHIN.as_secs_f64() => prev,
+      prev => {
+        // Put it back for next time
+        self.history.extend(prev);
+        track.playhead = 0;
+        let _ignore = self.tx_to_thread.push(MsgUiToThread::SeekTo(0));
+        return;
+      }
+    };
+
+    let current = track.track.clone();
+    // With repeat-all it went on the end when it finished, and it's about to
+    // be played again anyway
+    let requeued = self
+      .queue
+      .back()
+      .is_some_and(|back| back.path == prev.path && back.span == prev.span);
+    if self.config.repeat() == RepeatMode::All && requeued {
+      self.queue.pop_back();
+    }
+    self.queue.push_front(current);
+    self.queue.push_front(prev);
+    self.queue_editor.forget();
+    self.deque_and_send_track();
+  }
+
+  /// Call every frame with whether each wind button is down and whether it
+  /// was clicked. A click skips; holding one down scrubs, faster the longer
+  /// it's held.
+  pub fn wind(
+    &mut self,
+    back_down: bool,
+    forward_down: bool,
+    back_clicked: bool,
+    forward_clicked: bool,
+  ) {
+    // Letting go after a scrub counts as a click too
+    if self.scrub(back_down, forward_down) {
+      return;
+    }
+    if back_clicked {
+      self.skip_previous();
+    }
+    if forward_clicked {
+      self.skip_next();
+    }
+  }
+
+  /// Keep scrubbing while a wind button is held down. Returns whether one
+  /// that was scrubbing just got let go.
+  fn scrub(&mut self, back_down: bool, forward_down: bool) -> bool {
+    let now = Instant::now();
+    let down = match (back_down, forward_down) {
+      (true, false) => Some(false),
+      (false, true) => Some(true),
+      // Both at once or neither
+      _ => None,
+    };
+
+    let Some(hold) = self.wind_hold.as_mut() else {
+      if let (Some(forward), AppPlayingState::Selected { track, .. }) =
+        (down, &self.now_playing)
+      {
+        self.wind_hold = Some(WindHold {
+          forward,
+          since: now,
+          last_frame: now,
+          last_seek: now,
+          pos: track.playhead as f64,
+        });
       }
+      return false;
+    };
+
+    let held = now.duration_since(hold.since);
+    if down != Some(hold.forward) {
+      // Let go. If it was only a quick press, the click takes care of it
+      let hold = self.wind_hold.take().unwrap();
+      let scrubbed = held >= SCRUB_AFTER;
+      if scrubbed {
+        self.seek_scrub(hold.pos);
+      }
+      return scrubbed;
+    }
+
+    let dt = now.duration_since(hold.last_frame).as_secs_f64();
+    hold.last_frame = now;
+    if held < SCRUB_AFTER {
+      return false;
+    }
+    let AppPlayingState::Selected { track, .. } = &self.now_playing else {
+      self.wind_hold = None;
+      return false;
+    };
+    let Some(rate) = track.file_info.sample_rate else {
+      return false;
+    };
+    // Doubles every second it's held
+    let scrubbed = (held - SCRUB_AFTER).as_secs_f64();
+    let speed = (SCRUB_SPEED * 2f64.powf(scrubbed)).min(SCRUB_MAX_SPEED);
+    let step = speed * dt * rate as f64;
+    let step = if hold.forward { step } else { -step };
+    // Stop just short of the end, so it doesn't run off into the next track
+    // while it's still held
+    let last = track.bounds.len().saturating_sub(1) as f64;
+    hold.pos = (hold.pos + step).clamp(0.0, last);
+
+    if now.duration_since(hold.last_seek) >= SCRUB_SEEK_EVERY {
+      hold.last_seek = now;
+      let pos = hold.pos;
+      self.seek_scrub(pos);
+    }
+    false
+  }
+
+  fn seek_scrub(&mut self, pos: f64) {
+    let AppPlayingState::Selected { track, .. } = &mut self.now_playing else {
+      return;
+    };
+    // Don't wait for the audio thread to say where it is
+    track.playhead = pos as usize;
+    let _ignore = self.tx_to_thread.push(MsgUiToThread::SeekTo(pos as usize));
+  }
 
-      let info = stream.info().clone();
-      info!(
-        "Sending {:?} to audio thread:\n{:?}",
-        track.path.display(),
-        &info.params.metadata
-      );
+  /// Start playing the front of the queue.
+  ///
+  /// Returns whether anything actually started.
+  pub fn deque_and_send_track(&mut self) -> bool {
+    // Anything lined up after the old track is no longer next
+    if self.up_next.take().is_some() {
+      let _ignore = self.tx_to_thread.push(MsgUiToThread::ClearNext);
+    }
 
+    while let Some(track) = self.pop_queue_front() {
+      // Don't go poking at a drive that isn't there; dead network mounts
+      // can hang for ages
+      if !self.library.is_available(&track.path) {
+        continue;
+      }
+      let Some((track, stream)) = open_track(track, 0) else {
+        continue;
+      };
+      let prev = match &self.now_playing {
+        AppPlayingState::Selected { track, .. } => Some(track),
+        AppPlayingState::Stopped => None,
+      };
+      let gain = self.gain_for(&track, prev, self.queue.front());
+      let bounds = track.bounds;
       self.now_playing = AppPlayingState::Selected {
         playing: true,
-        track: CurrentlyPlayingTrack {
-          track,
-          playhead: 0,
-          file_info: info,
-        },
+        track,
       };
-      let _ignore =
-        self.tx_to_thread.push(MsgUiToThread::StartNewTrack(stream));
+      let _ignore = self.tx_to_thread.push(MsgUiToThread::StartNewTrack {
+        stream,
+        bounds,
+        gain,
+        playing: true,
+      });
 
+      self.send_up_next();
       // and done!
+      return true;
+    }
+    false
+  }
+
+  /// Open the track at the front of the queue and hand it to the audio thread
+  /// ahead of time, so it can go straight into it without a gap.
+  ///
+  /// It stays in the queue until the audio thread actually starts it.
+  pub fn send_up_next(&mut self) {
+    if self.up_next.is_some() {
+      return;
+    }
+    while let Some(track) = self.queue.front() {
+      if !self.library.is_available(&track.path) {
+        self.pop_queue_front();
+        continue;
+      }
+      let Some((track, stream)) = open_track(track.clone(), 0) else {
+        self.pop_queue_front();
+        continue;
+      };
+      let now = match &self.now_playing {
+        AppPlayingState::Selected { track, .. } => Some(track),
+        AppPlayingState::Stopped => None,
+      };
+      // Crossfading inside an album smears things that were meant to be
+      // gapless
+      let crossfade = now.is_some_and(|now| !now.is_same_album(&track));
+      let gain = self.gain_for(&track, now, self.queue.get(1));
+      let sent = self.tx_to_thread.push(MsgUiToThread::EnqueueNext {
+        stream,
+        bounds: track.bounds,
+        crossfade,
+        gain,
+      });
+      // If it didn't get there, it'll be started the slow way once the
+      // current one finishes
+      if sent.is_ok() {
+        self.up_next = Some(track);
+      }
+      return;
+    }
+  }
+}
+
+impl DecomposerApp {
+  /// Bring the queue up to date with files changing underneath it.
+  ///
+  /// Changed files keep their place in the queue; new ones go on the end, or
+  /// somewhere random if it's shuffled.
+  fn take_library_changes(&mut self, changes: LibraryChanges) {
+    self.loudness.follow_moves(&changes.moves);
+    self.playlists.follow_moves(&changes.moves);
+    if changes.tracks.is_empty()
+      && changes.removed.is_empty()
+      && changes.moves.is_empty()
+    {
+      return;
+    }
+    self.queue_editor.forget();
+
+    let mut removed = changes.removed.iter().collect::<HashSet<_>>();
+    for (from, to) in &changes.moves {
+      // A scan might have already put it in under its new name
+      if self.queue.iter().any(|track| track.path == *to) {
+        removed.insert(from);
+        continue;
+      }
+      for track in self.queue.iter_mut().filter(|t| t.path == *from) {
+        track.path = to.clone();
+      }
+    }
+    if !removed.is_empty() {
+      self.queue.retain(|track| !removed.contains(&track.path));
+    }
+
+    // A first scan can turn up tens of thousands of files, so don't go
+    // looking through the queue for every one of them
+    let queued = self
+      .queue
+      .iter()
+      .map(|track| track.path.clone())
+      .collect::<HashSet<_>>();
+    let mut arrived = Vec::new();
+    for (path, tracks) in changes.tracks {
+      if !queued.contains(&path) {
+        // Everything else was already in the library last time, so if it's
+        // not in the queue it was played or taken out
+        if !self.queue_from_session || changes.new.contains(&path) {
+          arrived.extend(tracks);
+        }
+        continue;
+      }
+      let Some(at) = self.queue.iter().position(|track| track.path == path)
+      else {
+        continue;
+      };
+      self.queue.retain(|track| track.path != path);
+      for (i, track) in tracks.into_iter().enumerate() {
+        self.queue.insert(at + i, track);
+      }
+    }
+    match self.shuffle.as_mut() {
+      Some(state) => state.add(&mut self.queue, arrived, self.config.shuffle()),
+      None => self.queue.extend(arrived),
+    }
+
+    self.refresh_up_next();
+  }
+
+  /// Make sure what the audio thread has lined up is still the front of the
+  /// queue, after the queue's been messed with.
+  pub(super) fn refresh_up_next(&mut self) {
+    let front_moved = match (&self.up_next, self.queue.front()) {
+      (Some(next), Some(front)) => {
+        next.track.path != front.path || next.track.span != front.span
+      }
+      (Some(_), None) => true,
+      (None, _) => false,
+    };
+    if front_moved {
+      self.up_next = None;
+      let _ignore = self.tx_to_thread.push(MsgUiToThread::ClearNext);
+      self.send_up_next();
+    }
+  }
+
+  /// Put a playlist's tracks in the queue, either instead of what's there or
+  /// after it.
+  pub fn load_playlist(&mut self, idx: usize, replace: bool) {
+    let Some(playlist) = self.playlists.get(idx) else {
       return;
+    };
+    let mut tracks = Vec::with_capacity(playlist.tracks.len());
+    for entry in &playlist.tracks {
+      match self.library.resolve(entry) {
+        Some(track) => tracks.push(track),
+        None => warn!(
+          "Could not find {:?} from playlist {:?}",
+          &entry.path, &playlist.name
+        ),
+      }
+    }
+
+    if replace {
+      // Whatever was lined up came from the old queue
+      if self.up_next.take().is_some() {
+        let _ignore = self.tx_to_thread.push(MsgUiToThread::ClearNext);
+      }
+      self.queue.clear();
+    }
+    self.queue.extend(tracks);
+    self.queue_editor.forget();
+    if let AppPlayingState::Selected { .. } = self.now_playing {
+      self.send_up_next();
+    }
+  }
+
+  /// Work out the normalization gain for a track that's about to play.
+  fn gain_for(
+    &self,
+    track: &CurrentlyPlayingTrack,
+    prev: Option<&CurrentlyPlayingTrack>,
+    next: Option<&Track>,
+  ) -> f32 {
+    self
+      .loudness
+      .gain_for(self.config.replay_gain(), track, prev, next)
+  }
+}
+
+/// Where a track goes once it's done. See `DecomposerApp::finish_current`.
+fn finish_track(
+  track: &Track,
+  repeat: RepeatMode,
+  history: &mut VecDeque<Track>,
+  queue: &mut VecDeque<Track>,
+) {
+  if history.len() >= HISTORY_LEN {
+    history.pop_front();
+  }
+  history.push_back(track.clone());
+  if repeat == RepeatMode::All {
+    queue.push_back(track.clone());
+  }
+}
+
+/// Open a track and get it ready to play from `start_frame`.
+fn open_track(
+  track: Track,
+  start_frame: usize,
+) -> Option<(CurrentlyPlayingTrack, ReadDiskStream<SymphoniaDecoder>)> {
+  // I don't have that functionality so i will just have 1 cache
+  let opts = ReadStreamOptions {
+    num_cache_blocks: 20,
+    num_caches: 1,
+    ..Default::default()
+  };
+
+  let mut stream =
+    match ReadDiskStream::<SymphoniaDecoder>::new(&track.path, 0, opts) {
+      Ok(it) => it,
+      Err(err) => {
+        error!("Could not load file at {:?}: {}", &track.path, err);
+        return None;
+      }
+    };
+
+  let info = stream.info().clone();
+  // Without a rate there's no telling where a span is, so play it all
+  let bounds = match info.sample_rate {
+    Some(rate) => track.bounds(rate, info.num_frames),
+    None => TrackBounds {
+      start: 0,
+      end: info.num_frames,
+    },
+  };
+
+  // Cache the start of the track at cache index 0
+  let _ignore = stream.cache(0, bounds.start);
+  let start_frame = start_frame.min(bounds.len());
+  if let Err(ono) = stream.seek(bounds.start + start_frame, SeekMode::Auto) {
+    error!(
+      "Some kind of fascinating seeking error when sending {:?}: {}",
+      &track.path, ono
+    );
+    return None;
+  }
+
+  let info = stream.info().clone();
+  info!(
+    "Sending {:?} to audio thread:\n{:?}",
+    track.path.display(),
+    &info.params.metadata
+  );
+
+  let track = CurrentlyPlayingTrack {
+    track,
+    playhead: start_frame,
+    bounds,
+    file_info: info,
+  };
+  Some((track, stream))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use crate::model::TrackMeta;
+
+  use super::*;
+
+  fn track(name: &str) -> Track {
+    Track {
+      path: PathBuf::from(format!("/music/{}.flac", name)),
+      meta: TrackMeta {
+        title: name.to_owned(),
+        ..Default::default()
+      },
+      span: None,
+    }
+  }
+
+  fn names(tracks: &VecDeque<Track>) -> Vec<&str> {
+    tracks.iter().map(|t| t.meta.title.as_str()).collect()
+  }
+
+  #[test]
+  fn finished_tracks_go_where_repeat_says() {
+    for repeat in [RepeatMode::Off, RepeatMode::One, RepeatMode::All] {
+      let mut history = VecDeque::from([track("a")]);
+      let mut queue = VecDeque::from([track("c")]);
+      finish_track(&track("b"), repeat, &mut history, &mut queue);
+      assert_eq!(names(&history), ["a", "b"], "{:?}", repeat);
+      // Repeating one is the audio thread looping, so once it's done it's
+      // done like any other
+      let expected = match repeat {
+        RepeatMode::All => vec!["c", "b"],
+        _ => vec!["c"],
+      };
+      assert_eq!(names(&queue), expected, "{:?}", repeat);
     }
   }
+
+  #[test]
+  fn history_only_goes_back_so_far() {
+    let mut history = (0..HISTORY_LEN).map(|_| track("old")).collect();
+    let mut queue = VecDeque::new();
+    finish_track(&track("new"), RepeatMode::Off, &mut history, &mut queue);
+    assert_eq!(history.len(), HISTORY_LEN);
+    assert_eq!(history.back().unwrap().meta.title, "new");
+  }
 }
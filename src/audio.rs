@@ -1,16 +1,33 @@
 //! The processing that lives on the cpal audio thread.
 
+mod chain;
+mod crossfade;
+mod downmix;
+mod limiter;
+mod offline;
+mod output;
+mod resample;
+
+pub use chain::*;
+pub use crossfade::*;
+pub use downmix::*;
+pub use limiter::*;
+pub use offline::*;
+pub use output::*;
+pub use resample::*;
+
 use cpal::OutputCallbackInfo;
 use creek::{ReadDiskStream, SeekMode, SymphoniaDecoder};
 use log::{debug, error, info};
 use rtrb::{Consumer, Producer};
 
 use crate::{
-  model::{MsgThreadToUi, MsgUiToThread, PlayingState},
+  model::{MsgThreadToUi, MsgUiToThread, PlayingState, TrackBounds},
   settings::DecomposerConfig,
 };
 
-pub const OUTPUT_CHANNEL_COUNT: usize = 2;
+/// Don't ask creek for more than this many frames at once.
+const MAX_READ_FRAMES: usize = 2048;
 
 type ThreadPlayingState = PlayingState<ReadDiskStream<SymphoniaDecoder>>;
 type CreekError = creek::read::ReadError<symphonia::core::errors::Error>;
@@ -18,12 +35,32 @@ type CreekError = creek::read::ReadError<symphonia::core::errors::Error>;
 /// The struct that goes and lives on the audio thread.
 pub struct DecomposerAudioDaemont {
   playback_state: ThreadPlayingState,
+  /// Stream to switch to the moment the current one runs out
+  next: Option<NextStream>,
+  /// The last track, if we're crossfading out of it
+  fade: Option<Fade>,
+  /// Which part of the current stream is the track
+  bounds: TrackBounds,
   looping: bool,
+  /// See `set_blocking`
+  blocking: bool,
 
   tx_to_ui: Producer<MsgThreadToUi>,
   rx_from_ui: Consumer<MsgUiToThread>,
 
   volume: f32,
+  /// Normalization gain for the current track
+  gain: f32,
+  crossfade: CrossfadeSettings,
+  device_sample_rate: u32,
+  device_channels: usize,
+  chain: SignalChain,
+  /// For the outgoing track during a crossfade
+  fade_chain: SignalChain,
+  /// The outgoing track's frames during a crossfade
+  fade_scratch: Vec<f32>,
+  /// Catches whatever the downmix and gain push over full scale
+  limiter: PeakLimiter,
 }
 
 /// It's like a daemon, but it's not
@@ -32,24 +69,51 @@ impl DecomposerAudioDaemont {
     tx_to_ui: Producer<MsgThreadToUi>,
     rx_from_ui: Consumer<MsgUiToThread>,
     config: &DecomposerConfig,
+    device_sample_rate: u32,
+    device_channels: usize,
   ) -> Self {
     Self {
       tx_to_ui,
       rx_from_ui,
 
       playback_state: ThreadPlayingState::Stopped,
+      next: None,
+      fade: None,
+      bounds: TrackBounds { start: 0, end: 0 },
       looping: false,
+      blocking: false,
 
       volume: config.copy_volume(),
+      gain: 1.0,
+      crossfade: *config.crossfade(),
+      device_sample_rate,
+      device_channels,
+      chain: SignalChain::new(config, device_sample_rate, device_channels),
+      fade_chain: SignalChain::new(config, device_sample_rate, device_channels),
+      fade_scratch: Vec::new(),
+      limiter: PeakLimiter::new(),
     }
   }
 
   pub fn process(&mut self, data: &mut [f32], _callback: &OutputCallbackInfo) {
+    self.render(data);
+  }
+
+  /// Don't output silence while creek catches up, just wait for it.
+  ///
+  /// Never turn this on for a real device; it's for rendering offline where
+  /// it matters more that the output is the same every time.
+  pub fn set_blocking(&mut self, blocking: bool) {
+    self.blocking = blocking;
+  }
+
+  /// Fill `data` with interleaved frames, handling any messages first.
+  pub fn render(&mut self, data: &mut [f32]) {
     while let Ok(msg) = self.rx_from_ui.pop() {
       self.take_msg(msg);
     }
 
-    let res = self.finagle_audio_state(data, _callback);
+    let res = self.finagle_audio_state(data);
     if let Err(err) = res {
       if let creek::read::ReadError::EndOfFile = err {
         // Welp we're done here onto the next pls
@@ -63,18 +127,68 @@ impl DecomposerAudioDaemont {
       }
       self.playback_state = ThreadPlayingState::Stopped;
     }
+
+    self.limiter.process(data, self.device_channels);
+  }
+
+  /// Switch to the stream that was sent with `EnqueueNext` right away.
+  fn start_next(&mut self, next: NextStream) {
+    self.chain.configure_for(next.stream.info());
+    self.chain.reset();
+    self.gain = next.gain;
+    self.bounds = next.bounds;
+    self.playback_state = ThreadPlayingState::Selected {
+      track: next.stream,
+      playing: true,
+    };
+    let _ignore = self.tx_to_ui.push(MsgThreadToUi::StartedNext);
   }
 
   fn take_msg(&mut self, msg: MsgUiToThread) {
     debug!("Recv message on audio thread: {:?}", &msg);
 
     match msg {
-      MsgUiToThread::StartNewTrack(stream) => {
+      MsgUiToThread::StartNewTrack {
+        stream,
+        bounds,
+        gain,
+        playing,
+      } => {
+        self.gain = gain;
+        self.bounds = bounds;
+        self.chain.configure_for(stream.info());
+        // Whatever was in there belongs to the old track
+        self.chain.reset();
+        self.next = None;
+        self.fade = None;
         self.playback_state = ThreadPlayingState::Selected {
           track: stream,
-          playing: true,
+          playing,
+        }
+      }
+      MsgUiToThread::EnqueueNext {
+        stream,
+        bounds,
+        crossfade,
+        gain,
+      } => {
+        let next = NextStream {
+          stream,
+          bounds,
+          crossfade,
+          gain,
+        };
+        if let ThreadPlayingState::Stopped = self.playback_state {
+          // We ran out before this got here, so no point in waiting
+          info!("Got the next track after finishing the last one");
+          self.start_next(next);
+        } else {
+          self.next = Some(next);
         }
       }
+      MsgUiToThread::ClearNext => {
+        self.next = None;
+      }
       MsgUiToThread::Resume => {
         if let ThreadPlayingState::Selected {
           ref mut playing, ..
@@ -93,13 +207,20 @@ impl DecomposerAudioDaemont {
       }
       MsgUiToThread::Stop => {
         self.playback_state = ThreadPlayingState::Stopped;
+        self.next = None;
+        self.fade = None;
       }
 
       MsgUiToThread::SeekTo(pos) => {
         if let ThreadPlayingState::Selected { ref mut track, .. } =
           self.playback_state
         {
+          // The ui only knows about the track, not the whole file
+          let pos = (self.bounds.start + pos).min(self.bounds.end);
           let _ignore = track.seek(pos, creek::SeekMode::Auto);
+          self.chain.reset();
+          // Jumping around mid-fade would sound awful
+          self.fade = None;
         }
       }
 
@@ -114,8 +235,7 @@ impl DecomposerAudioDaemont {
 
   fn finagle_audio_state(
     &mut self,
-    mut data: &mut [f32],
-    _callback: &OutputCallbackInfo,
+    data: &mut [f32],
   ) -> Result<(), CreekError> {
     // I would be doing this with the slick new let-else but the formatter
     // does not like it
@@ -132,79 +252,262 @@ impl DecomposerAudioDaemont {
 
     // The original app injects silence; instead I will pause until things
     // are ok
-    if !stream.is_ready()? {
+    if !is_ready(stream, self.blocking)? {
       let _ignore = self.tx_to_ui.push(MsgThreadToUi::Buffering);
       // but prevent stuttering
       make_silent(data);
       return Ok(());
     }
 
-    let prev_playhead = stream.playhead();
+    let mut prev_playhead = stream.playhead().saturating_sub(self.bounds.start);
 
-    if playing {
-      let frame_count = stream.info().num_frames;
-      let channel_count = stream.info().num_channels as usize;
-      // The original code here has the magic number 2 as a divisor;
-      // I'm not sure if it gracefully handles files with non-2 channels.
-      // Code and find out, I guess
-      while data.len() >= channel_count {
-        let must_read_count = data.len() / OUTPUT_CHANNEL_COUNT;
-        let mut playhead = stream.playhead();
+    if !playing {
+      make_silent(data);
+      return Ok(());
+    }
 
-        // Suck the data off disc
-        let read_data = stream.read(must_read_count)?;
-        let actually_read_count = read_data.num_frames();
-        playhead += actually_read_count;
+    // See if it's time to start fading into the next one
+    let fade_frames = self.crossfade.frames(self.device_sample_rate);
+    let wants_fade = self.next.as_ref().is_some_and(|next| next.crossfade);
+    if wants_fade && fade_frames > 0 && self.fade.is_none() && !self.looping {
+      let left = self
+        .chain
+        .output_frames_for(self.bounds.end.saturating_sub(stream.playhead()));
+      if left <= fade_frames {
+        let incoming = self.next.take().unwrap();
+        info!("Crossfading into the next song over {} frames", left);
+        let outgoing = std::mem::replace(stream, incoming.stream);
+        let outgoing_gain = std::mem::replace(&mut self.gain, incoming.gain);
+        let outgoing_bounds =
+          std::mem::replace(&mut self.bounds, incoming.bounds);
+        // The old chain goes with the old stream
+        std::mem::swap(&mut self.chain, &mut self.fade_chain);
+        self.chain.configure_for(stream.info());
+        self.chain.reset();
+        self.fade = Some(Fade {
+          outgoing: Some(outgoing),
+          bounds: outgoing_bounds,
+          gain: outgoing_gain,
+          pos: 0,
+          len: left.max(1),
+        });
+        let _ignore = self.tx_to_ui.push(MsgThreadToUi::StartedNext);
+        prev_playhead = usize::MAX;
 
-        let must_loop = self.looping && playhead >= frame_count;
-        let write_count = if must_loop {
-          read_data.num_frames() - (playhead - frame_count)
-        } else {
-          read_data.num_frames()
-        };
+        if !is_ready(stream, self.blocking)? {
+          // Keep the old one fading out while the new one catches up
+          let _ignore = self.tx_to_ui.push(MsgThreadToUi::Buffering);
+          make_silent(data);
+          self.mix_fade(data);
+          return Ok(());
+        }
+      }
+    }
 
-        // Copy all of the read data (no looping)
-        if read_data.num_channels() == 1 {
-          let ch = read_data.read_channel(0);
+    let mut rest = &mut data[..];
+    loop {
+      let written = fill_from(
+        stream,
+        self.bounds,
+        &mut self.chain,
+        rest,
+        self.looping,
+        self.volume * self.gain,
+      )?;
+      rest = &mut rest[written * self.device_channels..];
+      if rest.len() < self.device_channels {
+        break;
+      }
+
+      // Ran out of this stream
+      let Some(next) = self.next.take() else {
+        make_silent(rest);
+        // Fade out whatever's left of the last one before we give up
+        self.mix_fade(data);
+        return Err(CreekError::EndOfFile);
+      };
+      // Gapless handoff; keep filling this same buffer from the next stream
+      info!("Finished with this song, moving right on to the next one");
+      *stream = next.stream;
+      self.gain = next.gain;
+      self.bounds = next.bounds;
+      self.chain.configure_for(stream.info());
+      let _ignore = self.tx_to_ui.push(MsgThreadToUi::StartedNext);
+      // Make sure the ui hears where the new one is at
+      prev_playhead = usize::MAX;
 
-          for i in 0..write_count {
-            data[i * OUTPUT_CHANNEL_COUNT] = ch[i] * self.volume;
-            data[(i * OUTPUT_CHANNEL_COUNT) + 1] = ch[i] * self.volume;
-          }
+      if !is_ready(stream, self.blocking)? {
+        let _ignore = self.tx_to_ui.push(MsgThreadToUi::Buffering);
+        make_silent(rest);
+        break;
+      }
+    }
+
+    let playhead = stream.playhead().saturating_sub(self.bounds.start);
+    self.mix_fade(data);
+
+    if playhead != prev_playhead {
+      let _ignore = self.tx_to_ui.push(MsgThreadToUi::PlayheadPos(playhead));
+    }
+
+    Ok(())
+  }
+
+  /// If there's a crossfade going on, mix the outgoing track into `data`,
+  /// which has the incoming track in it.
+  fn mix_fade(&mut self, data: &mut [f32]) {
+    let Some(fade) = self.fade.as_mut() else {
+      return;
+    };
+
+    let frames = data.len() / self.device_channels;
+    self.fade_scratch.resize(data.len(), 0.0);
+    let got = match fade.outgoing.as_mut() {
+      Some(outgoing) => {
+        if is_ready(outgoing, self.blocking).unwrap_or(false) {
+          fill_from(
+            outgoing,
+            fade.bounds,
+            &mut self.fade_chain,
+            &mut self.fade_scratch,
+            false,
+            self.volume * fade.gain,
+          )
+          .unwrap_or(0)
         } else {
-          let ch1 = read_data.read_channel(0);
-          let ch2 = read_data.read_channel(1);
-
-          // For now just Delete the other channels i guess?
-          // this is bad
-          for chan_id in 2..read_data.num_channels() {
-            read_data.read_channel(chan_id);
-          }
-
-          for i in 0..write_count {
-            data[i * OUTPUT_CHANNEL_COUNT] = ch1[i] * self.volume;
-            data[(i * OUTPUT_CHANNEL_COUNT) + 1] = ch2[i] * self.volume;
-          }
+          // Don't hold up the incoming track for it
+          let _ignore = self.tx_to_ui.push(MsgThreadToUi::Buffering);
+          0
         }
+      }
+      None => 0,
+    };
+    if got < frames && fade.outgoing.is_some() {
+      // Ran out, so the rest of it is silence
+      fade.outgoing = None;
+    }
+    make_silent(&mut self.fade_scratch[got * self.device_channels..]);
 
-        if must_loop {
-          stream.seek(0, SeekMode::Auto)?;
-        }
+    for (frame, (out, old)) in data
+      .chunks_exact_mut(self.device_channels)
+      .zip(self.fade_scratch.chunks_exact(self.device_channels))
+      .enumerate()
+    {
+      let t = (fade.pos + frame) as f32 / fade.len as f32;
+      let (gain_in, gain_out) = self.crossfade.curve.gains(t);
+      for (o, s) in out.iter_mut().zip(old) {
+        *o = *o * gain_in + s * gain_out;
+      }
+    }
+
+    fade.pos += frames;
+    if fade.pos >= fade.len {
+      self.fade = None;
+    }
+  }
+}
+
+/// An outgoing track on its way out.
+struct Fade {
+  /// `None` once it's run out
+  outgoing: Option<ReadDiskStream<SymphoniaDecoder>>,
+  bounds: TrackBounds,
+  gain: f32,
+  /// How many device frames into the fade we are
+  pos: usize,
+  len: usize,
+}
+
+/// Something sent with `EnqueueNext`.
+struct NextStream {
+  stream: ReadDiskStream<SymphoniaDecoder>,
+  bounds: TrackBounds,
+  crossfade: bool,
+  gain: f32,
+}
+
+/// Whether there's enough buffered to play, or wait until there is if
+/// `blocking`.
+fn is_ready(
+  stream: &mut ReadDiskStream<SymphoniaDecoder>,
+  blocking: bool,
+) -> Result<bool, CreekError> {
+  if blocking {
+    stream.block_until_ready()?;
+    Ok(true)
+  } else {
+    stream.is_ready()
+  }
+}
 
-        data = &mut data[actually_read_count * OUTPUT_CHANNEL_COUNT..];
+/// Fill `out` with device frames from the `bounds` part of `stream`.
+///
+/// Returns how many frames were written. If that's less than `out` could
+/// hold, the stream has run out.
+fn fill_from(
+  stream: &mut ReadDiskStream<SymphoniaDecoder>,
+  bounds: TrackBounds,
+  chain: &mut SignalChain,
+  out: &mut [f32],
+  looping: bool,
+  volume: f32,
+) -> Result<usize, CreekError> {
+  let channels = chain.channels();
+  let total = out.len() / channels;
+  let mut written = 0;
+  while written < total {
+    // Get whatever's already in the resampler out of the way first
+    written += chain.produce(&mut out[written * channels..]);
+    if written >= total {
+      break;
+    }
+
+    let end = bounds.end;
+    if stream.playhead() >= end {
+      // Looping nothing would go round forever
+      if !looping || bounds.len() == 0 {
+        break;
       }
-    } else {
-      make_silent(data);
+      stream.seek(bounds.start, SeekMode::Auto)?;
     }
 
-    if stream.playhead() != prev_playhead {
-      let _ignore = self
-        .tx_to_ui
-        .push(MsgThreadToUi::PlayheadPos(stream.playhead()));
+    let mut playhead = stream.playhead();
+    // Don't read past the end of the track into whatever's after it
+    let must_read_count = chain
+      .input_needed(total - written)
+      .clamp(1, MAX_READ_FRAMES)
+      .min(end - playhead);
+
+    // Suck the data off disc
+    let read_data = match stream.read(must_read_count) {
+      Ok(it) => it,
+      Err(creek::read::ReadError::EndOfFile) => break,
+      Err(err) => return Err(err),
+    };
+    let actually_read_count = read_data.num_frames();
+    if actually_read_count == 0 {
+      // Nothing to give, don't spin forever
+      break;
     }
+    playhead += actually_read_count;
 
-    Ok(())
+    let must_loop = looping && playhead >= end;
+    let write_count = if playhead > end {
+      read_data.num_frames() - (playhead - end)
+    } else {
+      read_data.num_frames()
+    };
+
+    // The channel matrix takes care of turning the file's channels into
+    // the output's channels, then the resampler takes care of the rate
+    chain.push(&read_data, write_count, volume);
+
+    if must_loop {
+      stream.seek(bounds.start, SeekMode::Auto)?;
+    }
   }
+
+  Ok(written)
 }
 
 fn make_silent(data: &mut [f32]) {
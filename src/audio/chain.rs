@@ -0,0 +1,114 @@
+//! Everything between creek handing us planar file-rate frames and cpal
+//! getting interleaved device-rate frames.
+
+use creek::{read::ReadData, FileInfo, SymphoniaDecoderInfo};
+
+use crate::settings::DecomposerConfig;
+
+use super::{
+  ChannelMatrix, DownmixMode, Resampler, UpmixMode, MAX_READ_FRAMES,
+};
+
+#[derive(Debug)]
+pub struct SignalChain {
+  downmix: DownmixMode,
+  upmix: UpmixMode,
+  device_sample_rate: u32,
+  device_channels: usize,
+
+  /// Recalculated for every new track
+  matrix: ChannelMatrix,
+  /// Reconfigured for every new track
+  resampler: Resampler,
+  /// Downmixed frames on their way into the resampler
+  scratch: Vec<f32>,
+}
+
+impl SignalChain {
+  pub fn new(
+    config: &DecomposerConfig,
+    device_sample_rate: u32,
+    device_channels: usize,
+  ) -> Self {
+    Self {
+      downmix: config.downmix().clone(),
+      upmix: config.upmix(),
+      device_sample_rate,
+      device_channels,
+      matrix: ChannelMatrix::new(
+        &DownmixMode::FrontPair,
+        UpmixMode::FrontOnly,
+        None,
+        device_channels,
+        device_channels,
+      ),
+      resampler: Resampler::new(
+        config.resample_quality(),
+        device_channels,
+        device_sample_rate,
+        device_sample_rate,
+      ),
+      scratch: Vec::with_capacity(MAX_READ_FRAMES * device_channels),
+    }
+  }
+
+  /// How many channels come out the other end.
+  pub fn channels(&self) -> usize {
+    self.device_channels
+  }
+
+  /// Set up the channel matrix and resampler for a new stream.
+  ///
+  /// This doesn't clear anything that's already buffered, so the end of the
+  /// last stream can flow right into the start of this one.
+  pub fn configure_for(&mut self, info: &FileInfo<SymphoniaDecoderInfo>) {
+    self.matrix = ChannelMatrix::new(
+      &self.downmix,
+      self.upmix,
+      info.params.codec_params.channels,
+      info.num_channels as usize,
+      self.device_channels,
+    );
+    // Files that don't know their rate are probably at the device rate
+    self.resampler.set_rates(
+      info.sample_rate.unwrap_or(self.device_sample_rate),
+      self.device_sample_rate,
+    );
+  }
+
+  /// Forget everything buffered, like after a seek.
+  pub fn reset(&mut self) {
+    self.resampler.reset();
+  }
+
+  /// Write as many device frames as are ready into `out`.
+  /// Returns how many frames were written.
+  pub fn produce(&mut self, out: &mut [f32]) -> usize {
+    self.resampler.produce(out)
+  }
+
+  /// How many more file frames need to go in before `out_frames` device
+  /// frames can come out.
+  pub fn input_needed(&self, out_frames: usize) -> usize {
+    self.resampler.input_needed(out_frames)
+  }
+
+  /// About how many device frames `file_frames` file frames turn into.
+  pub fn output_frames_for(&self, file_frames: usize) -> usize {
+    self.resampler.output_frames_for(file_frames)
+  }
+
+  /// Feed the first `frame_count` frames of `read_data` in.
+  pub fn push(
+    &mut self,
+    read_data: &ReadData<f32>,
+    frame_count: usize,
+    gain: f32,
+  ) {
+    self.scratch.resize(frame_count * self.device_channels, 0.0);
+    self
+      .matrix
+      .mix(read_data, frame_count, &mut self.scratch, gain);
+    self.resampler.push(&self.scratch);
+  }
+}
@@ -0,0 +1,63 @@
+//! Fading one track out while the next one fades in.
+
+use std::f32::consts::FRAC_PI_2;
+
+use serde::{Deserialize, Serialize};
+
+/// Longest crossfade we'll agree to do, in seconds.
+pub const MAX_CROSSFADE_SECS: f32 = 12.0;
+
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum CrossfadeCurve {
+  /// Straight line. Dips in the middle.
+  Linear,
+  /// Sine/cosine, so the total power stays the same the whole way through.
+  #[default]
+  EqualPower,
+  /// Smoothstep; lingers on each track and swaps over quickly in the middle.
+  SCurve,
+}
+
+impl CrossfadeCurve {
+  /// Gains for the incoming and outgoing track, `t` of the way through the
+  /// fade.
+  pub fn gains(self, t: f32) -> (f32, f32) {
+    let t = t.clamp(0.0, 1.0);
+    match self {
+      CrossfadeCurve::Linear => (t, 1.0 - t),
+      CrossfadeCurve::EqualPower => {
+        ((t * FRAC_PI_2).sin(), (t * FRAC_PI_2).cos())
+      }
+      CrossfadeCurve::SCurve => {
+        let s = t * t * (3.0 - 2.0 * t);
+        (s, 1.0 - s)
+      }
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CrossfadeSettings {
+  /// 0 for no crossfade.
+  pub secs: f32,
+  pub curve: CrossfadeCurve,
+}
+
+impl Default for CrossfadeSettings {
+  fn default() -> Self {
+    Self {
+      secs: 0.0,
+      curve: CrossfadeCurve::default(),
+    }
+  }
+}
+
+impl CrossfadeSettings {
+  /// How long the fade is in device frames.
+  pub fn frames(&self, device_sample_rate: u32) -> usize {
+    let secs = self.secs.clamp(0.0, MAX_CROSSFADE_SECS);
+    (secs * device_sample_rate as f32) as usize
+  }
+}
@@ -0,0 +1,448 @@
+//! Squishing (or stretching) however many channels the file has into
+//! however many channels the device has.
+
+use creek::read::ReadData;
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::Channels;
+
+/// -3dB, the magic number for folding one speaker into two.
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+/// -6dB, for things that are centered *and* behind you.
+const MINUS_6DB: f32 = 0.5;
+
+/// How to turn files with lots of channels into stereo.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum DownmixMode {
+  /// Pick ITU-R BS.775 coefficients based on the channel layout the file
+  /// reports.
+  #[default]
+  Auto,
+  /// Play the first two channels and throw the rest away.
+  FrontPair,
+  /// One row per output channel, one column per input channel.
+  /// Missing entries are treated as 0.
+  Custom(Vec<Vec<f32>>),
+}
+
+/// What to do with stereo when there are more speakers than that.
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum UpmixMode {
+  /// Just the front left and right, leave the rest quiet.
+  #[default]
+  FrontOnly,
+  /// Left goes to every left speaker and right to every right speaker.
+  Duplicate,
+  /// Like `Duplicate`, but also fill in the centre, and keep the
+  /// surrounds a bit quieter than the fronts.
+  Spread,
+}
+
+/// Gain of each input channel in each output channel.
+#[derive(Debug, Clone)]
+pub struct ChannelMatrix {
+  inputs: usize,
+  outputs: usize,
+  /// Stored as `coeffs[out * inputs + in]`
+  coeffs: Vec<f32>,
+}
+
+impl ChannelMatrix {
+  /// Make the matrix for a file with the given layout.
+  ///
+  /// Symphonia doesn't always know the layout, so `input_count` is the
+  /// source of truth for how many channels there actually are.
+  /// The outputs are assumed to be in WAVE order.
+  pub fn new(
+    mode: &DownmixMode,
+    upmix: UpmixMode,
+    layout: Option<Channels>,
+    input_count: usize,
+    output_count: usize,
+  ) -> Self {
+    let mut out = Self {
+      inputs: input_count,
+      outputs: output_count,
+      coeffs: vec![0.0; input_count * output_count],
+    };
+    if input_count == 0 || output_count == 0 {
+      return out;
+    }
+    let speakers = output_speakers(output_count);
+
+    match mode {
+      // Mono is always just mono, no matter what speaker symphonia thinks
+      // it comes out of
+      _ if input_count == 1 => {
+        for o in 0..output_count.min(2) {
+          out.set(o, 0, 1.0);
+        }
+      }
+      DownmixMode::Auto => {
+        let layout = layout
+          .filter(|l| l.count() == input_count)
+          .or_else(|| guess_layout(input_count));
+        match layout {
+          Some(layout) => out.fill_routed(layout, &speakers, upmix),
+          None => out.fill_front_pair(),
+        }
+      }
+      DownmixMode::FrontPair => out.fill_front_pair(),
+      DownmixMode::Custom(rows) => {
+        for (o, row) in rows.iter().take(output_count).enumerate() {
+          for (i, coeff) in row.iter().take(input_count).enumerate() {
+            out.set(o, i, *coeff);
+          }
+        }
+      }
+    }
+
+    out
+  }
+
+  pub fn get(&self, output: usize, input: usize) -> f32 {
+    self.coeffs[output * self.inputs + input]
+  }
+
+  fn set(&mut self, output: usize, input: usize, coeff: f32) {
+    self.coeffs[output * self.inputs + input] = coeff;
+  }
+
+  /// Mix the first `frame_count` frames of the planar `read_data` into the
+  /// interleaved `out`, overwriting whatever was there.
+  pub fn mix(
+    &self,
+    read_data: &ReadData<f32>,
+    frame_count: usize,
+    out: &mut [f32],
+    gain: f32,
+  ) {
+    let out = &mut out[..frame_count * self.outputs];
+    for s in out.iter_mut() {
+      *s = 0.0;
+    }
+
+    let inputs = self.inputs.min(read_data.num_channels());
+    for i in 0..inputs {
+      let ch = read_data.read_channel(i);
+      for o in 0..self.outputs {
+        let coeff = self.get(o, i) * gain;
+        if coeff == 0.0 {
+          continue;
+        }
+        for (frame, sample) in ch[..frame_count].iter().enumerate() {
+          out[frame * self.outputs + o] += sample * coeff;
+        }
+      }
+    }
+  }
+
+  fn fill_front_pair(&mut self) {
+    for io in 0..self.inputs.min(self.outputs).min(2) {
+      self.set(io, io, 1.0);
+    }
+  }
+
+  /// Send every input speaker to the same output speaker if there is one,
+  /// otherwise fold it into its neighbours per ITU-R BS.775.
+  /// The LFE is dropped unless there's somewhere for it to go.
+  fn fill_routed(
+    &mut self,
+    layout: Channels,
+    speakers: &[Channels],
+    upmix: UpmixMode,
+  ) {
+    let available = speakers
+      .iter()
+      .fold(Channels::empty(), |acc, speaker| acc | *speaker);
+    for (i, ch) in layout.iter().enumerate() {
+      for (target, gain) in route(ch, available) {
+        if let Some(o) = speakers.iter().position(|s| *s == target) {
+          self.set(o, i, gain);
+        }
+      }
+    }
+
+    let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+    if layout == stereo && available != stereo {
+      self.fill_upmix(speakers, upmix);
+    }
+  }
+
+  /// Spread the front pair out over the rest of the speakers.
+  fn fill_upmix(&mut self, speakers: &[Channels], upmix: UpmixMode) {
+    let surround_gain = match upmix {
+      UpmixMode::FrontOnly => return,
+      UpmixMode::Duplicate => 1.0,
+      UpmixMode::Spread => MINUS_3DB,
+    };
+    for (o, speaker) in speakers.iter().enumerate() {
+      if speaker.intersects(Channels::FRONT_LEFT | Channels::FRONT_RIGHT) {
+        continue;
+      }
+      let (l, r) = if *speaker == Channels::FRONT_CENTRE {
+        match upmix {
+          UpmixMode::Spread => (MINUS_6DB, MINUS_6DB),
+          _ => (0.0, 0.0),
+        }
+      } else {
+        itu_stereo_gains(*speaker)
+      };
+      // Only the side it's on, and no bass-only speakers
+      let (l, r) = match (l > 0.0, r > 0.0) {
+        (true, false) => (surround_gain, 0.0),
+        (false, true) => (0.0, surround_gain),
+        _ => (l, r),
+      };
+      self.set(o, 0, l);
+      self.set(o, 1, r);
+    }
+  }
+}
+
+/// Which output speakers one input speaker should end up in, and how loud.
+fn route(ch: Channels, available: Channels) -> Vec<(Channels, f32)> {
+  if available.contains(ch) {
+    return vec![(ch, 1.0)];
+  }
+  // Sides and rears stand in for each other before folding forwards
+  let swap = if ch == Channels::SIDE_LEFT {
+    Channels::REAR_LEFT
+  } else if ch == Channels::REAR_LEFT {
+    Channels::SIDE_LEFT
+  } else if ch == Channels::SIDE_RIGHT {
+    Channels::REAR_RIGHT
+  } else if ch == Channels::REAR_RIGHT {
+    Channels::SIDE_RIGHT
+  } else {
+    Channels::empty()
+  };
+  if !swap.is_empty() && available.contains(swap) {
+    return vec![(swap, 1.0)];
+  }
+
+  let front = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+  if !available.contains(front) {
+    // A single speaker gets everything but the LFE
+    return match ch.intersects(Channels::LFE1 | Channels::LFE2) {
+      true => Vec::new(),
+      false => vec![(Channels::FRONT_CENTRE, MINUS_3DB)],
+    };
+  }
+  let (l, r) = itu_stereo_gains(ch);
+  [(Channels::FRONT_LEFT, l), (Channels::FRONT_RIGHT, r)]
+    .into_iter()
+    .filter(|(_, gain)| *gain > 0.0)
+    .collect()
+}
+
+/// How much of a speaker goes to the left and right in a stereo fold-down.
+fn itu_stereo_gains(ch: Channels) -> (f32, f32) {
+  if ch == Channels::FRONT_LEFT {
+    (1.0, 0.0)
+  } else if ch == Channels::FRONT_RIGHT {
+    (0.0, 1.0)
+  } else if ch == Channels::FRONT_CENTRE {
+    (MINUS_3DB, MINUS_3DB)
+  } else if ch.intersects(Channels::LFE1 | Channels::LFE2) {
+    (0.0, 0.0)
+  } else if ch.intersects(
+    Channels::REAR_LEFT
+      | Channels::SIDE_LEFT
+      | Channels::FRONT_LEFT_CENTRE
+      | Channels::FRONT_LEFT_WIDE
+      | Channels::FRONT_LEFT_HIGH
+      | Channels::REAR_LEFT_CENTRE
+      | Channels::TOP_FRONT_LEFT
+      | Channels::TOP_REAR_LEFT,
+  ) {
+    (MINUS_3DB, 0.0)
+  } else if ch.intersects(
+    Channels::REAR_RIGHT
+      | Channels::SIDE_RIGHT
+      | Channels::FRONT_RIGHT_CENTRE
+      | Channels::FRONT_RIGHT_WIDE
+      | Channels::FRONT_RIGHT_HIGH
+      | Channels::REAR_RIGHT_CENTRE
+      | Channels::TOP_FRONT_RIGHT
+      | Channels::TOP_REAR_RIGHT,
+  ) {
+    (0.0, MINUS_3DB)
+  } else {
+    // Rear centre and the top centres
+    (MINUS_6DB, MINUS_6DB)
+  }
+}
+
+/// Which speaker each output channel is plugged into.
+///
+/// cpal doesn't tell us, so go with WAVE order. Channels past what we know
+/// about are left as `Channels::empty()` and never get anything.
+fn output_speakers(count: usize) -> Vec<Channels> {
+  let layout = match count {
+    1 => Some(Channels::FRONT_CENTRE),
+    _ => guess_layout(count),
+  };
+  let mut out = match layout {
+    Some(layout) => layout.iter().collect::<Vec<_>>(),
+    None => vec![Channels::FRONT_LEFT, Channels::FRONT_RIGHT],
+  };
+  out.resize(count, Channels::empty());
+  out
+}
+
+/// Lots of files don't say what their layout is.
+/// In that case go with the WAVE ordering for the usual suspects.
+fn guess_layout(count: usize) -> Option<Channels> {
+  let front = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+  let layout = match count {
+    2 => front,
+    3 => front | Channels::FRONT_CENTRE,
+    4 => front | Channels::REAR_LEFT | Channels::REAR_RIGHT,
+    5 => {
+      front
+        | Channels::FRONT_CENTRE
+        | Channels::REAR_LEFT
+        | Channels::REAR_RIGHT
+    }
+    6 => {
+      front
+        | Channels::FRONT_CENTRE
+        | Channels::LFE1
+        | Channels::REAR_LEFT
+        | Channels::REAR_RIGHT
+    }
+    8 => {
+      front
+        | Channels::FRONT_CENTRE
+        | Channels::LFE1
+        | Channels::REAR_LEFT
+        | Channels::REAR_RIGHT
+        | Channels::SIDE_LEFT
+        | Channels::SIDE_RIGHT
+    }
+    _ => return None,
+  };
+  Some(layout)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rows(matrix: &ChannelMatrix) -> Vec<Vec<f32>> {
+    (0..matrix.outputs)
+      .map(|o| (0..matrix.inputs).map(|i| matrix.get(o, i)).collect())
+      .collect()
+  }
+
+  fn assert_rows(matrix: &ChannelMatrix, expected: &[&[f32]]) {
+    let got = rows(matrix);
+    assert_eq!(got.len(), expected.len());
+    for (got, expected) in got.iter().zip(expected) {
+      assert_eq!(got.len(), expected.len());
+      for (g, e) in got.iter().zip(expected.iter()) {
+        assert!((g - e).abs() < 1e-6, "{:?} != {:?}", got, expected);
+      }
+    }
+  }
+
+  const H: f32 = MINUS_3DB;
+
+  #[test]
+  fn five_one_to_stereo_is_itu() {
+    let matrix =
+      ChannelMatrix::new(&DownmixMode::Auto, UpmixMode::FrontOnly, None, 6, 2);
+    // FL FR FC LFE RL RR
+    assert_rows(
+      &matrix,
+      &[&[1.0, 0.0, H, 0.0, H, 0.0], &[0.0, 1.0, H, 0.0, 0.0, H]],
+    );
+  }
+
+  #[test]
+  fn seven_one_to_stereo_is_itu() {
+    let matrix =
+      ChannelMatrix::new(&DownmixMode::Auto, UpmixMode::FrontOnly, None, 8, 2);
+    // FL FR FC LFE RL RR SL SR
+    assert_rows(
+      &matrix,
+      &[
+        &[1.0, 0.0, H, 0.0, H, 0.0, H, 0.0],
+        &[0.0, 1.0, H, 0.0, 0.0, H, 0.0, H],
+      ],
+    );
+  }
+
+  #[test]
+  fn front_pair_drops_the_rest() {
+    let matrix = ChannelMatrix::new(
+      &DownmixMode::FrontPair,
+      UpmixMode::FrontOnly,
+      None,
+      6,
+      2,
+    );
+    assert_rows(
+      &matrix,
+      &[
+        &[1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        &[0.0, 1.0, 0.0, 0.0, 0.0, 0.0],
+      ],
+    );
+  }
+
+  #[test]
+  fn custom_fills_missing_with_zero() {
+    let mode = DownmixMode::Custom(vec![vec![0.5, 0.25, 0.125, 9.0], vec![]]);
+    let matrix = ChannelMatrix::new(&mode, UpmixMode::FrontOnly, None, 3, 2);
+    assert_rows(&matrix, &[&[0.5, 0.25, 0.125], &[0.0, 0.0, 0.0]]);
+  }
+
+  #[test]
+  fn mono_goes_to_both_sides() {
+    let matrix =
+      ChannelMatrix::new(&DownmixMode::Auto, UpmixMode::Spread, None, 1, 6);
+    assert_rows(&matrix, &[&[1.0], &[1.0], &[0.0], &[0.0], &[0.0], &[0.0]]);
+  }
+
+  #[test]
+  fn stereo_upmix_to_five_one() {
+    let upmix = |mode| ChannelMatrix::new(&DownmixMode::Auto, mode, None, 2, 6);
+    // FL FR FC LFE RL RR
+    assert_rows(
+      &upmix(UpmixMode::FrontOnly),
+      &[
+        &[1.0, 0.0],
+        &[0.0, 1.0],
+        &[0.0, 0.0],
+        &[0.0, 0.0],
+        &[0.0, 0.0],
+        &[0.0, 0.0],
+      ],
+    );
+    assert_rows(
+      &upmix(UpmixMode::Duplicate),
+      &[
+        &[1.0, 0.0],
+        &[0.0, 1.0],
+        &[0.0, 0.0],
+        &[0.0, 0.0],
+        &[1.0, 0.0],
+        &[0.0, 1.0],
+      ],
+    );
+    assert_rows(
+      &upmix(UpmixMode::Spread),
+      &[
+        &[1.0, 0.0],
+        &[0.0, 1.0],
+        &[MINUS_6DB, MINUS_6DB],
+        &[0.0, 0.0],
+        &[H, 0.0],
+        &[0.0, H],
+      ],
+    );
+  }
+}
@@ -0,0 +1,77 @@
+//! Keeping the output from clipping.
+//!
+//! The downmix coefficients add up to more than 1 on purpose so dialogue and
+//! such stay at the level they were mixed at, so every so often something
+//! comes out louder than the device can take.
+
+/// How loud we let anything get.
+const CEILING: f32 = 1.0;
+/// About how many frames it takes to come back up after a peak.
+/// Around a fifth of a second at the usual rates.
+const RELEASE_FRAMES: f32 = 10_000.0;
+
+/// Turns the whole output down the moment a peak would clip, then eases
+/// back up to unity.
+#[derive(Debug, Clone)]
+pub struct PeakLimiter {
+  gain: f32,
+}
+
+impl PeakLimiter {
+  pub fn new() -> Self {
+    Self { gain: 1.0 }
+  }
+
+  /// Limit the interleaved `data` in place.
+  pub fn process(&mut self, data: &mut [f32], channels: usize) {
+    for frame in data.chunks_exact_mut(channels.max(1)) {
+      let peak = frame.iter().fold(0.0f32, |acc, s| acc.max(s.abs()));
+      let wanted = if peak > CEILING { CEILING / peak } else { 1.0 };
+      if wanted < self.gain {
+        self.gain = wanted;
+      } else {
+        self.gain += (wanted - self.gain) / RELEASE_FRAMES;
+      }
+
+      if self.gain < 1.0 {
+        for s in frame.iter_mut() {
+          *s *= self.gain;
+        }
+      }
+    }
+  }
+}
+
+impl Default for PeakLimiter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn leaves_quiet_signals_alone() {
+    let mut limiter = PeakLimiter::new();
+    let input: Vec<f32> = (0..2000).map(|i| (i as f32 * 0.05).sin()).collect();
+    let mut data = input.clone();
+    limiter.process(&mut data, 2);
+    assert_eq!(data, input);
+  }
+
+  #[test]
+  fn never_goes_over_and_recovers() {
+    let mut limiter = PeakLimiter::new();
+    let mut loud = vec![2.5; 200];
+    limiter.process(&mut loud, 2);
+    assert!(loud.iter().all(|s| *s <= CEILING));
+
+    // Back up to (nearly) unity once it's quiet again
+    let mut quiet = vec![0.5; RELEASE_FRAMES as usize * 20];
+    limiter.process(&mut quiet, 2);
+    assert!(quiet.iter().all(|s| *s <= 0.5));
+    assert!((quiet.last().unwrap() - 0.5).abs() < 1e-3);
+  }
+}
@@ -0,0 +1,404 @@
+//! Running the daemon without a sound card, for checking what it does.
+
+use std::{
+  fs::File,
+  io::{BufWriter, Write},
+  path::Path,
+};
+
+use creek::{ReadDiskStream, ReadStreamOptions, SymphoniaDecoder};
+use eyre::{bail, eyre, WrapErr};
+use log::{info, warn};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::{
+  loudness::LoudnessCache,
+  model::{
+    CurrentlyPlayingTrack, MsgThreadToUi, MsgUiToThread, Track, TrackBounds,
+    TrackMeta,
+  },
+  settings::{DecomposerConfig, LOUDNESS_CACHE_NAME},
+};
+
+use super::DecomposerAudioDaemont;
+
+/// About what a real device asks for at once.
+const BLOCK_FRAMES: usize = 512;
+
+/// Send `msg` to the daemon right before rendering `at_frame`.
+#[derive(Debug)]
+pub struct ScriptedMsg {
+  pub at_frame: usize,
+  pub msg: MsgUiToThread,
+}
+
+/// What came out of a render.
+#[derive(Debug, Default)]
+pub struct Rendered {
+  /// Interleaved
+  pub samples: Vec<f32>,
+  /// Everything the daemon said, and the frame it was rendering when it
+  /// said it
+  pub msgs: Vec<(usize, MsgThreadToUi)>,
+}
+
+/// Drives a daemon block by block like cpal would, but into memory.
+///
+/// The daemon waits on disk reads instead of buffering, so the same script
+/// always renders the same samples.
+pub struct OfflineRenderer {
+  daemon: DecomposerAudioDaemont,
+  tx_to_thread: Producer<MsgUiToThread>,
+  rx_from_thread: Consumer<MsgThreadToUi>,
+  sample_rate: u32,
+  channels: usize,
+}
+
+impl OfflineRenderer {
+  pub fn new(
+    config: &DecomposerConfig,
+    sample_rate: u32,
+    channels: usize,
+  ) -> Self {
+    let (tx_to_thread, rx_from_ui) = RingBuffer::new(64);
+    let (tx_to_ui, rx_from_thread) = RingBuffer::new(256);
+    let mut daemon = DecomposerAudioDaemont::new(
+      tx_to_ui,
+      rx_from_ui,
+      config,
+      sample_rate,
+      channels,
+    );
+    daemon.set_blocking(true);
+    Self {
+      daemon,
+      tx_to_thread,
+      rx_from_thread,
+      sample_rate,
+      channels,
+    }
+  }
+
+  /// Render `frame_count` frames, sending each message at its frame.
+  ///
+  /// Blocks are split up at message frames, so messages land exactly where
+  /// they're asked for.
+  pub fn render(
+    &mut self,
+    mut script: Vec<ScriptedMsg>,
+    frame_count: usize,
+  ) -> Rendered {
+    script.sort_by_key(|msg| msg.at_frame);
+    let mut script = script.into_iter().peekable();
+    let mut out = Rendered {
+      samples: vec![0.0; frame_count * self.channels],
+      msgs: Vec::new(),
+    };
+
+    let mut frame = 0;
+    while frame < frame_count {
+      while let Some(msg) = script.next_if(|msg| msg.at_frame <= frame) {
+        if self.tx_to_thread.push(msg.msg).is_err() {
+          warn!("Too many scripted messages at frame {}", frame);
+        }
+      }
+
+      let mut end = (frame + BLOCK_FRAMES).min(frame_count);
+      if let Some(next) = script.peek() {
+        end = end.min(next.at_frame);
+      }
+      self
+        .daemon
+        .render(&mut out.samples[frame * self.channels..end * self.channels]);
+
+      while let Ok(msg) = self.rx_from_thread.pop() {
+        out.msgs.push((frame, msg));
+      }
+      frame = end;
+    }
+
+    out
+  }
+
+  /// Render and write it out as a 32-bit float WAV.
+  pub fn render_to_wav(
+    &mut self,
+    script: Vec<ScriptedMsg>,
+    frame_count: usize,
+    path: &Path,
+  ) -> eyre::Result<Rendered> {
+    let rendered = self.render(script, frame_count);
+    write_wav(path, &rendered.samples, self.channels, self.sample_rate)
+      .wrap_err_with(|| format!("Could not write {:?}", path))?;
+    Ok(rendered)
+  }
+}
+
+/// Play `tracks` back to back like the queue would and write it to `out`.
+///
+/// Gain and crossfades get worked out the same way the app does it, so
+/// this is what it would sound like in there.
+pub fn render_files(
+  config: &DecomposerConfig,
+  tracks: &[&Path],
+  out: &Path,
+  sample_rate: u32,
+  channels: usize,
+) -> eyre::Result<()> {
+  if tracks.is_empty() {
+    bail!("Nothing to render");
+  }
+
+  let loudness =
+    LoudnessCache::open(&config.sibling_location(LOUDNESS_CACHE_NAME));
+  let (playing, streams): (Vec<_>, Vec<_>) = tracks
+    .iter()
+    .map(|path| open_for_render(path))
+    .collect::<eyre::Result<Vec<_>>>()?
+    .into_iter()
+    .unzip();
+  let fade_frames = config.crossfade().frames(sample_rate);
+
+  let mut script = Vec::new();
+  // Where the last track started and ended, in output frames
+  let mut prev_start = 0;
+  let mut prev_frames = 0;
+  let mut end = 0;
+  for (i, stream) in streams.into_iter().enumerate() {
+    let track = &playing[i];
+    let prev = i.checked_sub(1).map(|i| &playing[i]);
+    let next = playing.get(i + 1).map(|next| &next.track);
+    let gain = loudness.gain_for(config.replay_gain(), track, prev, next);
+    let bounds = track.bounds;
+    let file_rate = track.file_info.sample_rate.unwrap_or(sample_rate);
+    let frames =
+      (bounds.len() as u64 * sample_rate as u64 / file_rate as u64) as usize;
+
+    let (msg, start) = match prev {
+      None => (
+        MsgUiToThread::StartNewTrack {
+          stream,
+          bounds,
+          gain,
+          playing: true,
+        },
+        0,
+      ),
+      Some(prev) => {
+        // Crossfading inside an album smears things that were meant to be
+        // gapless
+        let crossfade = !prev.is_same_album(track);
+        let overlap = if crossfade {
+          fade_frames.min(prev_frames)
+        } else {
+          0
+        };
+        let msg = MsgUiToThread::EnqueueNext {
+          stream,
+          bounds,
+          crossfade,
+          gain,
+        };
+        (msg, end - overlap)
+      }
+    };
+    // The daemon only holds onto one next track at a time, so send each one
+    // once the one before it has started
+    script.push(ScriptedMsg {
+      at_frame: if i == 0 { 0 } else { prev_start + 1 },
+      msg,
+    });
+    prev_start = start;
+    prev_frames = frames;
+    end = start + frames;
+  }
+
+  info!("Rendering {} tracks to {:?}", tracks.len(), out);
+  let mut renderer = OfflineRenderer::new(config, sample_rate, channels);
+  renderer.render_to_wav(script, end, out)?;
+  Ok(())
+}
+
+/// Open a whole file to render, with what the app would know about it.
+fn open_for_render(
+  path: &Path,
+) -> eyre::Result<(CurrentlyPlayingTrack, ReadDiskStream<SymphoniaDecoder>)> {
+  let stream = open_blocking(path)?;
+  let file_info = stream.info().clone();
+  let (meta, _) = TrackMeta::read_with_cue(path)
+    .wrap_err_with(|| format!("Could not read tags from {:?}", path))?;
+  let track = CurrentlyPlayingTrack {
+    track: Track {
+      path: path.to_path_buf(),
+      meta,
+      span: None,
+    },
+    playhead: 0,
+    bounds: TrackBounds {
+      start: 0,
+      end: file_info.num_frames,
+    },
+    file_info,
+  };
+  Ok((track, stream))
+}
+
+/// Open a file for rendering.
+///
+/// This waits until the start is ready.
+pub fn open_blocking(
+  path: &Path,
+) -> eyre::Result<ReadDiskStream<SymphoniaDecoder>> {
+  let opts = ReadStreamOptions {
+    num_cache_blocks: 20,
+    num_caches: 1,
+    ..Default::default()
+  };
+  let mut stream = ReadDiskStream::<SymphoniaDecoder>::new(path, 0, opts)
+    .map_err(|err| eyre!("Could not open {:?}: {}", path, err))?;
+  let _ignore = stream.cache(0, 0);
+  stream
+    .block_until_ready()
+    .map_err(|err| eyre!("Could not read {:?}: {}", path, err))?;
+  Ok(stream)
+}
+
+/// There's no hound in here, and the header is simple enough.
+fn write_wav(
+  path: &Path,
+  samples: &[f32],
+  channels: usize,
+  sample_rate: u32,
+) -> std::io::Result<()> {
+  let mut file = BufWriter::new(File::create(path)?);
+  let data_len = (samples.len() * 4) as u32;
+  let block_align = (channels * 4) as u16;
+
+  file.write_all(b"RIFF")?;
+  file.write_all(&(36 + data_len).to_le_bytes())?;
+  file.write_all(b"WAVE")?;
+
+  file.write_all(b"fmt ")?;
+  file.write_all(&16u32.to_le_bytes())?;
+  // IEEE float
+  file.write_all(&3u16.to_le_bytes())?;
+  file.write_all(&(channels as u16).to_le_bytes())?;
+  file.write_all(&sample_rate.to_le_bytes())?;
+  file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+  file.write_all(&block_align.to_le_bytes())?;
+  file.write_all(&32u16.to_le_bytes())?;
+
+  file.write_all(b"data")?;
+  file.write_all(&data_len.to_le_bytes())?;
+  for s in samples {
+    file.write_all(&s.to_le_bytes())?;
+  }
+  file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, path::PathBuf};
+
+  use super::*;
+
+  const RATE: u32 = 8000;
+
+  /// A mono file where every sample is different, so it's easy to tell
+  /// which bit of it ended up where.
+  fn ramp(name: &str, frames: usize, step: f32) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+      "decomposer-{}-{}.wav",
+      std::process::id(),
+      name
+    ));
+    let samples = (0..frames)
+      .map(|i| (i + 1) as f32 * step)
+      .collect::<Vec<_>>();
+    write_wav(&path, &samples, 1, RATE).unwrap();
+    path
+  }
+
+  fn whole(stream: &ReadDiskStream<SymphoniaDecoder>) -> TrackBounds {
+    TrackBounds {
+      start: 0,
+      end: stream.info().num_frames,
+    }
+  }
+
+  #[test]
+  fn seeks_and_moves_on_to_the_next_track() {
+    let a = ramp("a", 4000, 1e-4);
+    let b = ramp("b", 2000, -1e-4);
+    let cfg = std::env::temp_dir().join("decomposer-no-such-config.ron");
+    let config = DecomposerConfig::open(Some(&cfg.to_string_lossy())).unwrap();
+
+    let a_stream = open_blocking(&a).unwrap();
+    let b_stream = open_blocking(&b).unwrap();
+    let script = vec![
+      ScriptedMsg {
+        at_frame: 0,
+        msg: MsgUiToThread::StartNewTrack {
+          bounds: whole(&a_stream),
+          stream: a_stream,
+          gain: 1.0,
+          playing: true,
+        },
+      },
+      ScriptedMsg {
+        at_frame: 1000,
+        msg: MsgUiToThread::SeekTo(3000),
+      },
+      ScriptedMsg {
+        at_frame: 1001,
+        msg: MsgUiToThread::EnqueueNext {
+          bounds: whole(&b_stream),
+          stream: b_stream,
+          crossfade: false,
+          gain: 0.5,
+        },
+      },
+    ];
+    let mut renderer = OfflineRenderer::new(&config, RATE, 1);
+    let out = renderer.render(script, 4500);
+    let _ignore = fs::remove_file(&a);
+    let _ignore = fs::remove_file(&b);
+
+    let a_at = |i: usize| (i + 1) as f32 * 1e-4;
+    let b_at = |i: usize| (i + 1) as f32 * -1e-4 * 0.5;
+    let expected = (0..1000)
+      .map(a_at)
+      .chain((3000..4000).map(a_at))
+      .chain((0..2000).map(b_at))
+      .chain(std::iter::repeat_n(0.0, 500));
+    assert_eq!(out.samples.len(), 4500);
+    for (i, (got, want)) in out.samples.iter().zip(expected).enumerate() {
+      assert!(
+        (got - want).abs() < 1e-6,
+        "frame {}: got {}, wanted {}",
+        i,
+        got,
+        want
+      );
+    }
+
+    // The block with the seek in it is only one frame long
+    assert!(out.msgs.iter().any(|(frame, msg)| {
+      *frame == 1000 && matches!(msg, MsgThreadToUi::PlayheadPos(3001))
+    }));
+    // Messages are stamped with the start of the block they came out of
+    let when = |wanted: fn(&MsgThreadToUi) -> bool| {
+      out
+        .msgs
+        .iter()
+        .find(|(_, msg)| wanted(msg))
+        .map(|(frame, _)| *frame)
+    };
+    let started_next = when(|msg| matches!(msg, MsgThreadToUi::StartedNext))
+      .expect("never moved on to the next track");
+    assert!((2000 - BLOCK_FRAMES..=2000).contains(&started_next));
+    let finished = when(|msg| matches!(msg, MsgThreadToUi::FinishedTrack))
+      .expect("never finished");
+    assert!((4000 - BLOCK_FRAMES..=4000).contains(&finished));
+  }
+}
@@ -0,0 +1,183 @@
+//! Finding a device to play out of and hooking the daemon up to it.
+
+use std::sync::mpsc::Sender;
+
+use cpal::{
+  traits::{DeviceTrait, HostTrait, StreamTrait},
+  StreamError,
+};
+use eyre::{eyre, WrapErr};
+use log::{error, info, warn};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  model::{MsgThreadToUi, MsgUiToThread},
+  settings::DecomposerConfig,
+};
+
+use super::DecomposerAudioDaemont;
+
+/// Which output device to use, by name.
+///
+/// cpal doesn't have any more stable way to refer to devices than this.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OutputDeviceChoice {
+  pub host: String,
+  pub name: String,
+}
+
+/// A running output stream and the pipes to the daemon that lives in it.
+pub struct OutputStream {
+  pub tx_to_thread: Producer<MsgUiToThread>,
+  pub rx_from_thread: Consumer<MsgThreadToUi>,
+  pub stream: cpal::Stream,
+  pub device: OutputDeviceChoice,
+  pub channels: u16,
+}
+
+/// Every output device on every host.
+pub fn list_output_devices() -> Vec<OutputDeviceChoice> {
+  let mut out = Vec::new();
+  for host_id in cpal::available_hosts() {
+    let host = match cpal::host_from_id(host_id) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not open audio host {}: {}", host_id.name(), err);
+        continue;
+      }
+    };
+    let devices = match host.output_devices() {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not list devices on {}: {}", host_id.name(), err);
+        continue;
+      }
+    };
+    for device in devices {
+      if let Ok(name) = device.name() {
+        out.push(OutputDeviceChoice {
+          host: host_id.name().to_owned(),
+          name,
+        });
+      }
+    }
+  }
+  out
+}
+
+fn find_device(choice: &OutputDeviceChoice) -> Option<cpal::Device> {
+  let host_id = cpal::available_hosts()
+    .into_iter()
+    .find(|id| id.name() == choice.host)?;
+  let host = cpal::host_from_id(host_id).ok()?;
+  let mut devices = host.output_devices().ok()?;
+  devices.find(|device| device.name().is_ok_and(|n| n == choice.name))
+}
+
+fn default_device() -> Option<(cpal::Device, OutputDeviceChoice)> {
+  let host = cpal::default_host();
+  let device = host.default_output_device()?;
+  let choice = OutputDeviceChoice {
+    host: host.id().name().to_owned(),
+    name: device.name().unwrap_or_else(|_| "Default".to_owned()),
+  };
+  Some((device, choice))
+}
+
+/// Spin up a new daemon and a cpal stream for it to live in.
+///
+/// If the chosen device can't be found this falls back to the default one.
+/// Errors from the stream after it starts go to `errors`.
+pub fn open_output(
+  config: &DecomposerConfig,
+  choice: Option<&OutputDeviceChoice>,
+  errors: Sender<StreamError>,
+) -> eyre::Result<OutputStream> {
+  let found = choice.and_then(|choice| {
+    let device = find_device(choice);
+    if device.is_none() {
+      warn!("Could not find output device {:?}, using default", choice);
+    }
+    device.map(|device| (device, choice.clone()))
+  });
+  let (device, choice) = found
+    .or_else(default_device)
+    .ok_or_else(|| eyre!("There aren't any audio output devices"))?;
+  info!("Opening output device {:?}", &choice);
+
+  let default_cfg = device
+    .default_output_config()
+    .wrap_err_with(|| format!("Could not get config for {}", &choice.name))?;
+  let sample_rate = default_cfg.sample_rate();
+  let channels = match config.output_channels() {
+    Some(wanted) if supports_channels(&device, wanted, sample_rate) => wanted,
+    Some(wanted) => {
+      warn!(
+        "{} can't do {} channels, using its default of {}",
+        &choice.name,
+        wanted,
+        default_cfg.channels()
+      );
+      default_cfg.channels()
+    }
+    None => default_cfg.channels(),
+  };
+  let audio_cfg = cpal::StreamConfig {
+    channels,
+    sample_rate,
+    buffer_size: cpal::BufferSize::Default,
+  };
+
+  let (tx_to_thread, rx_from_ui) = RingBuffer::new(64);
+  let (tx_to_ui, rx_from_thread) = RingBuffer::new(256);
+
+  let mut looks_like_youre_going_to_the_shadow_thread_jimbo =
+    DecomposerAudioDaemont::new(
+      tx_to_ui,
+      rx_from_ui,
+      config,
+      sample_rate.0,
+      channels as usize,
+    );
+
+  let stream = device
+    .build_output_stream(
+      &audio_cfg,
+      move |data: &mut [f32], ci| {
+        looks_like_youre_going_to_the_shadow_thread_jimbo.process(data, ci);
+      },
+      move |err| {
+        error!("{}", err);
+        let _ignore = errors.send(err);
+      },
+      None,
+    )
+    .wrap_err_with(|| format!("Could not open stream on {}", &choice.name))?;
+  stream
+    .play()
+    .wrap_err_with(|| format!("Could not play stream on {}", &choice.name))?;
+
+  Ok(OutputStream {
+    tx_to_thread,
+    rx_from_thread,
+    stream,
+    device: choice,
+    channels,
+  })
+}
+
+fn supports_channels(
+  device: &cpal::Device,
+  channels: u16,
+  sample_rate: cpal::SampleRate,
+) -> bool {
+  let Ok(mut configs) = device.supported_output_configs() else {
+    return false;
+  };
+  configs.any(|cfg| {
+    cfg.channels() == channels
+      && cfg.min_sample_rate() <= sample_rate
+      && sample_rate <= cfg.max_sample_rate()
+  })
+}
@@ -1,6 +1,8 @@
 // Rust-analyzer seems to REALLY not like emoji,
 // so i will do the codes manually.
 pub const REPEAT: &str = "\u{1F501}";
+/// With a little 1 on it
+pub const REPEAT_ONE: &str = "\u{1F502}";
 pub const SHUFFLE: &str = "\u{1F500}";
 pub const PLAYING: &str = "\u{256B}";
 /// This requires some ZWJ bullshit or something
@@ -9,3 +11,5 @@ pub const PAUSING: &str = "\u{23F8}";
 pub const WIND_LEFT: &str = "\u{23EA}";
 /// Fast-forward (no bar on the end)
 pub const WIND_RIGHT: &str = "\u{23E9}";
+/// Anticlockwise arrows
+pub const REFRESH: &str = "\u{1F504}";
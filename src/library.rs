@@ -0,0 +1,917 @@
+//! Remembering what's in the library between launches, so we only have to
+//! read tags off files that changed.
+
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+
+use eyre::WrapErr;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+mod filter;
+mod scanner;
+mod watcher;
+
+pub use filter::*;
+pub use scanner::{LibraryScan, ScanProgress};
+pub use watcher::*;
+
+use scanner::{Known, ScanMsg};
+
+use crate::model::{
+  CueSheet, PlaylistEntry, Track, TrackLocator, TrackMeta, TrackSpan,
+};
+
+/// Bump this whenever `LibraryIndex` changes shape, and teach `migrate`
+/// about the old one.
+///
+/// - 1: path, size, mtime, metadata
+/// - 2: added `locator`
+/// - 3: added `cue` and `embedded_cue`
+/// - 4: added `root`
+pub const LIBRARY_SCHEMA_VERSION: u32 = 4;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LibraryIndex {
+  version: u32,
+  entries: HashMap<PathBuf, LibraryEntry>,
+  /// Whether there's anything that hasn't been saved yet
+  #[serde(skip)]
+  dirty: bool,
+  /// Roots that weren't there at the last scan, as they're written in the
+  /// config
+  #[serde(skip)]
+  offline: HashSet<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LibraryEntry {
+  pub size: u64,
+  pub mtime: Option<SystemTime>,
+  pub meta: TrackMeta,
+  /// None if the entry is from before we had them
+  #[serde(default)]
+  pub locator: Option<TrackLocator>,
+  /// From a `.cue` file next to this one. These are read fresh every scan
+  #[serde(default)]
+  pub cue: Option<CueSheet>,
+  /// From the file's own tags
+  #[serde(default)]
+  pub embedded_cue: Option<CueSheet>,
+  /// Which library root this was found under, as it's written in the config
+  #[serde(default)]
+  pub root: Option<PathBuf>,
+  /// Set when the entry is from an index that didn't read everything we
+  /// read now, so the tags need reading again even if the file hasn't
+  /// changed
+  #[serde(skip)]
+  pub stale_tags: bool,
+}
+
+/// Just enough to find out what version a file is before parsing the rest.
+#[derive(Deserialize)]
+struct VersionHeader {
+  version: u32,
+}
+
+/// What happened during a rescan.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScanStats {
+  pub added: usize,
+  pub updated: usize,
+  pub unchanged: usize,
+  pub removed: usize,
+  pub moved: usize,
+  /// Roots that weren't there
+  pub offline: usize,
+}
+
+/// Everything read off one file, ready to go in the index.
+///
+/// Reading it is the slow part, so this can be done somewhere else first.
+#[derive(Debug)]
+pub struct ScannedFile {
+  pub path: PathBuf,
+  /// As written in the config
+  pub root: PathBuf,
+  pub size: u64,
+  pub mtime: Option<SystemTime>,
+  pub meta: TrackMeta,
+  pub embedded_cue: Option<CueSheet>,
+  pub locator: Option<TrackLocator>,
+}
+
+impl ScannedFile {
+  /// `locator` is what it was last time, if the audio hasn't changed since.
+  /// Working it out means reading through the start of the audio, so it's
+  /// only done when there isn't one.
+  ///
+  /// Fails if the file can't be read, or isn't audio we understand.
+  pub fn read(
+    path: &Path,
+    root: &Path,
+    locator: Option<TrackLocator>,
+  ) -> eyre::Result<ScannedFile> {
+    let stat = fs::metadata(path)?;
+    let (meta, embedded_cue) =
+      TrackMeta::read_with_cue(path).wrap_err("Could not read tags")?;
+    let locator = match locator {
+      Some(it) => it,
+      None => {
+        TrackLocator::compute(path).wrap_err("Could not fingerprint it")?
+      }
+    };
+    Ok(ScannedFile {
+      path: path.to_path_buf(),
+      root: root.to_path_buf(),
+      size: stat.len(),
+      mtime: stat.modified().ok(),
+      meta,
+      embedded_cue,
+      locator: Some(locator),
+    })
+  }
+}
+
+/// What scanning one file did to its entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileChange {
+  Added,
+  Updated,
+}
+
+/// Somewhere music lives.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LibraryRoot {
+  pub path: PathBuf,
+  /// What to call it instead of the path
+  #[serde(default)]
+  pub label: Option<String>,
+  /// Disabled roots aren't scanned or shown, but what's known about them is
+  /// kept for when they come back
+  #[serde(default = "enabled_by_default")]
+  pub enabled: bool,
+}
+
+impl LibraryRoot {
+  pub fn new(path: PathBuf) -> LibraryRoot {
+    LibraryRoot {
+      path,
+      label: None,
+      enabled: true,
+    }
+  }
+
+  pub fn name(&self) -> String {
+    match &self.label {
+      Some(label) => label.clone(),
+      None => self.path.display().to_string(),
+    }
+  }
+}
+
+fn enabled_by_default() -> bool {
+  true
+}
+
+/// What happened when some changes were noticed while running.
+#[derive(Debug, Default)]
+pub struct LibraryChanges {
+  /// Files that are new or different, and what tracks they are now
+  pub tracks: Vec<(PathBuf, Vec<Track>)>,
+  /// Which of `tracks` weren't in the index at all before
+  pub new: HashSet<PathBuf>,
+  /// Files that are gone, not counting ones that moved
+  pub removed: Vec<PathBuf>,
+  /// `(from, to)` for files that were moved or renamed
+  pub moves: Vec<(PathBuf, PathBuf)>,
+  pub stats: ScanStats,
+}
+
+impl Default for LibraryIndex {
+  fn default() -> Self {
+    Self {
+      version: LIBRARY_SCHEMA_VERSION,
+      entries: HashMap::new(),
+      dirty: true,
+      offline: HashSet::new(),
+    }
+  }
+}
+
+impl LibraryEntry {
+  /// The sheet next to the file wins over the one inside it, since it's
+  /// easier to fix.
+  pub fn sheet(&self) -> Option<&CueSheet> {
+    self.cue.as_ref().or(self.embedded_cue.as_ref())
+  }
+
+  /// Every track in the file, which is just the file itself unless it has a
+  /// CUE sheet.
+  pub fn tracks(&self, path: &Path) -> Vec<Track> {
+    let tracks = self
+      .sheet()
+      .map(|sheet| sheet.expand(path, &self.meta))
+      .unwrap_or_default();
+    if !tracks.is_empty() {
+      return tracks;
+    }
+    vec![Track {
+      path: path.to_path_buf(),
+      meta: self.meta.clone(),
+      span: None,
+    }]
+  }
+}
+
+impl LibraryIndex {
+  pub fn open(path: &Path) -> LibraryIndex {
+    let src = match fs::read_to_string(path) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!(
+          "Could not open library index {:?}, starting fresh: {}",
+          path, err
+        );
+        return LibraryIndex::default();
+      }
+    };
+    let version = match ron::from_str::<VersionHeader>(&src) {
+      Ok(it) => it.version,
+      Err(err) => {
+        warn!(
+          "Could not find library index version in {:?}, starting fresh: {}",
+          path, err
+        );
+        return LibraryIndex::default();
+      }
+    };
+    match migrate(version, &src) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!(
+          "Could not load library index {:?}, starting fresh: {}",
+          path, err
+        );
+        LibraryIndex::default()
+      }
+    }
+  }
+
+  /// Only actually writes anything if something changed.
+  pub fn save(&mut self, path: &Path) {
+    if !self.dirty {
+      return;
+    }
+    let src = match ron::to_string(self) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not serialize library index: {}", err);
+        return;
+      }
+    };
+    if let Err(err) = fs::write(path, src.as_bytes()) {
+      warn!("Could not save library index to {:?}: {}", path, err);
+      return;
+    }
+    self.dirty = false;
+  }
+
+  /// What we know about a playlist entry without going to the disc.
+  pub fn meta(&self, entry: &PlaylistEntry) -> Option<TrackMeta> {
+    let found = self.entries.get(&entry.path)?;
+    found
+      .tracks(&entry.path)
+      .into_iter()
+      .find(|track| track.span == entry.span)
+      .map(|track| track.meta)
+  }
+
+  /// Something to put in a playlist that can find this track again.
+  pub fn playlist_entry(
+    &self,
+    path: &Path,
+    span: Option<TrackSpan>,
+  ) -> PlaylistEntry {
+    PlaylistEntry {
+      path: path.to_path_buf(),
+      locator: self.entries.get(path).and_then(|entry| entry.locator),
+      span,
+    }
+  }
+
+  /// Find the file a playlist entry means, even if it's moved since.
+  pub fn resolve(&self, entry: &PlaylistEntry) -> Option<Track> {
+    let found = self.entries.get_key_value(&entry.path).filter(|(_, e)| {
+      entry.locator.is_none()
+        || e.locator.is_none()
+        || e.locator == entry.locator
+    });
+    let found = found.or_else(|| {
+      let locator = entry.locator?;
+      self
+        .entries
+        .iter()
+        .find(|(_, e)| e.locator == Some(locator))
+    });
+    match found {
+      Some((path, e)) => e
+        .tracks(path)
+        .into_iter()
+        .find(|track| track.span == entry.span),
+      // Not somewhere we scan, but it might still be there
+      None if entry.path.is_file() => {
+        let (meta, cue) = match TrackMeta::read_with_cue(&entry.path) {
+          Ok(it) => it,
+          Err(err) => {
+            warn!("Could not read {:?}: {:#}", &entry.path, err);
+            return None;
+          }
+        };
+        let track = cue
+          .map(|cue| cue.expand(&entry.path, &meta))
+          .unwrap_or_default()
+          .into_iter()
+          .find(|track| track.span == entry.span);
+        track.or_else(|| {
+          // The sheet's gone, but the rest of the file is better than
+          // nothing
+          Some(Track {
+            path: entry.path.clone(),
+            meta,
+            span: entry.span,
+          })
+        })
+      }
+      None => None,
+    }
+  }
+
+  /// Look for something that seems like the same track when we don't know
+  /// exactly where it is: by title (and artist, if given), then by filename.
+  pub fn find_like(
+    &self,
+    title: Option<&str>,
+    artist: Option<&str>,
+    file_name: Option<&str>,
+  ) -> Option<PathBuf> {
+    let same = |a: &str, b: &str| a.trim().eq_ignore_ascii_case(b.trim());
+    // Lowest path wins, so the same playlist always finds the same files
+    let best = |it: &mut dyn Iterator<Item = &PathBuf>| it.min().cloned();
+
+    if let Some(title) = title {
+      let found = best(&mut self.entries.iter().filter_map(|(path, e)| {
+        let title_matches = same(&e.meta.title, title);
+        let artist_matches = match (artist, e.meta.display_artist()) {
+          (Some(want), Some(have)) => same(want, have),
+          (Some(_), None) => false,
+          (None, _) => true,
+        };
+        (title_matches && artist_matches).then_some(path)
+      }));
+      if found.is_some() {
+        return found;
+      }
+    }
+
+    let file_name = file_name?;
+    best(&mut self.entries.keys().filter(|path| {
+      path
+        .file_name()
+        .is_some_and(|name| same(&name.to_string_lossy(), file_name))
+    }))
+  }
+
+  /// Everything on the enabled roots, as of the last scan, in path order.
+  pub fn tracks(&self, roots: &[LibraryRoot]) -> Vec<Track> {
+    roots
+      .iter()
+      .filter(|root| root.enabled)
+      .flat_map(|root| self.tracks_from(&root.path))
+      .collect()
+  }
+
+  /// Every file on the root, as of the last scan, in path order.
+  pub fn files(&self, root: &Path) -> Vec<PathBuf> {
+    let mut paths = self
+      .entries
+      .iter()
+      .filter(|(_, entry)| entry.root.as_deref() == Some(root))
+      .map(|(path, _)| path.clone())
+      .collect::<Vec<_>>();
+    paths.sort();
+    paths
+  }
+
+  /// Start walking every enabled root in the background, reading tags only
+  /// off files that are new or have changed size or mtime since last time.
+  ///
+  /// Roots that aren't there right now keep everything they had, and their
+  /// tracks are unavailable until they're back.
+  pub fn start_scan(
+    &mut self,
+    roots: &[LibraryRoot],
+    settings: &ScanSettings,
+  ) -> LibraryScan {
+    let mut online = Vec::new();
+    let mut stats = ScanStats::default();
+    self.offline.clear();
+    for root in roots.iter().filter(|root| root.enabled) {
+      match self.online_dir(root) {
+        Some(dir) => online.push((root.path.clone(), dir)),
+        None => {
+          warn!(
+            "Library root {} is offline, keeping what we knew about it",
+            root.name()
+          );
+          stats.offline += 1;
+          self.offline.insert(root.path.clone());
+        }
+      }
+    }
+
+    LibraryScan::spawn(online, settings.clone(), self.known(), stats)
+  }
+
+  /// Start watching the roots a scan found, see `LibraryScan::roots`.
+  pub fn watch(
+    &self,
+    roots: Vec<(PathBuf, PathBuf)>,
+    settings: &ScanSettings,
+  ) -> eyre::Result<LibraryWatcher> {
+    LibraryWatcher::spawn(roots, settings.clone(), self.known())
+  }
+
+  /// What a scan or the watcher needs to know about what's already here.
+  fn known(&self) -> HashMap<PathBuf, Known> {
+    self
+      .entries
+      .iter()
+      .map(|(path, entry)| {
+        let known = Known {
+          size: entry.size,
+          mtime: entry.mtime,
+          stale: entry.stale_tags,
+          locator: entry.locator,
+        };
+        (path.clone(), known)
+      })
+      .collect()
+  }
+
+  /// Take in whatever the scan's found since last time.
+  ///
+  /// Once it's finished, files that weren't found are forgotten, and new
+  /// files with the same audio as a file that's gone count as that file
+  /// being moved. That's only done if the scan got all the way through.
+  pub fn poll_scan(&mut self, scan: &mut LibraryScan) -> LibraryChanges {
+    let mut out = LibraryChanges::default();
+    if scan.is_finished() {
+      return out;
+    }
+
+    for msg in scan.poll() {
+      match msg {
+        ScanMsg::Unchanged { path, root } => {
+          scan.stats.unchanged += 1;
+          scan.seen.insert(path.clone());
+          let Some(entry) = self.entries.get_mut(&path) else {
+            continue;
+          };
+          // Not from any root before, so it wasn't in the queue either
+          if entry.root.as_ref() != Some(&root) {
+            entry.root = Some(root);
+            self.dirty = true;
+            out.tracks.push((path.clone(), entry.tracks(&path)));
+          }
+        }
+        ScanMsg::Scanned(file) => {
+          let path = file.path.clone();
+          match self.insert_scanned(*file) {
+            FileChange::Added => {
+              scan.new_paths.push(path.clone());
+              out.new.insert(path.clone());
+            }
+            FileChange::Updated => scan.stats.updated += 1,
+          }
+          scan.seen.insert(path.clone());
+          if let Some(entry) = self.entries.get(&path) {
+            out.tracks.push((path.clone(), entry.tracks(&path)));
+          }
+        }
+        ScanMsg::Failed { path, error } => {
+          warn!("Could not scan {:?}: {}", &path, error);
+          // Still there as far as we know, so don't forget it
+          scan.seen.insert(path);
+        }
+        ScanMsg::Cue(path) => scan.cue_paths.push(path),
+        ScanMsg::RootDone(dir) => scan.walked.push(dir),
+        ScanMsg::Queued => {}
+        ScanMsg::Done => self.finish_scan(scan, &mut out),
+      }
+    }
+    out
+  }
+
+  fn finish_scan(&mut self, scan: &mut LibraryScan, out: &mut LibraryChanges) {
+    let found = scan.seen.iter().cloned().collect::<Vec<_>>();
+    let cue_paths = std::mem::take(&mut scan.cue_paths);
+    for path in self.attach_sheets(&found, cue_paths, &scan.seen) {
+      if let Some(entry) = self.entries.get(&path) {
+        out.tracks.push((path.clone(), entry.tracks(&path)));
+      }
+    }
+
+    // Only forget things under roots we looked all the way through; the
+    // rest might be somewhere that just isn't being scanned right now. A
+    // cancelled scan might not have heard back about everything it walked
+    let mut gone = HashMap::new();
+    let walked = if scan.is_cancelled() {
+      &[][..]
+    } else {
+      &scan.walked[..]
+    };
+    let seen = &scan.seen;
+    self.entries.retain(|path, entry| {
+      let keep =
+        seen.contains(path) || !walked.iter().any(|dir| path.starts_with(dir));
+      if !keep {
+        if let Some(locator) = entry.locator {
+          gone.insert(locator, path.clone());
+        }
+        out.removed.push(path.clone());
+      }
+      keep
+    });
+    for path in scan.new_paths.drain(..) {
+      let locator = self.entries.get(&path).and_then(|entry| entry.locator);
+      match locator.and_then(|locator| gone.remove(&locator)) {
+        Some(from) => {
+          scan.stats.moved += 1;
+          out.moves.push((from, path));
+        }
+        None => scan.stats.added += 1,
+      }
+    }
+    out
+      .removed
+      .retain(|path| !out.moves.iter().any(|(from, _)| from == path));
+    scan.stats.removed = out.removed.len();
+    if !out.removed.is_empty() || !out.moves.is_empty() {
+      self.dirty = true;
+    }
+
+    out.stats = scan.stats;
+    if scan.is_cancelled() {
+      info!("Library scan was cancelled: {:?}", &scan.stats);
+    } else {
+      info!("Scanned library: {:?}", &scan.stats);
+    }
+  }
+
+  /// Take in what the watcher noticed.
+  pub fn apply(&mut self, batch: WatchBatch) -> LibraryChanges {
+    let mut out = LibraryChanges::default();
+
+    let mut gone = HashMap::new();
+    for path in &batch.gone {
+      // Could be a whole folder
+      let doomed = self
+        .entries
+        .keys()
+        .filter(|entry| entry.starts_with(path))
+        .cloned()
+        .collect::<Vec<_>>();
+      for path in doomed {
+        let Some(entry) = self.entries.remove(&path) else {
+          continue;
+        };
+        if let Some(locator) = entry.locator {
+          gone.insert(locator, path.clone());
+        }
+        out.removed.push(path);
+        self.dirty = true;
+      }
+    }
+
+    let mut changed = Vec::new();
+    for file in batch.files {
+      let path = file.path.clone();
+      let locator = file.locator;
+      match self.insert_scanned(file) {
+        FileChange::Added => {
+          out.new.insert(path.clone());
+          match locator.and_then(|locator| gone.remove(&locator)) {
+            Some(from) => {
+              out.stats.moved += 1;
+              out.moves.push((from, path.clone()));
+            }
+            None => out.stats.added += 1,
+          }
+        }
+        _ => out.stats.updated += 1,
+      }
+      changed.push(path);
+    }
+    out
+      .removed
+      .retain(|path| !out.moves.iter().any(|(from, _)| from == path));
+    out.stats.removed = out.removed.len();
+
+    for dir in &batch.dirs {
+      changed.extend(self.reattach_sheets(dir));
+    }
+    changed.sort();
+    changed.dedup();
+    out.tracks = changed
+      .into_iter()
+      .filter_map(|path| {
+        let tracks = self.entries.get(&path)?.tracks(&path);
+        Some((path, tracks))
+      })
+      .collect();
+
+    info!("Library changed while running: {:?}", &out.stats);
+    out
+  }
+
+  /// False for files on a root that was offline at the last scan.
+  pub fn is_available(&self, path: &Path) -> bool {
+    let root = self.entries.get(path).and_then(|entry| entry.root.as_ref());
+    root.is_none_or(|root| !self.offline.contains(root))
+  }
+
+  /// Put in what was read off a file, replacing whatever was there.
+  ///
+  /// A sheet next to the file stays, since that isn't part of the file.
+  fn insert_scanned(&mut self, file: ScannedFile) -> FileChange {
+    let old = self.entries.remove(&file.path);
+    let change = if old.is_some() {
+      FileChange::Updated
+    } else {
+      FileChange::Added
+    };
+    let entry = LibraryEntry {
+      size: file.size,
+      mtime: file.mtime,
+      meta: file.meta,
+      locator: file.locator,
+      cue: old.and_then(|old| old.cue),
+      embedded_cue: file.embedded_cue,
+      root: Some(file.root),
+      stale_tags: false,
+    };
+    self.entries.insert(file.path, entry);
+    self.dirty = true;
+    change
+  }
+
+  /// Give each file the `.cue` sheet next to it that's about it, if any,
+  /// and return the ones whose sheet changed.
+  fn attach_sheets(
+    &mut self,
+    found: &[PathBuf],
+    cue_paths: Vec<PathBuf>,
+    seen: &HashSet<PathBuf>,
+  ) -> Vec<PathBuf> {
+    let mut sheets = HashMap::new();
+    for cue_path in cue_paths {
+      let sheet = match CueSheet::open(&cue_path) {
+        Ok(it) => it,
+        Err(err) => {
+          warn!("Could not read CUE sheet {:?}: {}", &cue_path, err);
+          continue;
+        }
+      };
+      for path in sheet_targets(&cue_path, &sheet, seen) {
+        sheets.insert(path, sheet.clone());
+      }
+    }
+    let mut changed = Vec::new();
+    for path in found {
+      let Some(entry) = self.entries.get_mut(path) else {
+        continue;
+      };
+      let sheet = sheets.remove(path);
+      if entry.cue != sheet {
+        entry.cue = sheet;
+        changed.push(path.clone());
+        self.dirty = true;
+      }
+    }
+    changed
+  }
+
+  /// Read the sheets in one folder again.
+  fn reattach_sheets(&mut self, dir: &Path) -> Vec<PathBuf> {
+    let cue_paths = match fs::read_dir(dir) {
+      Ok(it) => it
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_cue(path))
+        .collect(),
+      // Gone, so there's nothing left in it to change
+      Err(_) => return Vec::new(),
+    };
+    let found = self
+      .entries
+      .keys()
+      .filter(|path| path.parent() == Some(dir))
+      .cloned()
+      .collect::<Vec<_>>();
+    let seen = found.iter().cloned().collect();
+    self.attach_sheets(&found, cue_paths, &seen)
+  }
+
+  /// Everything we remember being on a root, in path order.
+  fn tracks_from(&self, root: &Path) -> Vec<Track> {
+    self
+      .files(root)
+      .into_iter()
+      .flat_map(|path| self.entries[&path].tracks(&path))
+      .collect()
+  }
+
+  /// Where the root really is, or None if it's not there right now.
+  fn online_dir(&self, root: &LibraryRoot) -> Option<PathBuf> {
+    let dir = root.path.canonicalize().ok().filter(|dir| dir.is_dir())?;
+    // An unmounted drive usually leaves an empty folder behind, and we don't
+    // want to forget everything that was on it
+    let empty = !fs::read_dir(&dir).is_ok_and(|mut it| it.next().is_some());
+    let had_files = self
+      .entries
+      .values()
+      .any(|entry| entry.root.as_deref() == Some(&root.path));
+    if empty && had_files {
+      return None;
+    }
+    Some(dir)
+  }
+}
+
+fn is_cue(path: &Path) -> bool {
+  path
+    .extension()
+    .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Which of the files we found a CUE sheet is talking about.
+///
+/// Its `FILE`s are relative to the sheet. If none of them are there, a sheet
+/// for one file goes with whatever's next to it with the same name, since
+/// that's usually what it was converted to.
+fn sheet_targets(
+  cue_path: &Path,
+  sheet: &CueSheet,
+  found: &HashSet<PathBuf>,
+) -> Vec<PathBuf> {
+  let dir = cue_path.parent().unwrap_or(Path::new(""));
+  let targets = sheet
+    .files
+    .iter()
+    .map(|file| dir.join(file.name.replace('\\', "/")))
+    .filter(|path| found.contains(path))
+    .collect::<Vec<_>>();
+  if !targets.is_empty() || sheet.files.len() != 1 {
+    return targets;
+  }
+
+  let stem = cue_path.file_stem();
+  let mut siblings = found
+    .iter()
+    .filter(|path| path.parent() == Some(dir) && path.file_stem() == stem)
+    .cloned()
+    .collect::<Vec<_>>();
+  // Lowest path wins so it's always the same one
+  siblings.sort();
+  siblings.truncate(1);
+  siblings
+}
+
+/// Turn whatever version of the index was on disc into the current one.
+fn migrate(version: u32, src: &str) -> eyre::Result<LibraryIndex> {
+  match version {
+    LIBRARY_SCHEMA_VERSION => {
+      let index = ron::from_str::<LibraryIndex>(src)?;
+      Ok(index)
+    }
+    // Same shape, just without locators and CUE sheets. Locators get
+    // filled in on the next rescan, and the tags get read again for any
+    // embedded sheets
+    1 | 2 => {
+      let mut index = ron::from_str::<LibraryIndex>(src)?;
+      info!("Migrating library index from version {}", version);
+      for entry in index.entries.values_mut() {
+        entry.stale_tags = true;
+      }
+      index.version = LIBRARY_SCHEMA_VERSION;
+      index.dirty = true;
+      Ok(index)
+    }
+    // Same shape, just without roots. Those get filled in on the next
+    // rescan
+    3 => {
+      let mut index = ron::from_str::<LibraryIndex>(src)?;
+      info!("Migrating library index from version 3");
+      index.version = LIBRARY_SCHEMA_VERSION;
+      index.dirty = true;
+      Ok(index)
+    }
+    _ => eyre::bail!(
+      "don't know how to read version {} (this is version {})",
+      version,
+      LIBRARY_SCHEMA_VERSION
+    ),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const V1: &str = r#"(
+    version: 1,
+    entries: {
+      "/music/a.flac": (
+        size: 1000,
+        mtime: None,
+        meta: (title: "A", artist: Some("Someone"), track_number: Some(1)),
+      ),
+    },
+  )"#;
+
+  const V3: &str = r#"(
+    version: 3,
+    entries: {
+      "/music/a.flac": (
+        size: 1000,
+        mtime: None,
+        meta: (title: "A"),
+        locator: Some((fingerprint: 1234, duration: Some(44100))),
+      ),
+    },
+  )"#;
+
+  fn only_entry(index: &LibraryIndex) -> &LibraryEntry {
+    assert_eq!(index.entries.len(), 1);
+    &index.entries[Path::new("/music/a.flac")]
+  }
+
+  #[test]
+  fn version_1_gets_read_again() {
+    let index = migrate(1, V1).unwrap();
+    assert_eq!(index.version, LIBRARY_SCHEMA_VERSION);
+    assert!(index.dirty);
+    let entry = only_entry(&index);
+    assert_eq!(entry.size, 1000);
+    assert_eq!(entry.meta.title, "A");
+    assert_eq!(entry.meta.artist.as_deref(), Some("Someone"));
+    // Everything it didn't have gets filled in by the next scan
+    assert!(entry.stale_tags);
+    assert_eq!(entry.locator, None);
+    assert!(entry.cue.is_none() && entry.embedded_cue.is_none());
+    assert_eq!(entry.root, None);
+  }
+
+  #[test]
+  fn version_3_keeps_locators() {
+    let index = migrate(3, V3).unwrap();
+    assert_eq!(index.version, LIBRARY_SCHEMA_VERSION);
+    let entry = only_entry(&index);
+    assert!(!entry.stale_tags);
+    assert_eq!(entry.locator.map(|it| it.fingerprint), Some(1234));
+    assert_eq!(entry.root, None);
+  }
+
+  #[test]
+  fn newer_versions_start_fresh() {
+    let src = V1.replace("version: 1", "version: 999");
+    assert!(migrate(999, &src).is_err());
+
+    let path = std::env::temp_dir().join("decomposer-test-future-index.ron");
+    fs::write(&path, src).unwrap();
+    let index = LibraryIndex::open(&path);
+    let _ = fs::remove_file(&path);
+    assert!(index.entries.is_empty());
+  }
+
+  #[test]
+  fn saved_index_opens_the_same() {
+    let mut index = migrate(1, V1).unwrap();
+    let path = std::env::temp_dir().join("decomposer-test-index.ron");
+    index.save(&path);
+    assert!(!index.dirty);
+    let opened = LibraryIndex::open(&path);
+    let _ = fs::remove_file(&path);
+
+    assert!(!opened.dirty);
+    let entry = only_entry(&opened);
+    assert_eq!(entry.meta, only_entry(&index).meta);
+    // Saved at the current version, so it doesn't get read again
+    assert!(!entry.stale_tags);
+  }
+}
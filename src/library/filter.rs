@@ -0,0 +1,315 @@
+//! Deciding which files under a root are worth putting in the library.
+
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{model::open_format, util::WalkOptions};
+
+/// Put one of these in a folder to keep things under it out of the library.
+/// It works like a `.gitignore`.
+pub const IGNORE_FILE_NAME: &str = ".decomposerignore";
+
+/// What Symphonia can open with every codec turned on.
+const AUDIO_EXTENSIONS: &[&str] = &[
+  "aac", "adts", "aif", "aifc", "aiff", "alac", "caf", "flac", "m4a", "m4b",
+  "mka", "mkv", "mp1", "mp2", "mp3", "mp4", "oga", "ogg", "wav", "wave",
+  "webm",
+];
+/// Things that are always next to music but never music themselves, so we
+/// don't bother opening them to check.
+const NOT_AUDIO_EXTENSIONS: &[&str] = &[
+  "accurip", "bmp", "db", "gif", "ini", "jpeg", "jpg", "json", "log", "m3u",
+  "m3u8", "md", "md5", "nfo", "pdf", "pls", "png", "ron", "sfv", "txt", "webp",
+  "xspf",
+];
+/// Not audio, but the library wants to see them anyway.
+const SIDECAR_EXTENSIONS: &[&str] = &["cue"];
+
+/// Which files the library scan looks at, on top of the ignore files.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScanSettings {
+  /// If there are any, only files matching one of these get in
+  #[serde(default)]
+  pub include: Vec<String>,
+  /// Anything matching one of these is left out, even if it's included
+  #[serde(default)]
+  pub exclude: Vec<String>,
+  #[serde(default = "follow_by_default")]
+  pub follow_symlinks: bool,
+  /// Things like `.git` and `.Trash-1000` are skipped unless this is on
+  #[serde(default)]
+  pub include_hidden: bool,
+  /// How many folders down from a root to look. None is all the way
+  #[serde(default)]
+  pub max_depth: Option<usize>,
+}
+
+impl Default for ScanSettings {
+  fn default() -> Self {
+    Self {
+      include: Vec::new(),
+      exclude: Vec::new(),
+      follow_symlinks: follow_by_default(),
+      include_hidden: false,
+      max_depth: None,
+    }
+  }
+}
+
+impl ScanSettings {
+  pub fn walk_options(&self) -> WalkOptions {
+    WalkOptions {
+      follow_symlinks: self.follow_symlinks,
+      include_hidden: self.include_hidden,
+      max_depth: self.max_depth,
+    }
+  }
+}
+
+fn follow_by_default() -> bool {
+  true
+}
+
+/// Everything that decides whether a file gets scanned, compiled and ready.
+///
+/// Globs are matched against paths relative to the root.
+#[derive(Debug)]
+pub struct ScanFilter {
+  root: PathBuf,
+  include: Option<GlobSet>,
+  exclude: GlobSet,
+  /// Ignore files found so far, with the folder each one is in
+  ignores: Vec<(PathBuf, Gitignore)>,
+}
+
+impl ScanFilter {
+  pub fn new(root: &Path, settings: &ScanSettings) -> ScanFilter {
+    let include =
+      (!settings.include.is_empty()).then(|| compile(&settings.include));
+    ScanFilter {
+      root: root.to_path_buf(),
+      include,
+      exclude: compile(&settings.exclude),
+      ignores: Vec::new(),
+    }
+  }
+
+  /// Call this on every folder before looking at what's in it, so its
+  /// ignore file gets picked up.
+  pub fn enter_dir(&mut self, dir: &Path) {
+    // Folders we've walked back out of don't count any more
+    self.ignores.retain(|(owner, _)| dir.starts_with(owner));
+
+    let path = dir.join(IGNORE_FILE_NAME);
+    if !path.is_file() {
+      return;
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(err) = builder.add(&path) {
+      warn!("Problem reading {:?}: {}", &path, err);
+    }
+    match builder.build() {
+      Ok(it) => self.ignores.push((dir.to_path_buf(), it)),
+      Err(err) => warn!("Could not use {:?}: {}", &path, err),
+    }
+  }
+
+  /// Pick up the ignore files between the root and `path`, and say whether
+  /// a walk from the root would have got as far as it.
+  pub fn reaches(&mut self, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(&self.root) else {
+      return false;
+    };
+    let mut dir = self.root.clone();
+    self.enter_dir(&dir);
+    let mut parts = relative.components().peekable();
+    while let Some(part) = parts.next() {
+      // The last one is `path` itself
+      if parts.peek().is_none() {
+        break;
+      }
+      dir.push(part);
+      if !self.allows_dir(&dir) {
+        return false;
+      }
+      self.enter_dir(&dir);
+    }
+    true
+  }
+
+  /// Whether to go into this folder at all.
+  pub fn allows_dir(&self, dir: &Path) -> bool {
+    !self.is_ignored(dir, true)
+  }
+
+  /// Whether this file should end up in the library.
+  pub fn allows_file(&self, path: &Path) -> bool {
+    if self.is_ignored(path, false) {
+      return false;
+    }
+    if let Some(include) = &self.include {
+      if !include.is_match(self.relative(path)) {
+        return false;
+      }
+    }
+    is_audio_file(path) || has_extension(path, SIDECAR_EXTENSIONS)
+  }
+
+  fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+    if path
+      .file_name()
+      .is_some_and(|name| name == IGNORE_FILE_NAME)
+    {
+      return true;
+    }
+    if self.exclude.is_match(self.relative(path)) {
+      return true;
+    }
+    // The deepest one gets the last word, like git
+    for (_, ignore) in self.ignores.iter().rev() {
+      let matched = ignore.matched(path, is_dir);
+      if matched.is_ignore() {
+        return true;
+      }
+      if matched.is_whitelist() {
+        return false;
+      }
+    }
+    false
+  }
+
+  fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+    path.strip_prefix(&self.root).unwrap_or(path)
+  }
+}
+
+/// Whether Symphonia can probably play this.
+///
+/// Going by the extension when we know it, and otherwise actually having a
+/// look inside.
+pub fn is_audio_file(path: &Path) -> bool {
+  if has_extension(path, AUDIO_EXTENSIONS) {
+    return true;
+  }
+  if has_extension(path, NOT_AUDIO_EXTENSIONS)
+    || has_extension(path, SIDECAR_EXTENSIONS)
+  {
+    return false;
+  }
+  // Dotfiles like `.DS_Store` have no extension as far as `Path` knows
+  if path
+    .file_name()
+    .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+  {
+    return false;
+  }
+  open_format(path).is_ok()
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+  let Some(ext) = path.extension() else {
+    return false;
+  };
+  let ext = ext.to_string_lossy().to_ascii_lowercase();
+  extensions.contains(&ext.as_str())
+}
+
+/// Bad globs are left out with a warning rather than stopping the scan.
+fn compile(globs: &[String]) -> GlobSet {
+  let mut builder = GlobSetBuilder::new();
+  for glob in globs {
+    match Glob::new(glob) {
+      Ok(it) => {
+        builder.add(it);
+      }
+      Err(err) => warn!("Skipping bad glob {:?}: {}", glob, err),
+    }
+  }
+  builder.build().unwrap_or_else(|err| {
+    warn!("Could not use scan globs: {}", err);
+    GlobSet::empty()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+
+  /// An empty folder of our own to make files in.
+  fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "decomposer-filter-{}-{}",
+      name,
+      std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.canonicalize().unwrap()
+  }
+
+  fn write(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+  }
+
+  #[test]
+  fn globs_include_and_exclude() {
+    let root = Path::new("/music");
+    let settings = ScanSettings {
+      include: vec!["**/*.flac".to_owned(), "**/*.cue".to_owned()],
+      exclude: vec!["**/Bootlegs".to_owned(), "**/*live*".to_owned()],
+      ..Default::default()
+    };
+    let filter = ScanFilter::new(root, &settings);
+    assert!(filter.allows_file(&root.join("Artist/Album/01.flac")));
+    assert!(filter.allows_file(&root.join("Artist/Album/album.cue")));
+    assert!(!filter.allows_file(&root.join("Artist/Album/01.mp3")));
+    assert!(!filter.allows_file(&root.join("Artist/Album/02 live.flac")));
+    assert!(!filter.allows_dir(&root.join("Artist/Bootlegs")));
+    assert!(filter.allows_dir(&root.join("Artist/Album")));
+  }
+
+  #[test]
+  fn only_audio_and_sheets_get_in() {
+    let root = Path::new("/music");
+    let filter = ScanFilter::new(root, &ScanSettings::default());
+    assert!(filter.allows_file(&root.join("01.FLAC")));
+    assert!(filter.allows_file(&root.join("album.cue")));
+    assert!(!filter.allows_file(&root.join("cover.jpg")));
+    assert!(!filter.allows_file(&root.join("album.m3u8")));
+    assert!(!filter.allows_file(&root.join(IGNORE_FILE_NAME)));
+  }
+
+  #[test]
+  fn ignore_files_work_like_gitignore() {
+    let root = scratch("ignore");
+    write(&root, IGNORE_FILE_NAME, "*.mp3\n!keep.mp3\nDemos/\n");
+    write(&root, "Artist/.decomposerignore", "!deep.mp3\n");
+
+    let mut filter = ScanFilter::new(&root, &ScanSettings::default());
+    assert!(filter.reaches(&root.join("a.mp3")));
+    assert!(!filter.allows_file(&root.join("a.mp3")));
+    assert!(filter.allows_file(&root.join("keep.mp3")));
+    assert!(filter.allows_file(&root.join("a.flac")));
+    assert!(!filter.allows_dir(&root.join("Demos")));
+    // Nothing under an ignored folder gets looked at
+    assert!(!filter.reaches(&root.join("Demos/x/01.flac")));
+
+    // The deepest ignore file gets the last word
+    assert!(filter.reaches(&root.join("Artist/deep.mp3")));
+    assert!(filter.allows_file(&root.join("Artist/deep.mp3")));
+    assert!(!filter.allows_file(&root.join("Artist/other.mp3")));
+    // and stops counting once we're back out of its folder
+    assert!(filter.reaches(&root.join("deep.mp3")));
+    assert!(!filter.allows_file(&root.join("deep.mp3")));
+
+    let _ = fs::remove_dir_all(&root);
+  }
+}
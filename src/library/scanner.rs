@@ -0,0 +1,284 @@
+//! Scanning the library in the background, so the window comes up straight
+//! away with whatever we knew from last time.
+
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex,
+  },
+  thread::{self, JoinHandle},
+  time::SystemTime,
+};
+
+use log::{error, info, warn};
+
+use crate::{model::TrackLocator, util};
+
+use super::{is_cue, ScanFilter, ScanSettings, ScanStats, ScannedFile};
+
+/// Most threads reading tags at once. It's mostly waiting on the disc, and
+/// a spinning one doesn't like being asked for too many things at once.
+const MAX_WORKERS: usize = 8;
+/// How many files can be waiting for a worker before the walk holds off.
+const QUEUED_FILES: usize = 256;
+
+/// What the index said about a file when the scan started, so the walk can
+/// tell what needs reading without asking it.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Known {
+  pub size: u64,
+  pub mtime: Option<SystemTime>,
+  /// Left over from an older index, so it needs reading anyway
+  pub stale: bool,
+  pub locator: Option<TrackLocator>,
+}
+
+/// Something the scan found, in whatever order it found it.
+#[derive(Debug)]
+pub(super) enum ScanMsg {
+  /// Same size and mtime as last time, so not read again
+  Unchanged {
+    path: PathBuf,
+    root: PathBuf,
+  },
+  /// Sent off to be read
+  Queued,
+  Cue(PathBuf),
+  Scanned(Box<ScannedFile>),
+  Failed {
+    path: PathBuf,
+    error: String,
+  },
+  /// All the way through one root, as it really is
+  RootDone(PathBuf),
+  Done,
+}
+
+/// How far along a scan is, for showing off.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScanProgress {
+  pub seen: usize,
+  /// Had their tags read
+  pub parsed: usize,
+  pub errors: usize,
+}
+
+/// A scan that's going on.
+pub struct LibraryScan {
+  rx: Receiver<ScanMsg>,
+  cancel: Arc<AtomicBool>,
+  /// `(as written in the config, where it really is)`
+  roots: Vec<(PathBuf, PathBuf)>,
+  pub progress: ScanProgress,
+  finished: bool,
+
+  // Bookkeeping for when it's done
+  pub(super) seen: HashSet<PathBuf>,
+  pub(super) new_paths: Vec<PathBuf>,
+  pub(super) cue_paths: Vec<PathBuf>,
+  /// Roots that were walked all the way through
+  pub(super) walked: Vec<PathBuf>,
+  pub(super) stats: ScanStats,
+}
+
+impl LibraryScan {
+  pub(super) fn spawn(
+    roots: Vec<(PathBuf, PathBuf)>,
+    settings: ScanSettings,
+    known: HashMap<PathBuf, Known>,
+    stats: ScanStats,
+  ) -> LibraryScan {
+    let (tx, rx) = mpsc::channel();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let walk_roots = roots.clone();
+    let walk_cancel = Arc::clone(&cancel);
+    let spawned = thread::Builder::new()
+      .name("library scan".to_owned())
+      .spawn(move || walk(walk_roots, settings, known, tx, walk_cancel));
+    let mut scan = LibraryScan {
+      rx,
+      cancel,
+      roots,
+      progress: ScanProgress::default(),
+      finished: false,
+      seen: HashSet::new(),
+      new_paths: Vec::new(),
+      cue_paths: Vec::new(),
+      walked: Vec::new(),
+      stats,
+    };
+    if let Err(err) = spawned {
+      error!("Could not spawn library scan thread: {}", err);
+      scan.finished = true;
+    }
+    scan
+  }
+
+  /// Stop as soon as whatever's being read right now is done.
+  ///
+  /// Nothing gets forgotten from a scan that didn't finish.
+  pub fn cancel(&self) {
+    self.cancel.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancel.load(Ordering::Relaxed)
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.finished
+  }
+
+  /// `(as written in the config, where it really is)` for every root that
+  /// was there to scan.
+  pub fn roots(&self) -> &[(PathBuf, PathBuf)] {
+    &self.roots
+  }
+
+  /// Everything it's said since last time, keeping count as it goes.
+  pub(super) fn poll(&mut self) -> Vec<ScanMsg> {
+    let msgs = self.rx.try_iter().collect::<Vec<_>>();
+    for msg in &msgs {
+      match msg {
+        ScanMsg::Unchanged { .. } | ScanMsg::Queued | ScanMsg::Cue(_) => {
+          self.progress.seen += 1
+        }
+        ScanMsg::Scanned(_) => self.progress.parsed += 1,
+        ScanMsg::Failed { .. } => self.progress.errors += 1,
+        ScanMsg::Done => self.finished = true,
+        ScanMsg::RootDone(_) => {}
+      }
+    }
+    msgs
+  }
+}
+
+fn walk(
+  roots: Vec<(PathBuf, PathBuf)>,
+  settings: ScanSettings,
+  known: HashMap<PathBuf, Known>,
+  tx: Sender<ScanMsg>,
+  cancel: Arc<AtomicBool>,
+) {
+  let (tx_jobs, rx_jobs) = mpsc::sync_channel(QUEUED_FILES);
+  let workers = spawn_workers(rx_jobs, &tx, &cancel);
+
+  'roots: for (root, dir) in &roots {
+    let filter = ScanFilter::new(dir, &settings);
+    let paths = util::get_all_children(dir)
+      .with_options(settings.walk_options())
+      .filtered(filter);
+    for path in paths {
+      if cancel.load(Ordering::Relaxed) {
+        break 'roots;
+      }
+      if is_cue(&path) {
+        let _ignore = tx.send(ScanMsg::Cue(path));
+        continue;
+      }
+      let known = known.get(&path);
+      let locator = match needs_reading(&path, known) {
+        Reading::No => {
+          let root = root.clone();
+          let _ignore = tx.send(ScanMsg::Unchanged { path, root });
+          continue;
+        }
+        // Only the tags are out of date, so the audio's the same as it was
+        Reading::TagsOnly => known.and_then(|known| known.locator),
+        Reading::All => None,
+      };
+      let _ignore = tx.send(ScanMsg::Queued);
+      if tx_jobs.send((path, root.clone(), locator)).is_err() {
+        // Every worker's gone, so there's no point carrying on
+        break 'roots;
+      }
+    }
+    if !cancel.load(Ordering::Relaxed) {
+      let _ignore = tx.send(ScanMsg::RootDone(dir.clone()));
+    }
+  }
+
+  drop(tx_jobs);
+  for worker in workers {
+    let _ignore = worker.join();
+  }
+  info!("Library scan thread is done");
+  let _ignore = tx.send(ScanMsg::Done);
+}
+
+/// How much of a file needs reading again.
+enum Reading {
+  No,
+  TagsOnly,
+  All,
+}
+
+/// How much of the file has changed from what the index knows.
+fn needs_reading(path: &Path, known: Option<&Known>) -> Reading {
+  let Some(known) = known else {
+    return Reading::All;
+  };
+  match fs::metadata(path) {
+    Ok(stat) => {
+      if known.size != stat.len() || known.mtime != stat.modified().ok() {
+        Reading::All
+      } else if known.stale {
+        Reading::TagsOnly
+      } else {
+        Reading::No
+      }
+    }
+    // Let a worker have a go so it gets reported properly
+    Err(_) => Reading::All,
+  }
+}
+
+fn spawn_workers(
+  rx_jobs: Receiver<(PathBuf, PathBuf, Option<TrackLocator>)>,
+  tx: &Sender<ScanMsg>,
+  cancel: &Arc<AtomicBool>,
+) -> Vec<JoinHandle<()>> {
+  let count = thread::available_parallelism()
+    .map_or(1, |it| it.get())
+    .min(MAX_WORKERS);
+  let rx_jobs = Arc::new(Mutex::new(rx_jobs));
+  (0..count)
+    .filter_map(|i| {
+      let rx_jobs = Arc::clone(&rx_jobs);
+      let tx = tx.clone();
+      let cancel = Arc::clone(cancel);
+      let spawned = thread::Builder::new()
+        .name(format!("library scan worker {}", i))
+        .spawn(move || loop {
+          let job = rx_jobs.lock().map(|rx| rx.recv());
+          let Ok(Ok((path, root, locator))) = job else {
+            return;
+          };
+          if cancel.load(Ordering::Relaxed) {
+            continue;
+          }
+          let msg = match ScannedFile::read(&path, &root, locator) {
+            Ok(file) => ScanMsg::Scanned(Box::new(file)),
+            Err(err) => ScanMsg::Failed {
+              path,
+              error: format!("{:#}", err),
+            },
+          };
+          if tx.send(msg).is_err() {
+            return;
+          }
+        });
+      match spawned {
+        Ok(it) => Some(it),
+        Err(err) => {
+          warn!("Could not spawn library scan worker: {}", err);
+          None
+        }
+      }
+    })
+    .collect()
+}
@@ -0,0 +1,228 @@
+//! Noticing files being added, changed and removed under the library roots
+//! while we're running.
+
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  path::{Path, PathBuf},
+  sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+  thread,
+  time::{Duration, Instant},
+};
+
+use eyre::WrapErr;
+use log::{debug, error, warn};
+use notify::{
+  event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode,
+  Watcher,
+};
+
+use crate::util;
+
+use super::{is_cue, Known, ScanFilter, ScanSettings, ScannedFile};
+
+/// Wait for things to be quiet this long before doing anything, so copying
+/// an album in is one batch instead of hundreds.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// But don't wait forever if something keeps writing.
+const MAX_WAIT: Duration = Duration::from_secs(20);
+
+/// Everything that changed in one burst.
+#[derive(Debug, Default)]
+pub struct WatchBatch {
+  /// New or changed, already read
+  pub files: Vec<ScannedFile>,
+  /// Files or whole folders that aren't there any more
+  pub gone: Vec<PathBuf>,
+  /// Folders something happened in, whose CUE sheets might need another look
+  pub dirs: Vec<PathBuf>,
+}
+
+/// Watches the roots and reads whatever changes on its own thread.
+pub struct LibraryWatcher {
+  /// Stops watching when dropped
+  _watcher: RecommendedWatcher,
+  rx: Receiver<WatchBatch>,
+}
+
+impl LibraryWatcher {
+  /// `roots` is `(as written in the config, where it really is)`.
+  pub(super) fn spawn(
+    roots: Vec<(PathBuf, PathBuf)>,
+    settings: ScanSettings,
+    known: HashMap<PathBuf, Known>,
+  ) -> eyre::Result<LibraryWatcher> {
+    let (tx_events, rx_events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx_events)
+      .wrap_err("could not start watching the library")?;
+    for (_, dir) in &roots {
+      if let Err(err) = watcher.watch(dir, RecursiveMode::Recursive) {
+        warn!("Could not watch {:?} for changes: {}", dir, err);
+      }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+      .name("library watcher".to_owned())
+      .spawn(move || work(rx_events, tx, roots, settings, known))
+      .wrap_err("could not spawn library watcher thread")?;
+    Ok(LibraryWatcher {
+      _watcher: watcher,
+      rx,
+    })
+  }
+
+  /// Everything that's changed since the last time we checked.
+  pub fn poll(&self) -> impl Iterator<Item = WatchBatch> + '_ {
+    self.rx.try_iter()
+  }
+}
+
+fn work(
+  rx: Receiver<notify::Result<Event>>,
+  tx: Sender<WatchBatch>,
+  roots: Vec<(PathBuf, PathBuf)>,
+  settings: ScanSettings,
+  // Kept up to date with whatever we read ourselves
+  mut known: HashMap<PathBuf, Known>,
+) {
+  // Whether each path turned up from somewhere, rather than just changing
+  let mut pending = HashMap::<PathBuf, bool>::new();
+  let mut first_at = Instant::now();
+  loop {
+    let got = if pending.is_empty() {
+      rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+    } else {
+      let waited = first_at.elapsed();
+      rx.recv_timeout(DEBOUNCE.min(MAX_WAIT.saturating_sub(waited)))
+    };
+    match got {
+      Ok(Ok(event)) => {
+        // Just opening and reading things doesn't change anything
+        if event.kind.is_access() {
+          continue;
+        }
+        if pending.is_empty() {
+          first_at = Instant::now();
+        }
+        let arrived = matches!(
+          event.kind,
+          EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+        );
+        for path in event.paths {
+          *pending.entry(path).or_default() |= arrived;
+        }
+      }
+      Ok(Err(err)) => warn!("Problem watching the library: {}", err),
+      Err(RecvTimeoutError::Timeout) => {
+        let paths = std::mem::take(&mut pending);
+        let batch = read_batch(paths, &roots, &settings, &mut known);
+        if tx.send(batch).is_err() {
+          // Nobody's listening any more
+          return;
+        }
+      }
+      Err(RecvTimeoutError::Disconnected) => {
+        error!("Library watcher stopped sending events");
+        return;
+      }
+    }
+  }
+}
+
+fn read_batch(
+  paths: HashMap<PathBuf, bool>,
+  roots: &[(PathBuf, PathBuf)],
+  settings: &ScanSettings,
+  known: &mut HashMap<PathBuf, Known>,
+) -> WatchBatch {
+  let mut out = WatchBatch::default();
+  let mut dirs = HashSet::new();
+  for (path, arrived) in paths {
+    let Some((root, dir)) = roots.iter().find(|(_, dir)| path.starts_with(dir))
+    else {
+      continue;
+    };
+    if let Some(parent) = path.parent() {
+      dirs.insert(parent.to_path_buf());
+    }
+
+    if !path.exists() {
+      known.retain(|known, _| !known.starts_with(&path));
+      out.gone.push(path);
+      continue;
+    }
+    // `.git` and the like change all the time, and we'd never have gone in
+    // there in the first place
+    let mut options = settings.walk_options();
+    if !options.reaches(dir, &path) {
+      continue;
+    }
+    let mut filter = ScanFilter::new(dir, settings);
+    if !filter.reaches(&path) {
+      debug!("Ignoring change to {:?}", &path);
+      continue;
+    }
+    if path.is_dir() {
+      // A folder changes whenever something in it does, and that something
+      // gets its own event
+      if !arrived || !filter.allows_dir(&path) {
+        continue;
+      }
+      // Probably a whole album that was just moved in. It can only go as deep
+      // as what's left from the root
+      let depth = path.strip_prefix(dir).map_or(0, |it| it.iter().count());
+      if options.max_depth.is_some_and(|max| depth > max) {
+        continue;
+      }
+      options.max_depth =
+        options.max_depth.map(|max| max.saturating_sub(depth));
+      let walk = util::get_all_children(&path)
+        .with_options(options)
+        .filtered(filter);
+      for child in walk {
+        read_file(&child, root, known, &mut out, &mut dirs);
+      }
+    } else if filter.allows_file(&path) {
+      read_file(&path, root, known, &mut out, &mut dirs);
+    }
+  }
+  out.dirs = dirs.into_iter().collect();
+  out
+}
+
+fn read_file(
+  path: &Path,
+  root: &Path,
+  known: &mut HashMap<PathBuf, Known>,
+  out: &mut WatchBatch,
+  dirs: &mut HashSet<PathBuf>,
+) {
+  if let Some(parent) = path.parent() {
+    dirs.insert(parent.to_path_buf());
+  }
+  // Sheets get read when their folder is looked at again
+  if is_cue(path) {
+    return;
+  }
+  // Retagging changes the mtime but usually not the size, and never the
+  // audio, so there's no need to fingerprint it again
+  let size = fs::metadata(path).map(|stat| stat.len()).ok();
+  let locator = known
+    .get(path)
+    .filter(|known| Some(known.size) == size)
+    .and_then(|known| known.locator);
+  match ScannedFile::read(path, root, locator) {
+    Ok(file) => {
+      let now = Known {
+        size: file.size,
+        mtime: file.mtime,
+        stale: false,
+        locator: file.locator,
+      };
+      known.insert(path.to_path_buf(), now);
+      out.files.push(file);
+    }
+    Err(err) => warn!("Could not read {:?}: {:#}", path, err),
+  }
+}
@@ -0,0 +1,170 @@
+//! Working out how loud files are when they don't have ReplayGain tags.
+
+mod meter;
+mod worker;
+
+pub use meter::*;
+pub use worker::*;
+
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{
+  CurrentlyPlayingTrack, ReplayGainSettings, ReplayGainTags, Track,
+};
+
+/// ReplayGain 2.0 aims everything at this.
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// How loud a track (or an album) is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessReport {
+  /// Gated integrated loudness
+  pub integrated_lufs: f64,
+  /// Linear, so 1.0 is full scale
+  pub true_peak: f32,
+  /// In LU
+  pub loudness_range: f64,
+  pub duration_secs: f64,
+}
+
+impl LoudnessReport {
+  pub fn gain_db(&self) -> f32 {
+    (REFERENCE_LUFS - self.integrated_lufs) as f32
+  }
+
+  /// Smoosh a bunch of tracks together into one album.
+  ///
+  /// This is an approximation: doing it properly needs every gating block of
+  /// every track, but weighting by duration is close enough for a gain.
+  pub fn combine<'a>(
+    reports: impl IntoIterator<Item = &'a LoudnessReport>,
+  ) -> Option<LoudnessReport> {
+    let mut energy = 0.0;
+    let mut out = LoudnessReport {
+      integrated_lufs: 0.0,
+      true_peak: 0.0,
+      loudness_range: 0.0,
+      duration_secs: 0.0,
+    };
+    for report in reports {
+      energy +=
+        report.duration_secs * 10f64.powf(report.integrated_lufs / 10.0);
+      out.true_peak = out.true_peak.max(report.true_peak);
+      out.loudness_range = out.loudness_range.max(report.loudness_range);
+      out.duration_secs += report.duration_secs;
+    }
+    if out.duration_secs <= 0.0 {
+      return None;
+    }
+    out.integrated_lufs = 10.0 * (energy / out.duration_secs).log10();
+    Some(out)
+  }
+}
+
+/// Everything we've measured so far, saved next to the config.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LoudnessCache {
+  tracks: HashMap<PathBuf, LoudnessReport>,
+  /// Keyed by the album's folder
+  albums: HashMap<PathBuf, LoudnessReport>,
+}
+
+impl LoudnessCache {
+  pub fn open(path: &Path) -> LoudnessCache {
+    let src = match fs::read_to_string(path) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!(
+          "Could not open loudness cache {:?}, starting fresh: {}",
+          path, err
+        );
+        return LoudnessCache::default();
+      }
+    };
+    match ron::from_str(&src) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!(
+          "Could not parse loudness cache {:?}, starting fresh: {}",
+          path, err
+        );
+        LoudnessCache::default()
+      }
+    }
+  }
+
+  pub fn save(&self, path: &Path) {
+    let src = match ron::to_string(self) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not serialize loudness cache: {}", err);
+        return;
+      }
+    };
+    if let Err(err) = fs::write(path, src.as_bytes()) {
+      warn!("Could not save loudness cache to {:?}: {}", path, err);
+    }
+  }
+
+  pub fn insert_track(&mut self, path: PathBuf, report: LoudnessReport) {
+    self.tracks.insert(path, report);
+  }
+
+  pub fn insert_album(&mut self, folder: PathBuf, report: LoudnessReport) {
+    self.albums.insert(folder, report);
+  }
+
+  /// Keep track of files that have moved since we measured them.
+  pub fn follow_moves(&mut self, moves: &[(PathBuf, PathBuf)]) {
+    for (from, to) in moves {
+      if let Some(report) = self.tracks.remove(from) {
+        self.tracks.insert(to.clone(), report);
+      }
+      // A whole album moving takes its album gain with it
+      if let (Some(from), Some(to)) = (from.parent(), to.parent()) {
+        if !self.albums.contains_key(to) {
+          if let Some(report) = self.albums.get(from).copied() {
+            self.albums.insert(to.to_path_buf(), report);
+          }
+        }
+      }
+    }
+  }
+
+  /// How loud to play `track`, going by its tags, what we've measured, and
+  /// what's either side of it in the queue.
+  pub fn gain_for(
+    &self,
+    settings: &ReplayGainSettings,
+    track: &CurrentlyPlayingTrack,
+    prev: Option<&CurrentlyPlayingTrack>,
+    next: Option<&Track>,
+  ) -> f32 {
+    let mut tags = ReplayGainTags::read(track);
+    self.fill_in(&mut tags, &track.track.path);
+    settings.gain(&tags, track.in_album_order(prev, next))
+  }
+
+  /// Fill in whatever the file's own tags were missing.
+  pub fn fill_in(&self, tags: &mut ReplayGainTags, path: &Path) {
+    if tags.track_gain_db.is_none() {
+      if let Some(report) = self.tracks.get(path) {
+        tags.track_gain_db = Some(report.gain_db());
+        tags.track_peak = Some(report.true_peak);
+      }
+    }
+    if tags.album_gain_db.is_none() {
+      if let Some(report) = path.parent().and_then(|dir| self.albums.get(dir)) {
+        tags.album_gain_db = Some(report.gain_db());
+        tags.album_peak = Some(report.true_peak);
+      }
+    }
+  }
+}
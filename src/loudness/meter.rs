@@ -0,0 +1,382 @@
+//! ITU-R BS.1770 / EBU R128 loudness measurement.
+
+use std::f64::consts::PI;
+
+use creek::read::ReadData;
+use symphonia::core::audio::Channels;
+
+use crate::audio::{ResampleQuality, Resampler};
+
+use super::LoudnessReport;
+
+/// Blocks quieter than this are never counted.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// For integrated loudness, blocks this far under the ungated loudness are
+/// thrown out.
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// Same but for loudness range.
+const LRA_RELATIVE_GATE_LU: f64 = -20.0;
+/// True peak is measured by oversampling this much.
+const TRUE_PEAK_OVERSAMPLE: u32 = 4;
+
+/// Takes in a whole file's worth of audio and then says how loud it was.
+pub struct LoudnessMeter {
+  channels: usize,
+  /// How much each channel counts for
+  weights: Vec<f64>,
+  filters: Vec<KWeighting>,
+
+  /// 100ms, in frames
+  sub_block_len: usize,
+  /// How far into the current sub-block we are
+  sub_block_pos: usize,
+  /// Weighted sum of squares for the current sub-block
+  sub_block_acc: f64,
+  /// Mean weighted square of every 100ms sub-block so far
+  sub_blocks: Vec<f64>,
+
+  frame_count: usize,
+  sample_rate: u32,
+
+  oversampler: Resampler,
+  interleaved: Vec<f32>,
+  oversampled: Vec<f32>,
+  true_peak: f32,
+}
+
+impl LoudnessMeter {
+  pub fn new(
+    layout: Option<Channels>,
+    channel_count: usize,
+    sample_rate: u32,
+  ) -> Self {
+    Self {
+      channels: channel_count,
+      weights: channel_weights(layout, channel_count),
+      filters: (0..channel_count)
+        .map(|_| KWeighting::new(sample_rate as f64))
+        .collect(),
+
+      sub_block_len: (sample_rate as usize / 10).max(1),
+      sub_block_pos: 0,
+      sub_block_acc: 0.0,
+      sub_blocks: Vec::new(),
+
+      frame_count: 0,
+      sample_rate,
+
+      oversampler: Resampler::new(
+        ResampleQuality::Sinc,
+        channel_count,
+        sample_rate,
+        sample_rate * TRUE_PEAK_OVERSAMPLE,
+      ),
+      interleaved: Vec::new(),
+      oversampled: vec![0.0; 1024 * channel_count],
+      true_peak: 0.0,
+    }
+  }
+
+  pub fn push(&mut self, read_data: &ReadData<f32>) {
+    let frames = read_data.num_frames();
+    let channels = self.channels.min(read_data.num_channels());
+
+    let mut interleaved = std::mem::take(&mut self.interleaved);
+    interleaved.clear();
+    interleaved.resize(frames * self.channels, 0.0);
+    for c in 0..channels {
+      for (i, s) in read_data.read_channel(c)[..frames].iter().enumerate() {
+        interleaved[i * self.channels + c] = *s;
+      }
+    }
+    self.push_interleaved(&interleaved);
+    self.interleaved = interleaved;
+  }
+
+  /// Same as `push`, for frames that are already interleaved.
+  pub fn push_interleaved(&mut self, interleaved: &[f32]) {
+    for frame in interleaved.chunks_exact(self.channels) {
+      for ((s, filter), weight) in
+        frame.iter().zip(self.filters.iter_mut()).zip(&self.weights)
+      {
+        let y = filter.process(*s as f64);
+        self.sub_block_acc += weight * y * y;
+      }
+
+      self.sub_block_pos += 1;
+      if self.sub_block_pos == self.sub_block_len {
+        self
+          .sub_blocks
+          .push(self.sub_block_acc / self.sub_block_len as f64);
+        self.sub_block_pos = 0;
+        self.sub_block_acc = 0.0;
+      }
+    }
+    self.frame_count += interleaved.len() / self.channels;
+
+    self.oversampler.push(interleaved);
+    loop {
+      let got = self.oversampler.produce(&mut self.oversampled);
+      if got == 0 {
+        break;
+      }
+      for s in &self.oversampled[..got * self.channels] {
+        self.true_peak = self.true_peak.max(s.abs());
+      }
+    }
+    // The oversampled peak can come out a hair under the real samples
+    for s in interleaved {
+      self.true_peak = self.true_peak.max(s.abs());
+    }
+  }
+
+  pub fn finish(self) -> LoudnessReport {
+    // 400ms blocks overlapping by 75%
+    let momentary = self
+      .sub_blocks
+      .windows(4)
+      .map(|w| w.iter().sum::<f64>() / 4.0)
+      .collect::<Vec<_>>();
+    let integrated_lufs = gated_loudness(&momentary, RELATIVE_GATE_LU);
+
+    // 3s blocks every 1s
+    let short_term = self
+      .sub_blocks
+      .windows(30)
+      .step_by(10)
+      .map(|w| w.iter().sum::<f64>() / 30.0)
+      .collect::<Vec<_>>();
+    let loudness_range = loudness_range(&short_term);
+
+    LoudnessReport {
+      integrated_lufs,
+      true_peak: self.true_peak,
+      loudness_range,
+      duration_secs: self.frame_count as f64 / self.sample_rate as f64,
+    }
+  }
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+  -0.691 + 10.0 * energy.log10()
+}
+
+/// The two-stage gating from BS.1770-4.
+fn gated_loudness(blocks: &[f64], relative_gate: f64) -> f64 {
+  let loud_enough = blocks
+    .iter()
+    .copied()
+    .filter(|e| energy_to_lufs(*e) > ABSOLUTE_GATE_LUFS)
+    .collect::<Vec<_>>();
+  if loud_enough.is_empty() {
+    return ABSOLUTE_GATE_LUFS;
+  }
+
+  let ungated = loud_enough.iter().sum::<f64>() / loud_enough.len() as f64;
+  let threshold = energy_to_lufs(ungated) + relative_gate;
+  let (sum, count) = loud_enough
+    .iter()
+    .filter(|e| energy_to_lufs(**e) > threshold)
+    .fold((0.0, 0), |(sum, count), e| (sum + e, count + 1));
+  if count == 0 {
+    return ABSOLUTE_GATE_LUFS;
+  }
+  energy_to_lufs(sum / count as f64)
+}
+
+/// EBU Tech 3342 loudness range.
+fn loudness_range(short_term: &[f64]) -> f64 {
+  let loud_enough = short_term
+    .iter()
+    .copied()
+    .filter(|e| energy_to_lufs(*e) > ABSOLUTE_GATE_LUFS)
+    .collect::<Vec<_>>();
+  if loud_enough.is_empty() {
+    return 0.0;
+  }
+
+  let ungated = loud_enough.iter().sum::<f64>() / loud_enough.len() as f64;
+  let threshold = energy_to_lufs(ungated) + LRA_RELATIVE_GATE_LU;
+  let mut levels = loud_enough
+    .into_iter()
+    .map(energy_to_lufs)
+    .filter(|l| *l > threshold)
+    .collect::<Vec<_>>();
+  if levels.is_empty() {
+    return 0.0;
+  }
+  levels.sort_by(|a, b| a.total_cmp(b));
+
+  let percentile = |p: f64| {
+    let idx = ((levels.len() - 1) as f64 * p).round() as usize;
+    levels[idx]
+  };
+  percentile(0.95) - percentile(0.10)
+}
+
+/// Surrounds count for more, the LFE doesn't count at all.
+fn channel_weights(layout: Option<Channels>, count: usize) -> Vec<f64> {
+  match layout.filter(|l| l.count() == count) {
+    Some(layout) => layout
+      .iter()
+      .map(|ch| {
+        if ch.intersects(Channels::LFE1 | Channels::LFE2) {
+          0.0
+        } else if ch.intersects(
+          Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+            | Channels::SIDE_LEFT
+            | Channels::SIDE_RIGHT,
+        ) {
+          1.41
+        } else {
+          1.0
+        }
+      })
+      .collect(),
+    // Assume WAVE order for 5.1
+    None if count == 6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+    None => vec![1.0; count],
+  }
+}
+
+/// The K-weighting pre-filter: a high shelf and then a high pass.
+///
+/// The coefficients in the spec are only for 48kHz, so these are worked out
+/// from the analog prototypes for whatever rate we're at.
+struct KWeighting {
+  shelf: Biquad,
+  high_pass: Biquad,
+}
+
+impl KWeighting {
+  fn new(rate: f64) -> Self {
+    let shelf = {
+      let f0 = 1681.974450955533;
+      let gain_db = 3.999843853973347;
+      let q = 0.7071752369554196;
+
+      let k = (PI * f0 / rate).tan();
+      let vh = 10f64.powf(gain_db / 20.0);
+      let vb = vh.powf(0.4996667741545416);
+      let a0 = 1.0 + k / q + k * k;
+      Biquad::new(
+        [
+          (vh + vb * k / q + k * k) / a0,
+          2.0 * (k * k - vh) / a0,
+          (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+      )
+    };
+    let high_pass = {
+      let f0 = 38.13547087602444;
+      let q = 0.5003270373238773;
+
+      let k = (PI * f0 / rate).tan();
+      let a0 = 1.0 + k / q + k * k;
+      Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+      )
+    };
+    Self { shelf, high_pass }
+  }
+
+  fn process(&mut self, x: f64) -> f64 {
+    self.high_pass.process(self.shelf.process(x))
+  }
+}
+
+/// Direct form II transposed.
+struct Biquad {
+  b: [f64; 3],
+  a: [f64; 2],
+  z: [f64; 2],
+}
+
+impl Biquad {
+  fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+    Self { b, a, z: [0.0; 2] }
+  }
+
+  fn process(&mut self, x: f64) -> f64 {
+    let y = self.b[0] * x + self.z[0];
+    self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+    self.z[1] = self.b[2] * x - self.a[1] * y;
+    y
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RATE: u32 = 48000;
+
+  /// Stereo sine with the same thing in both channels.
+  fn sine(freq: f64, dbfs: f64, secs: f64, phase: f64) -> Vec<f32> {
+    let amp = 10f64.powf(dbfs / 20.0);
+    let frames = (secs * RATE as f64) as usize;
+    (0..frames)
+      .flat_map(|i| {
+        let t = i as f64 / RATE as f64;
+        let s = (amp * (2.0 * PI * freq * t + phase).sin()) as f32;
+        [s, s]
+      })
+      .collect()
+  }
+
+  fn measure(parts: &[Vec<f32>]) -> LoudnessReport {
+    let mut meter = LoudnessMeter::new(None, 2, RATE);
+    for part in parts {
+      for chunk in part.chunks(4096 * 2) {
+        meter.push_interleaved(chunk);
+      }
+    }
+    meter.finish()
+  }
+
+  // These are the EBU Tech 3341 test signals, just shorter
+
+  #[test]
+  fn sine_at_minus_23_is_minus_23_lufs() {
+    let report = measure(&[sine(1000.0, -23.0, 5.0, 0.0)]);
+    assert!((report.integrated_lufs + 23.0).abs() < 0.1, "{:?}", report);
+    assert!((report.duration_secs - 5.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn quiet_parts_are_gated_out() {
+    // The quiet bits are more than 10 LU under, so they don't count. Blocks
+    // straddling the edges still do, so keep them few
+    let report = measure(&[
+      sine(1000.0, -36.0, 2.0, 0.0),
+      sine(1000.0, -23.0, 20.0, 0.0),
+      sine(1000.0, -36.0, 2.0, 0.0),
+    ]);
+    assert!((report.integrated_lufs + 23.0).abs() < 0.1, "{:?}", report);
+
+    // And silence is under the absolute gate
+    let report = measure(&[
+      vec![0.0; RATE as usize * 2 * 4],
+      sine(1000.0, -23.0, 10.0, 0.0),
+    ]);
+    assert!((report.integrated_lufs + 23.0).abs() < 0.1, "{:?}", report);
+
+    let report = measure(&[vec![0.0; RATE as usize * 2]]);
+    assert_eq!(report.integrated_lufs, ABSOLUTE_GATE_LUFS);
+  }
+
+  #[test]
+  fn true_peak_finds_inter_sample_peaks() {
+    // A quarter of the rate, 45 degrees out, so every sample lands at 0.707
+    // of the real peak
+    let signal = sine(RATE as f64 / 4.0, -6.0, 1.0, PI / 4.0);
+    let sample_peak = signal.iter().fold(0.0f32, |acc, s| acc.max(s.abs()));
+    let report = measure(&[signal]);
+    let expected = 10f32.powf(-6.0 / 20.0);
+    assert!(sample_peak < expected * 0.71);
+    assert!((report.true_peak - expected).abs() < 0.01, "{:?}", report);
+  }
+}
@@ -0,0 +1,200 @@
+//! Analysis runs on its own thread so the ui doesn't lock up.
+
+use std::{
+  collections::BTreeMap,
+  path::{Path, PathBuf},
+  sync::mpsc::{self, Receiver, Sender},
+  thread,
+};
+
+use creek::{
+  read::ReadError, ReadDiskStream, ReadStreamOptions, SymphoniaDecoder,
+};
+use eyre::{bail, eyre, WrapErr};
+use log::{error, info};
+
+use super::{LoudnessMeter, LoudnessReport};
+
+/// How many frames to read off disc at once.
+const CHUNK_FRAMES: usize = 4096;
+
+pub enum AnalysisJob {
+  Track(PathBuf),
+  /// Also works out the gain for the whole album afterwards.
+  Album {
+    folder: PathBuf,
+    tracks: Vec<PathBuf>,
+  },
+  /// Every file in the library, done an album (folder) at a time.
+  Library(Vec<PathBuf>),
+}
+
+#[derive(Debug)]
+pub enum AnalysisMsg {
+  Started {
+    path: PathBuf,
+    /// Which track of this job it is
+    index: usize,
+    of: usize,
+  },
+  Progress(f32),
+  TrackDone {
+    path: PathBuf,
+    report: LoudnessReport,
+  },
+  AlbumDone {
+    folder: PathBuf,
+    report: LoudnessReport,
+  },
+  Failed {
+    path: PathBuf,
+    error: String,
+  },
+  /// Nothing left to do
+  Idle,
+}
+
+pub struct LoudnessWorker {
+  tx: Sender<AnalysisJob>,
+  rx: Receiver<AnalysisMsg>,
+}
+
+impl LoudnessWorker {
+  pub fn spawn() -> eyre::Result<Self> {
+    let (tx, rx_jobs) = mpsc::channel();
+    let (tx_msgs, rx) = mpsc::channel();
+    thread::Builder::new()
+      .name("loudness analysis".to_owned())
+      .spawn(move || work(rx_jobs, tx_msgs))
+      .wrap_err("could not spawn analysis thread")?;
+    Ok(Self { tx, rx })
+  }
+
+  pub fn submit(&self, job: AnalysisJob) {
+    if self.tx.send(job).is_err() {
+      error!("Loudness analysis thread is gone");
+    }
+  }
+
+  /// Everything the worker has said since the last time we checked.
+  pub fn poll(&self) -> impl Iterator<Item = AnalysisMsg> + '_ {
+    self.rx.try_iter()
+  }
+}
+
+fn work(rx: Receiver<AnalysisJob>, tx: Sender<AnalysisMsg>) {
+  while let Ok(job) = rx.recv() {
+    let albums = match job {
+      AnalysisJob::Track(path) => {
+        let _ignore = analyze_and_report(&path, 0, 1, &tx);
+        Vec::new()
+      }
+      AnalysisJob::Album { folder, tracks } => vec![(folder, tracks)],
+      AnalysisJob::Library(files) => {
+        let mut by_folder = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+        for path in files {
+          let folder = path.parent().unwrap_or(&path).to_path_buf();
+          by_folder.entry(folder).or_default().push(path);
+        }
+        by_folder.into_iter().collect()
+      }
+    };
+
+    for (folder, tracks) in albums {
+      let reports = tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, path)| analyze_and_report(path, i, tracks.len(), &tx))
+        .collect::<Vec<_>>();
+      if let Some(report) = LoudnessReport::combine(&reports) {
+        let _ignore = tx.send(AnalysisMsg::AlbumDone { folder, report });
+      }
+    }
+
+    let _ignore = tx.send(AnalysisMsg::Idle);
+  }
+}
+
+fn analyze_and_report(
+  path: &Path,
+  index: usize,
+  of: usize,
+  tx: &Sender<AnalysisMsg>,
+) -> Option<LoudnessReport> {
+  let _ignore = tx.send(AnalysisMsg::Started {
+    path: path.to_owned(),
+    index,
+    of,
+  });
+  // Don't flood the ui with every single chunk
+  let mut last_sent = 0.0;
+  let res = analyze_file(path, |p| {
+    if p - last_sent >= 0.01 {
+      last_sent = p;
+      let _ignore = tx.send(AnalysisMsg::Progress(p));
+    }
+  });
+  match res {
+    Ok(report) => {
+      info!("Loudness of {:?}: {:?}", path, &report);
+      let _ignore = tx.send(AnalysisMsg::TrackDone {
+        path: path.to_owned(),
+        report,
+      });
+      Some(report)
+    }
+    Err(err) => {
+      let _ignore = tx.send(AnalysisMsg::Failed {
+        path: path.to_owned(),
+        error: err.to_string(),
+      });
+      None
+    }
+  }
+}
+
+/// Decode the whole file and measure it.
+pub fn analyze_file(
+  path: &Path,
+  mut progress: impl FnMut(f32),
+) -> eyre::Result<LoudnessReport> {
+  let opts = ReadStreamOptions::default();
+  let mut stream = ReadDiskStream::<SymphoniaDecoder>::new(path, 0, opts)
+    .map_err(|err| eyre!("could not open {:?}: {}", path, err))?;
+
+  let info = stream.info().clone();
+  let Some(sample_rate) = info.sample_rate else {
+    bail!("{:?} doesn't know its sample rate", path);
+  };
+  if info.num_channels == 0 {
+    bail!("{:?} has no channels", path);
+  }
+  let mut meter = LoudnessMeter::new(
+    info.params.codec_params.channels,
+    info.num_channels as usize,
+    sample_rate,
+  );
+
+  loop {
+    stream
+      .block_until_ready()
+      .map_err(|err| eyre!("reading {:?}: {}", path, err))?;
+    let read_data = match stream.read(CHUNK_FRAMES) {
+      Ok(it) => it,
+      Err(ReadError::EndOfFile) => break,
+      Err(err) => bail!("reading {:?}: {}", path, err),
+    };
+    if read_data.num_frames() == 0 {
+      break;
+    }
+    meter.push(&read_data);
+    let done = read_data.reached_end_of_file();
+
+    progress(stream.playhead() as f32 / info.num_frames.max(1) as f32);
+    if done {
+      break;
+    }
+  }
+
+  Ok(meter.finish())
+}
@@ -7,18 +7,33 @@
 mod app;
 mod audio;
 mod emoji;
+mod library;
+mod loudness;
 mod model;
+mod playlist_io;
 mod settings;
 mod util;
 
+use std::path::Path;
+
 use app::DecomposerApp;
 
-use eyre::eyre;
+use eyre::{bail, eyre};
+use settings::DecomposerConfig;
+
+/// What `--render` renders at.
+const RENDER_SAMPLE_RATE: u32 = 48000;
+const RENDER_CHANNELS: usize = 2;
 
 fn main() -> eyre::Result<()> {
   let env = env_logger::Env::default().default_filter_or("decomposer=info");
   env_logger::init_from_env(env);
 
+  let args = std::env::args().skip(1).collect::<Vec<_>>();
+  if args.first().map(String::as_str) == Some("--render") {
+    return render(&args[1..]);
+  }
+
   let options = eframe::NativeOptions {
     ..Default::default()
   };
@@ -37,3 +52,22 @@ fn main() -> eyre::Result<()> {
     Ok(())
   }
 }
+
+/// `decomposer --render out.wav track.flac [more tracks...]`
+///
+/// Plays the tracks through the same path as the app would, with no
+/// sound card, using the settings from the default config.
+fn render(args: &[String]) -> eyre::Result<()> {
+  let [out, tracks @ ..] = args else {
+    bail!("usage: decomposer --render <out.wav> <tracks...>");
+  };
+  let config = DecomposerConfig::open(None)?;
+  let tracks = tracks.iter().map(Path::new).collect::<Vec<_>>();
+  audio::render_files(
+    &config,
+    &tracks,
+    Path::new(out),
+    RENDER_SAMPLE_RATE,
+    RENDER_CHANNELS,
+  )
+}
@@ -0,0 +1,474 @@
+//! CUE sheets, for albums that were ripped to one big file.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::{formats::Cue, meta::MetadataRevision};
+
+use super::{metadata::leading_number, Track, TrackMeta, TrackSpan};
+
+/// CUE timestamps count in CD frames, 75 to the second.
+const CUE_FRAMES_PER_SEC: f64 = 75.0;
+/// FLAC gives the lead-out its own track number, which isn't a real track
+const FLAC_LEAD_OUTS: [u32; 2] = [170, 255];
+
+/// Everything a CUE sheet says.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CueSheet {
+  /// The album
+  pub title: Option<String>,
+  /// Who the album is by
+  pub performer: Option<String>,
+  pub genre: Option<String>,
+  pub year: Option<i32>,
+  pub files: Vec<CueFile>,
+}
+
+/// One `FILE` and the tracks in it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CueFile {
+  /// As written in the sheet, which is usually relative to the sheet
+  pub name: String,
+  pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CueTrack {
+  pub number: u32,
+  pub title: Option<String>,
+  pub performer: Option<String>,
+  /// Where `INDEX 01` is, so pregaps go with the track before
+  pub start_secs: f64,
+}
+
+impl CueSheet {
+  /// Read a `.cue` file.
+  ///
+  /// Older rippers write these in Latin-1, so if it isn't UTF-8 it's read as
+  /// that instead.
+  pub fn open(path: &Path) -> eyre::Result<CueSheet> {
+    let bytes = fs::read(path)?;
+    let src = match String::from_utf8(bytes) {
+      Ok(it) => it,
+      Err(err) => err.into_bytes().iter().map(|b| *b as char).collect(),
+    };
+    Ok(CueSheet::parse(&src))
+  }
+
+  /// Anything that doesn't make sense is skipped over.
+  pub fn parse(src: &str) -> CueSheet {
+    let mut sheet = CueSheet::default();
+    let mut track: Option<(CueTrack, bool)> = None;
+
+    let finish = |sheet: &mut CueSheet, track: Option<(CueTrack, bool)>| {
+      // A track that never said where it starts can't be played
+      if let (Some((track, true)), Some(file)) = (track, sheet.files.last_mut())
+      {
+        file.tracks.push(track);
+      }
+    };
+
+    for line in src.trim_start_matches('\u{feff}').lines() {
+      let line = line.trim();
+      let (command, rest) =
+        line.split_once(char::is_whitespace).unwrap_or((line, ""));
+      let rest = rest.trim();
+      match command.to_ascii_uppercase().as_str() {
+        "FILE" => {
+          finish(&mut sheet, track.take());
+          sheet.files.push(CueFile {
+            name: file_name_arg(rest),
+            tracks: Vec::new(),
+          });
+        }
+        "TRACK" => {
+          finish(&mut sheet, track.take());
+          let number = leading_number(rest).unwrap_or(0);
+          track = Some((
+            CueTrack {
+              number,
+              title: None,
+              performer: None,
+              start_secs: 0.0,
+            },
+            false,
+          ));
+        }
+        "INDEX" => {
+          let Some((track, started)) = track.as_mut() else {
+            continue;
+          };
+          let Some((index, time)) = rest.split_once(char::is_whitespace) else {
+            continue;
+          };
+          if leading_number(index) != Some(1) {
+            continue;
+          }
+          if let Some(secs) = parse_time(time.trim()) {
+            track.start_secs = secs;
+            *started = true;
+          }
+        }
+        "TITLE" => match track.as_mut() {
+          Some((track, _)) => track.title = non_empty(arg(rest)),
+          None => sheet.title = non_empty(arg(rest)),
+        },
+        "PERFORMER" => match track.as_mut() {
+          Some((track, _)) => track.performer = non_empty(arg(rest)),
+          None => sheet.performer = non_empty(arg(rest)),
+        },
+        "REM" => {
+          let (key, value) =
+            rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+          let value = arg(value.trim());
+          match key.to_ascii_uppercase().as_str() {
+            "GENRE" => sheet.genre = non_empty(value),
+            "DATE" => {
+              sheet.year = leading_number(&value).map(|year| year as i32)
+            }
+            _ => {}
+          }
+        }
+        _ => {}
+      }
+    }
+    finish(&mut sheet, track);
+
+    sheet.files.retain(|file| !file.tracks.is_empty());
+    sheet
+  }
+
+  /// Look for a `CUESHEET` tag, like FLAC rippers tend to add.
+  pub fn from_tags(tags: &MetadataRevision) -> Option<CueSheet> {
+    let tag = tags
+      .tags()
+      .iter()
+      .find(|tag| tag.key.eq_ignore_ascii_case("CUESHEET"))?;
+    let sheet = CueSheet::parse(&tag.value.to_string());
+    (!sheet.files.is_empty()).then_some(sheet)
+  }
+
+  /// Turn the cue points a container has natively, like FLAC's `CUESHEET`
+  /// block, into a sheet for one file.
+  pub fn from_cues(cues: &[Cue], sample_rate: u32) -> Option<CueSheet> {
+    let tracks = cues
+      .iter()
+      .filter(|cue| !FLAC_LEAD_OUTS.contains(&cue.index))
+      .map(|cue| {
+        // Index 0 is the pregap if there is one
+        let offset = cue
+          .points
+          .get(1)
+          .or(cue.points.first())
+          .map_or(0, |point| point.start_offset_ts);
+        CueTrack {
+          number: cue.index,
+          title: None,
+          performer: None,
+          start_secs: (cue.start_ts + offset) as f64 / sample_rate as f64,
+        }
+      })
+      .collect::<Vec<_>>();
+    // Just the one track is the same as not having a sheet at all
+    if tracks.len() < 2 {
+      return None;
+    }
+    Some(CueSheet {
+      files: vec![CueFile {
+        name: String::new(),
+        tracks,
+      }],
+      ..Default::default()
+    })
+  }
+
+  /// The tracks in the file with this name.
+  ///
+  /// A sheet about only one file gets the benefit of the doubt, since the
+  /// name in it often goes stale when the file gets converted or renamed.
+  pub fn tracks_for(&self, file_name: &str) -> Option<&[CueTrack]> {
+    if let [file] = &self.files[..] {
+      return Some(&file.tracks);
+    }
+    self
+      .files
+      .iter()
+      .find(|file| {
+        let name = file.name.rsplit(['/', '\\']).next().unwrap_or_default();
+        name.eq_ignore_ascii_case(file_name)
+      })
+      .map(|file| &file.tracks[..])
+  }
+
+  /// Split the file at `path` into one track for each track in the sheet,
+  /// using what the sheet says over what the file's tags say.
+  pub fn expand(&self, path: &Path, meta: &TrackMeta) -> Vec<Track> {
+    let file_name = path
+      .file_name()
+      .map(|name| name.to_string_lossy())
+      .unwrap_or_default();
+    let Some(tracks) = self.tracks_for(&file_name) else {
+      return Vec::new();
+    };
+
+    tracks
+      .iter()
+      .enumerate()
+      .map(|(i, track)| {
+        let end_secs = tracks.get(i + 1).map(|next| next.start_secs);
+        let duration_secs = end_secs
+          .or(meta.duration_secs)
+          .map(|end| (end - track.start_secs).max(0.0));
+        let meta = TrackMeta {
+          title: track
+            .title
+            .clone()
+            .unwrap_or_else(|| format!("Track {}", track.number)),
+          artist: track
+            .performer
+            .clone()
+            .or_else(|| self.performer.clone())
+            .or_else(|| meta.artist.clone()),
+          album: self.title.clone().or_else(|| meta.album.clone()),
+          album_artist: self
+            .performer
+            .clone()
+            .or_else(|| meta.album_artist.clone()),
+          track_number: Some(track.number),
+          disc_number: meta.disc_number,
+          year: self.year.or(meta.year),
+          genre: self.genre.clone().or_else(|| meta.genre.clone()),
+          duration_secs,
+        };
+        Track {
+          path: path.to_path_buf(),
+          meta,
+          span: Some(TrackSpan {
+            start_secs: track.start_secs,
+            end_secs,
+          }),
+        }
+      })
+      .collect()
+  }
+}
+
+/// `mm:ss:ff`, where the minutes can go past 59.
+fn parse_time(time: &str) -> Option<f64> {
+  let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+  let (Some(Some(mins)), Some(Some(secs)), Some(Some(frames)), None) =
+    (parts.next(), parts.next(), parts.next(), parts.next())
+  else {
+    return None;
+  };
+  Some(mins as f64 * 60.0 + secs as f64 + frames as f64 / CUE_FRAMES_PER_SEC)
+}
+
+/// What's in the quotes, or the whole thing if there aren't any.
+fn arg(rest: &str) -> String {
+  match rest.strip_prefix('"') {
+    Some(quoted) => match quoted.split_once('"') {
+      Some((inside, _)) => inside.to_owned(),
+      None => quoted.to_owned(),
+    },
+    None => rest.to_owned(),
+  }
+}
+
+/// `FILE "name" WAVE`, where the name might not be quoted.
+fn file_name_arg(rest: &str) -> String {
+  if rest.starts_with('"') {
+    return arg(rest);
+  }
+  match rest.rsplit_once(char::is_whitespace) {
+    Some((name, _kind)) => name.trim().to_owned(),
+    None => rest.to_owned(),
+  }
+}
+
+fn non_empty(value: String) -> Option<String> {
+  let value = value.trim();
+  (!value.is_empty()).then(|| value.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use symphonia::core::formats::CuePoint;
+
+  use super::*;
+
+  const TWO_FILES: &str = concat!(
+    "\u{feff}REM GENRE \"Post Rock\"\n",
+    "REM DATE 2003\n",
+    "PERFORMER \"Some Band\"\n",
+    "TITLE \"Live At The Place\"\n",
+    "FILE \"Disc One.flac\" WAVE\n",
+    "  TRACK 01 AUDIO\n",
+    "    TITLE \"Opening With Spaces\"\n",
+    "    INDEX 01 00:00:00\n",
+    "  TRACK 02 AUDIO\n",
+    "    TITLE Unquoted Title\n",
+    "    PERFORMER \"Guest\"\n",
+    "    INDEX 00 04:58:00\n",
+    "    INDEX 01 05:00:00\n",
+    "  TRACK 03 AUDIO\n",
+    "    TITLE \"Never Starts\"\n",
+    "    INDEX 00 09:00:00\n",
+    "FILE discs\\Disc Two.wav WAVE\n",
+    "  TRACK 04 AUDIO\n",
+    "    INDEX 01 00:00:00\n",
+    "  TRACK 05 AUDIO\n",
+    "    INDEX 01 61:02:37\n",
+  );
+
+  fn cue(index: u32, start_ts: u64, offsets: &[u64]) -> Cue {
+    Cue {
+      index,
+      start_ts,
+      tags: Vec::new(),
+      points: offsets
+        .iter()
+        .map(|offset| CuePoint {
+          start_offset_ts: *offset,
+          tags: Vec::new(),
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn parses_every_file_in_a_sheet() {
+    let sheet = CueSheet::parse(TWO_FILES);
+    assert_eq!(sheet.title.as_deref(), Some("Live At The Place"));
+    assert_eq!(sheet.performer.as_deref(), Some("Some Band"));
+    assert_eq!(sheet.genre.as_deref(), Some("Post Rock"));
+    assert_eq!(sheet.year, Some(2003));
+
+    let names = sheet
+      .files
+      .iter()
+      .map(|file| file.name.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(names, ["Disc One.flac", "discs\\Disc Two.wav"]);
+    let numbers = |file: &str| {
+      sheet
+        .tracks_for(file)
+        .unwrap()
+        .iter()
+        .map(|track| track.number)
+        .collect::<Vec<_>>()
+    };
+    // A track with no `INDEX 01` gets dropped
+    assert_eq!(numbers("disc one.flac"), [1, 2]);
+    assert_eq!(numbers("Disc Two.wav"), [4, 5]);
+    assert!(sheet.tracks_for("Disc Three.wav").is_none());
+  }
+
+  #[test]
+  fn titles_can_have_spaces_with_or_without_quotes() {
+    let sheet = CueSheet::parse(TWO_FILES);
+    let tracks = &sheet.files[0].tracks;
+    assert_eq!(tracks[0].title.as_deref(), Some("Opening With Spaces"));
+    assert_eq!(tracks[0].performer, None);
+    assert_eq!(tracks[1].title.as_deref(), Some("Unquoted Title"));
+    assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+
+    let sheet =
+      CueSheet::parse("FILE My Album.wav WAVE\nTRACK 1 AUDIO\nINDEX 1 0:0:0");
+    assert_eq!(sheet.files[0].name, "My Album.wav");
+  }
+
+  #[test]
+  fn tracks_start_at_index_01() {
+    let sheet = CueSheet::parse(TWO_FILES);
+    // The pregap at `INDEX 00` goes with the track before
+    assert_eq!(sheet.files[0].tracks[1].start_secs, 300.0);
+    // Minutes go past an hour rather than rolling over
+    let start = sheet.files[1].tracks[1].start_secs;
+    assert!((start - (61.0 * 60.0 + 2.0 + 37.0 / 75.0)).abs() < 1e-9);
+  }
+
+  #[test]
+  fn times_are_in_cd_frames() {
+    assert_eq!(parse_time("00:00:00"), Some(0.0));
+    assert_eq!(parse_time("00:01:00"), Some(1.0));
+    assert_eq!(parse_time("00:00:75"), Some(1.0));
+    assert_eq!(parse_time("02:30:15"), Some(150.2));
+    assert_eq!(parse_time("120:00:00"), Some(7200.0));
+    assert_eq!(parse_time("01:02"), None);
+    assert_eq!(parse_time("01:02:03:04"), None);
+    assert_eq!(parse_time("aa:02:03"), None);
+  }
+
+  #[test]
+  fn native_cues_skip_lead_outs() {
+    let cues = [
+      cue(1, 0, &[0]),
+      // Pregap first, then where the track really starts
+      cue(2, 44_100, &[0, 22_050]),
+      cue(3, 441_000, &[]),
+      cue(170, 882_000, &[0]),
+      cue(255, 882_000, &[0]),
+    ];
+    let sheet = CueSheet::from_cues(&cues, 44_100).unwrap();
+    let tracks = sheet.tracks_for("anything.flac").unwrap();
+    let starts = tracks
+      .iter()
+      .map(|track| (track.number, track.start_secs))
+      .collect::<Vec<_>>();
+    assert_eq!(starts, [(1, 0.0), (2, 1.5), (3, 10.0)]);
+
+    // One track and a lead-out isn't worth splitting
+    assert!(
+      CueSheet::from_cues(&[cue(1, 0, &[0]), cue(170, 1, &[0])], 1).is_none()
+    );
+  }
+
+  #[test]
+  fn expanded_tracks_end_where_the_next_one_starts() {
+    let sheet = CueSheet::parse(concat!(
+      "PERFORMER \"Some Band\"\n",
+      "TITLE \"An Album\"\n",
+      "FILE \"album.flac\" WAVE\n",
+      "TRACK 01 AUDIO\n",
+      "INDEX 01 00:00:00\n",
+      "TRACK 02 AUDIO\n",
+      "TITLE \"Second\"\n",
+      "INDEX 01 03:00:00\n",
+      "TRACK 03 AUDIO\n",
+      "INDEX 00 06:58:00\n",
+      "INDEX 01 07:00:00\n",
+    ));
+    let meta = TrackMeta {
+      title: "album".to_owned(),
+      artist: Some("Tagged Band".to_owned()),
+      disc_number: Some(2),
+      duration_secs: Some(600.0),
+      ..Default::default()
+    };
+    let tracks = sheet.expand(Path::new("/music/renamed.flac"), &meta);
+
+    let spans = tracks
+      .iter()
+      .map(|track| track.span.unwrap())
+      .map(|span| (span.start_secs, span.end_secs))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      spans,
+      [(0.0, Some(180.0)), (180.0, Some(420.0)), (420.0, None)]
+    );
+    let durations = tracks
+      .iter()
+      .map(|track| track.meta.duration_secs)
+      .collect::<Vec<_>>();
+    assert_eq!(durations, [Some(180.0), Some(240.0), Some(180.0)]);
+
+    let second = &tracks[1].meta;
+    assert_eq!(second.title, "Second");
+    assert_eq!(tracks[0].meta.title, "Track 1");
+    assert_eq!(second.artist.as_deref(), Some("Some Band"));
+    assert_eq!(second.album.as_deref(), Some("An Album"));
+    assert_eq!(second.track_number, Some(2));
+    assert_eq!(second.disc_number, Some(2));
+  }
+}
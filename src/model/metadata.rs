@@ -0,0 +1,268 @@
+//! Reading tags out of files so we have something nicer than a path to show.
+
+use std::{fs::File, path::Path};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+  formats::FormatOptions,
+  io::MediaSourceStream,
+  meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+  probe::{Hint, ProbeResult},
+  units::Time,
+};
+
+use super::CueSheet;
+
+/// Everything we know about a track without playing it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TrackMeta {
+  /// Always something, even if it's just the filename
+  pub title: String,
+  pub artist: Option<String>,
+  pub album: Option<String>,
+  pub album_artist: Option<String>,
+  pub track_number: Option<u32>,
+  pub disc_number: Option<u32>,
+  pub year: Option<i32>,
+  pub genre: Option<String>,
+  pub duration_secs: Option<f64>,
+}
+
+impl TrackMeta {
+  /// Read the tags, filling in what's missing from the filename, and a CUE
+  /// sheet if there's one in the file.
+  ///
+  /// Symphonia handles ID3v2, Vorbis comments, MP4 atoms and APE for us.
+  /// Fails if the file can't be read or symphonia can't make sense of it;
+  /// a file with no tags at all still gets the filename.
+  pub fn read_with_cue(
+    path: &Path,
+  ) -> eyre::Result<(TrackMeta, Option<CueSheet>)> {
+    let (mut meta, cue) = probe(path)?;
+    meta.fill_from_filename(path);
+    Ok((meta, cue))
+  }
+
+  /// Whoever the album is by, or failing that whoever the track is by.
+  pub fn display_artist(&self) -> Option<&str> {
+    self.artist.as_deref().or(self.album_artist.as_deref())
+  }
+
+  pub fn duration(&self) -> Option<Time> {
+    self
+      .duration_secs
+      .map(|secs| Time::new(secs as u64, secs.fract()))
+  }
+
+  fn fill_from_tags(&mut self, tags: &MetadataRevision) {
+    for tag in tags.tags() {
+      let Some(key) = tag.std_key else { continue };
+      let value = tag.value.to_string();
+      let value = value.trim();
+      if value.is_empty() {
+        continue;
+      }
+      // First one wins, so the container's own tags can go first
+      match key {
+        StandardTagKey::TrackTitle if self.title.is_empty() => {
+          self.title = value.to_owned();
+        }
+        StandardTagKey::Artist => fill(&mut self.artist, value),
+        StandardTagKey::Album => fill(&mut self.album, value),
+        StandardTagKey::AlbumArtist => fill(&mut self.album_artist, value),
+        StandardTagKey::Genre => fill(&mut self.genre, value),
+        StandardTagKey::TrackNumber if self.track_number.is_none() => {
+          self.track_number = leading_number(value);
+        }
+        StandardTagKey::DiscNumber if self.disc_number.is_none() => {
+          self.disc_number = leading_number(value);
+        }
+        StandardTagKey::Date | StandardTagKey::OriginalDate
+          if self.year.is_none() =>
+        {
+          self.year = leading_number(value).map(|year| year as i32);
+        }
+        _ => {}
+      }
+    }
+  }
+
+  /// Make a guess from names like `01 - Artist - Title.flac`.
+  fn fill_from_filename(&mut self, path: &Path) {
+    let Some(stem) = path.file_stem() else { return };
+    let stem = stem.to_string_lossy();
+
+    let digits = stem.chars().take_while(char::is_ascii_digit).count();
+    let after = stem[digits..].trim_start();
+    let separated = after.is_empty() || after.starts_with(['-', '.', '_']);
+    // `01 Title` and `1. Title` are numbered, `99 Luftballons` isn't
+    let numbered =
+      (1..=3).contains(&digits) && (stem.starts_with('0') || separated);
+    let rest = if numbered {
+      if self.track_number.is_none() {
+        self.track_number = stem[..digits].parse().ok();
+      }
+      stem[digits..].trim_start_matches(|c: char| {
+        c.is_whitespace() || c == '-' || c == '.' || c == '_'
+      })
+    } else {
+      &stem[..]
+    };
+
+    let (artist, title) = match rest.split_once(" - ") {
+      Some((artist, title)) => (Some(artist.trim()), title.trim()),
+      None => (None, rest.trim()),
+    };
+    if let Some(artist) = artist.filter(|a| !a.is_empty()) {
+      fill(&mut self.artist, artist);
+    }
+    if self.title.is_empty() {
+      self.title = if title.is_empty() {
+        stem.into_owned()
+      } else {
+        title.to_owned()
+      };
+    }
+  }
+}
+
+/// Get symphonia to work out what kind of file this is.
+pub fn open_format(path: &Path) -> eyre::Result<ProbeResult> {
+  let file = File::open(path)?;
+  let mss = MediaSourceStream::new(Box::new(file), Default::default());
+  let mut hint = Hint::new();
+  if let Some(ext) = path.extension() {
+    hint.with_extension(&ext.to_string_lossy());
+  }
+  let probed = symphonia::default::get_probe().format(
+    &hint,
+    mss,
+    &FormatOptions::default(),
+    &MetadataOptions::default(),
+  )?;
+  Ok(probed)
+}
+
+fn probe(path: &Path) -> eyre::Result<(TrackMeta, Option<CueSheet>)> {
+  let mut probed = open_format(path)?;
+
+  let mut meta = TrackMeta::default();
+  let mut cue = None;
+  if let Some(tags) = probed.format.metadata().current() {
+    meta.fill_from_tags(tags);
+    cue = CueSheet::from_tags(tags);
+  }
+  // Stuff like ID3v2 that's stuck on the front of the container
+  if let Some(tags) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+    meta.fill_from_tags(tags);
+    cue = cue.or_else(|| CueSheet::from_tags(tags));
+  }
+
+  if let Some(track) = probed.format.default_track() {
+    let params = &track.codec_params;
+    meta.duration_secs = match (params.n_frames, params.time_base) {
+      (Some(frames), Some(time_base)) => {
+        let time = time_base.calc_time(frames);
+        Some(time.seconds as f64 + time.frac)
+      }
+      (Some(frames), None) => {
+        params.sample_rate.map(|rate| frames as f64 / rate as f64)
+      }
+      _ => None,
+    };
+    if cue.is_none() {
+      cue = params
+        .sample_rate
+        .and_then(|rate| CueSheet::from_cues(probed.format.cues(), rate));
+    }
+  }
+
+  Ok((meta, cue))
+}
+
+fn fill(field: &mut Option<String>, value: &str) {
+  if field.is_none() {
+    *field = Some(value.to_owned());
+  }
+}
+
+/// For `3/12` and `2003-05-01` and the like.
+pub(super) fn leading_number(value: &str) -> Option<u32> {
+  let digits = value
+    .trim()
+    .chars()
+    .take_while(char::is_ascii_digit)
+    .collect::<String>();
+  digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn from_filename(name: &str) -> TrackMeta {
+    let mut meta = TrackMeta::default();
+    meta.fill_from_filename(Path::new(name));
+    meta
+  }
+
+  #[test]
+  fn filenames_with_track_numbers() {
+    let meta = from_filename("/music/01 - Some Band - Some Song.flac");
+    assert_eq!(meta.track_number, Some(1));
+    assert_eq!(meta.artist.as_deref(), Some("Some Band"));
+    assert_eq!(meta.title, "Some Song");
+
+    let meta = from_filename("07 Some Song.mp3");
+    assert_eq!(meta.track_number, Some(7));
+    assert_eq!(meta.artist, None);
+    assert_eq!(meta.title, "Some Song");
+
+    let meta = from_filename("3. Some Song.ogg");
+    assert_eq!(meta.track_number, Some(3));
+    assert_eq!(meta.title, "Some Song");
+
+    let meta = from_filename("12_Some Song.ogg");
+    assert_eq!(meta.track_number, Some(12));
+    assert_eq!(meta.title, "Some Song");
+  }
+
+  #[test]
+  fn numbers_in_titles_are_left_alone() {
+    let meta = from_filename("99 Luftballons.mp3");
+    assert_eq!(meta.track_number, None);
+    assert_eq!(meta.title, "99 Luftballons");
+
+    let meta = from_filename("1999.flac");
+    assert_eq!(meta.track_number, None);
+    assert_eq!(meta.title, "1999");
+
+    // Just a number is all there is to go on
+    let meta = from_filename("04.flac");
+    assert_eq!(meta.track_number, Some(4));
+    assert_eq!(meta.title, "04");
+  }
+
+  #[test]
+  fn tags_win_over_the_filename() {
+    let mut meta = TrackMeta {
+      title: "Tagged".to_owned(),
+      artist: Some("Tagged Band".to_owned()),
+      track_number: Some(5),
+      ..Default::default()
+    };
+    meta.fill_from_filename(Path::new("01 - Other Band - Other Song.flac"));
+    assert_eq!(meta.track_number, Some(5));
+    assert_eq!(meta.artist.as_deref(), Some("Tagged Band"));
+    assert_eq!(meta.title, "Tagged");
+  }
+
+  #[test]
+  fn leading_numbers() {
+    assert_eq!(leading_number("3/12"), Some(3));
+    assert_eq!(leading_number(" 2003-05-01"), Some(2003));
+    assert_eq!(leading_number("7"), Some(7));
+    assert_eq!(leading_number("Side A"), None);
+    assert_eq!(leading_number(""), None);
+  }
+}
@@ -1,30 +1,70 @@
+mod cue;
+mod metadata;
+mod playlist;
+mod repeat;
+mod replaygain;
+mod session;
+mod shuffle;
 mod track;
 
+pub use cue::*;
+pub use metadata::*;
+pub use playlist::*;
+pub use repeat::*;
+pub use replaygain::*;
+pub use session::*;
+pub use shuffle::*;
 pub use track::*;
 
 use creek::{FileInfo, ReadDiskStream, SymphoniaDecoder, SymphoniaDecoderInfo};
-use symphonia::core::codecs::CodecParameters;
-
-/// Generator for tracks.
-#[derive(Debug, Clone)]
-pub struct Playlist {
-  name: String,
-  tracks: Vec<Track>,
-}
+use symphonia::core::{codecs::CodecParameters, meta::StandardTagKey};
 
 #[derive(derive_debug::Dbg)]
 pub struct CurrentlyPlayingTrack {
   pub track: Track,
+  /// From the start of the track, not the file
   pub playhead: usize,
+  pub bounds: TrackBounds,
   #[dbg(placeholder = "...")]
   pub file_info: FileInfo<SymphoniaDecoderInfo>,
 }
 
+impl CurrentlyPlayingTrack {
+  /// Look up a tag in the file's metadata
+  pub fn tag(&self, key: StandardTagKey) -> Option<String> {
+    let metadata = self.file_info.params.metadata.as_ref()?;
+    metadata
+      .tags()
+      .iter()
+      .find(|tag| tag.std_key == Some(key))
+      .map(|tag| tag.value.to_string())
+  }
+
+  pub fn is_same_album(&self, other: &CurrentlyPlayingTrack) -> bool {
+    self.track.is_same_album(&other.track)
+  }
+
+  /// Whether this is in the middle of going through an album, going by
+  /// what's around it in the queue.
+  pub fn in_album_order(
+    &self,
+    prev: Option<&CurrentlyPlayingTrack>,
+    next: Option<&Track>,
+  ) -> bool {
+    prev.is_some_and(|prev| prev.is_same_album(self))
+      || next.is_some_and(|next| next.is_same_album(&self.track))
+  }
+}
+
 // The audio player needs to live on another thread so communicate via messages
 
 #[derive(derive_debug::Dbg)]
 pub enum MsgThreadToUi {
+  /// Ran out of track with nothing lined up after it
   FinishedTrack,
+  /// Moved on to the stream sent with `EnqueueNext`
+  StartedNext,
+  /// From the start of the track
   PlayheadPos(usize),
   Stop,
   Buffering,
@@ -32,11 +72,31 @@ pub enum MsgThreadToUi {
 
 #[derive(derive_debug::Dbg)]
 pub enum MsgUiToThread {
-  StartNewTrack(#[dbg(placeholder = "...")] ReadDiskStream<SymphoniaDecoder>),
+  StartNewTrack {
+    #[dbg(placeholder = "...")]
+    stream: ReadDiskStream<SymphoniaDecoder>,
+    bounds: TrackBounds,
+    /// Normalization gain, on top of the volume
+    gain: f32,
+    /// Or start out paused
+    playing: bool,
+  },
+  /// Line up a stream to play as soon as the current one ends
+  EnqueueNext {
+    #[dbg(placeholder = "...")]
+    stream: ReadDiskStream<SymphoniaDecoder>,
+    bounds: TrackBounds,
+    /// Whether it's allowed to crossfade into this one
+    crossfade: bool,
+    gain: f32,
+  },
+  /// Forget whatever was sent with `EnqueueNext`
+  ClearNext,
 
   Resume,
   Pause,
   Stop,
+  /// From the start of the track
   SeekTo(usize),
 
   SetLooping(bool),
@@ -0,0 +1,186 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{TrackLocator, TrackSpan};
+
+/// Generator for tracks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Playlist {
+  pub name: String,
+  pub tracks: Vec<PlaylistEntry>,
+}
+
+/// One track in a playlist.
+///
+/// The locator is what finds the file again if it's been moved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+  pub path: PathBuf,
+  pub locator: Option<TrackLocator>,
+  /// Which part of the file, for tracks from a CUE sheet
+  #[serde(default)]
+  pub span: Option<TrackSpan>,
+}
+
+impl Playlist {
+  pub fn add(&mut self, entries: impl IntoIterator<Item = PlaylistEntry>) {
+    self.tracks.extend(entries);
+  }
+
+  pub fn remove(&mut self, idx: usize) {
+    if idx < self.tracks.len() {
+      self.tracks.remove(idx);
+    }
+  }
+
+  /// Take the track at `from` out and put it back in at `to`.
+  pub fn reorder(&mut self, from: usize, to: usize) {
+    if from >= self.tracks.len() || to >= self.tracks.len() {
+      return;
+    }
+    let entry = self.tracks.remove(from);
+    self.tracks.insert(to, entry);
+  }
+}
+
+/// Every playlist, saved next to the config.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Playlists {
+  playlists: Vec<Playlist>,
+  #[serde(skip)]
+  dirty: bool,
+}
+
+impl Playlists {
+  pub fn open(path: &Path) -> Playlists {
+    let src = match fs::read_to_string(path) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!(
+          "Could not open playlists {:?}, starting fresh: {}",
+          path, err
+        );
+        return Playlists::default();
+      }
+    };
+    match ron::from_str(&src) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!(
+          "Could not parse playlists {:?}, starting fresh: {}",
+          path, err
+        );
+        Playlists::default()
+      }
+    }
+  }
+
+  /// Only actually writes anything if something changed.
+  pub fn save(&mut self, path: &Path) {
+    if !self.dirty {
+      return;
+    }
+    let src = match ron::ser::to_string_pretty(self, Default::default()) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not serialize playlists: {}", err);
+        return;
+      }
+    };
+    if let Err(err) = fs::write(path, src.as_bytes()) {
+      warn!("Could not save playlists to {:?}: {}", path, err);
+      return;
+    }
+    self.dirty = false;
+  }
+
+  pub fn len(&self) -> usize {
+    self.playlists.len()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Playlist> {
+    self.playlists.iter()
+  }
+
+  pub fn get(&self, idx: usize) -> Option<&Playlist> {
+    self.playlists.get(idx)
+  }
+
+  /// Anything done through this counts as a change that needs saving.
+  pub fn get_mut(&mut self, idx: usize) -> Option<&mut Playlist> {
+    self.dirty = true;
+    self.playlists.get_mut(idx)
+  }
+
+  /// Make a new empty playlist and return its index.
+  ///
+  /// If the name's taken a number goes on the end.
+  pub fn create(&mut self, name: &str) -> usize {
+    let name = self.unique_name(name, None);
+    self.playlists.push(Playlist {
+      name,
+      tracks: Vec::new(),
+    });
+    self.dirty = true;
+    self.playlists.len() - 1
+  }
+
+  /// Add a playlist that came from somewhere else and return its index.
+  pub fn insert(&mut self, mut playlist: Playlist) -> usize {
+    playlist.name = self.unique_name(&playlist.name, None);
+    self.playlists.push(playlist);
+    self.dirty = true;
+    self.playlists.len() - 1
+  }
+
+  pub fn rename(&mut self, idx: usize, name: &str) {
+    let name = self.unique_name(name, Some(idx));
+    if let Some(playlist) = self.playlists.get_mut(idx) {
+      playlist.name = name;
+      self.dirty = true;
+    }
+  }
+
+  pub fn delete(&mut self, idx: usize) {
+    if idx < self.playlists.len() {
+      self.playlists.remove(idx);
+      self.dirty = true;
+    }
+  }
+
+  /// Point entries for files that moved at where they are now.
+  pub fn follow_moves(&mut self, moves: &[(PathBuf, PathBuf)]) {
+    for playlist in self.playlists.iter_mut() {
+      for entry in playlist.tracks.iter_mut() {
+        if let Some((_, to)) =
+          moves.iter().find(|(from, _)| *from == entry.path)
+        {
+          entry.path = to.clone();
+          self.dirty = true;
+        }
+      }
+    }
+  }
+
+  fn unique_name(&self, name: &str, ignoring: Option<usize>) -> String {
+    let taken = |candidate: &str| {
+      self
+        .playlists
+        .iter()
+        .enumerate()
+        .any(|(i, p)| Some(i) != ignoring && p.name == candidate)
+    };
+    if !taken(name) {
+      return name.to_owned();
+    }
+    (2..)
+      .map(|n| format!("{} {}", name, n))
+      .find(|candidate| !taken(candidate))
+      .unwrap()
+  }
+}
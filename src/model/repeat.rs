@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use crate::emoji;
+
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum RepeatMode {
+  #[default]
+  Off,
+  /// Go round the current track until told otherwise. The audio thread does
+  /// the looping so there's no gap
+  One,
+  /// Put tracks back on the end of the queue once they're done
+  All,
+}
+
+impl RepeatMode {
+  /// What clicking the button goes to.
+  pub fn cycle(self) -> RepeatMode {
+    match self {
+      RepeatMode::Off => RepeatMode::All,
+      RepeatMode::All => RepeatMode::One,
+      RepeatMode::One => RepeatMode::Off,
+    }
+  }
+
+  pub fn icon(self) -> &'static str {
+    match self {
+      RepeatMode::Off | RepeatMode::All => emoji::REPEAT,
+      RepeatMode::One => emoji::REPEAT_ONE,
+    }
+  }
+
+  pub fn describe(self) -> &'static str {
+    match self {
+      RepeatMode::Off => "Repeat: off",
+      RepeatMode::One => "Repeat: this track",
+      RepeatMode::All => "Repeat: the whole queue",
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cycle_goes_through_everything() {
+    let mut mode = RepeatMode::default();
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+      seen.push(mode);
+      mode = mode.cycle();
+    }
+    assert_eq!(seen, [RepeatMode::Off, RepeatMode::All, RepeatMode::One]);
+    // and back round to the start
+    assert_eq!(mode, RepeatMode::Off);
+  }
+}
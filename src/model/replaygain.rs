@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use symphonia::core::meta::StandardTagKey;
+
+use super::CurrentlyPlayingTrack;
+
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum ReplayGainMode {
+  Off,
+  Track,
+  Album,
+  /// Album gain when the queue is going through an album in order,
+  /// track gain otherwise.
+  #[default]
+  Auto,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplayGainSettings {
+  pub mode: ReplayGainMode,
+  /// Added on top of whatever the tags say, in dB
+  pub preamp_db: f32,
+  /// Turn the gain down if the peak tag says it would clip
+  pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainSettings {
+  fn default() -> Self {
+    Self {
+      mode: ReplayGainMode::default(),
+      preamp_db: 0.0,
+      prevent_clipping: true,
+    }
+  }
+}
+
+/// What the ReplayGain tags on a file say.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGainTags {
+  pub track_gain_db: Option<f32>,
+  pub track_peak: Option<f32>,
+  pub album_gain_db: Option<f32>,
+  pub album_peak: Option<f32>,
+}
+
+impl ReplayGainTags {
+  pub fn read(track: &CurrentlyPlayingTrack) -> Self {
+    let get = |key| track.tag(key).as_deref().and_then(parse_leading_float);
+    Self {
+      track_gain_db: get(StandardTagKey::ReplayGainTrackGain),
+      track_peak: get(StandardTagKey::ReplayGainTrackPeak),
+      album_gain_db: get(StandardTagKey::ReplayGainAlbumGain),
+      album_peak: get(StandardTagKey::ReplayGainAlbumPeak),
+    }
+  }
+}
+
+impl ReplayGainSettings {
+  /// The linear gain to play a track at.
+  pub fn gain(&self, tags: &ReplayGainTags, in_album_order: bool) -> f32 {
+    let use_album = match self.mode {
+      ReplayGainMode::Off => return 1.0,
+      ReplayGainMode::Track => false,
+      ReplayGainMode::Album => true,
+      ReplayGainMode::Auto => in_album_order,
+    };
+
+    // Fall back to the other one if the one we want is missing
+    let (gain_db, peak) = if use_album && tags.album_gain_db.is_some() {
+      (tags.album_gain_db, tags.album_peak)
+    } else if tags.track_gain_db.is_some() {
+      (tags.track_gain_db, tags.track_peak)
+    } else {
+      (tags.album_gain_db, tags.album_peak)
+    };
+
+    let db = gain_db.unwrap_or(0.0) + self.preamp_db;
+    let mut gain = 10f32.powf(db / 20.0);
+    if let (true, Some(peak)) = (self.prevent_clipping, peak) {
+      if peak > 0.0 {
+        gain = gain.min(1.0 / peak);
+      }
+    }
+    gain
+  }
+}
+
+/// Tags look like `-6.54 dB` or `0.998871`
+fn parse_leading_float(s: &str) -> Option<f32> {
+  s.split_whitespace().next()?.parse().ok()
+}
@@ -0,0 +1,74 @@
+use std::{fs, path::Path};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::PlaylistEntry;
+
+/// What was going on when we last closed, so we can pick up where we left
+/// off.
+///
+/// The shuffle and repeat modes live in the config.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Session {
+  pub queue: Vec<PlaylistEntry>,
+  /// What had been played, the most recent last
+  #[serde(default)]
+  pub history: Vec<PlaylistEntry>,
+  #[serde(default)]
+  pub now_playing: Option<SavedTrack>,
+  /// The seed and the queue from before it was shuffled, if it was
+  #[serde(default)]
+  pub unshuffled: Option<(u64, Vec<PlaylistEntry>)>,
+}
+
+/// The track that was playing, and how far into it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedTrack {
+  pub entry: PlaylistEntry,
+  /// In frames from the start of the track, not the file
+  pub playhead: usize,
+  /// It always comes back paused, but this is how it was left
+  pub playing: bool,
+}
+
+impl Session {
+  /// None if there isn't one, or it can't be read.
+  pub fn open(path: &Path) -> Option<Session> {
+    let src = match fs::read_to_string(path) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not open session {:?}, starting fresh: {}", path, err);
+        return None;
+      }
+    };
+    match ron::from_str(&src) {
+      Ok(it) => Some(it),
+      Err(err) => {
+        warn!(
+          "Could not parse session {:?}, starting fresh: {}",
+          path, err
+        );
+        None
+      }
+    }
+  }
+
+  pub fn save(&self, path: &Path) {
+    let src = match ron::ser::to_string_pretty(self, Default::default()) {
+      Ok(it) => it,
+      Err(err) => {
+        warn!("Could not serialize session: {}", err);
+        return;
+      }
+    };
+    if let Err(err) = fs::write(path, src.as_bytes()) {
+      warn!("Could not save session to {:?}: {}", path, err);
+    }
+  }
+
+  /// Whether there's anything in it worth restoring.
+  pub fn is_empty(&self) -> bool {
+    self.queue.is_empty() && self.now_playing.is_none()
+  }
+}
@@ -0,0 +1,568 @@
+//! Shuffling the queue in ways that don't play three songs off the same
+//! album in a row.
+
+use std::{
+  collections::{BinaryHeap, HashMap, VecDeque},
+  hash::Hash,
+  path::{Path, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::Track;
+
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum ShuffleMode {
+  #[default]
+  Off,
+  /// Any order at all
+  Random,
+  /// Tracks by the same artist, and off the same album, are spread out as
+  /// evenly as they can be
+  Balanced,
+  /// Albums in a random order, but each one played through in order
+  Album,
+}
+
+impl ShuffleMode {
+  /// What clicking the button goes to.
+  pub fn cycle(self) -> ShuffleMode {
+    match self {
+      ShuffleMode::Off => ShuffleMode::Random,
+      ShuffleMode::Random => ShuffleMode::Balanced,
+      ShuffleMode::Balanced => ShuffleMode::Album,
+      ShuffleMode::Album => ShuffleMode::Off,
+    }
+  }
+
+  pub fn describe(self) -> &'static str {
+    match self {
+      ShuffleMode::Off => "Shuffle: off",
+      ShuffleMode::Random => "Shuffle: random",
+      ShuffleMode::Balanced => "Shuffle: spread out artists and albums",
+      ShuffleMode::Album => "Shuffle: albums",
+    }
+  }
+}
+
+/// The queue as it was before it got shuffled, so turning shuffle off can
+/// put it back.
+#[derive(Debug, Clone)]
+pub struct ShuffleState {
+  /// The same seed on the same tracks always comes out the same
+  pub seed: u64,
+  original: Vec<Track>,
+}
+
+impl ShuffleState {
+  /// Shuffle `tracks`, remembering how they were. Does nothing if `mode` is
+  /// off.
+  pub fn start(
+    tracks: Vec<Track>,
+    mode: ShuffleMode,
+    seed: u64,
+  ) -> (Vec<Track>, Option<ShuffleState>) {
+    if mode == ShuffleMode::Off {
+      return (tracks, None);
+    }
+    let shuffled = shuffle(tracks.clone(), mode, seed);
+    let state = ShuffleState {
+      seed,
+      original: tracks,
+    };
+    (shuffled, Some(state))
+  }
+
+  /// Pick up a shuffle from before, going back to `original` when it's
+  /// turned off.
+  pub fn resume(seed: u64, original: Vec<Track>) -> ShuffleState {
+    ShuffleState { seed, original }
+  }
+
+  /// How the queue was before it got shuffled.
+  pub fn original(&self) -> &[Track] {
+    &self.original
+  }
+
+  /// Work tracks that turned up after the queue got shuffled into it at
+  /// random spots, and remember them as going on the end of the original
+  /// order.
+  ///
+  /// When shuffling by album, new albums go in whole between the others.
+  pub fn add(
+    &mut self,
+    queue: &mut VecDeque<Track>,
+    tracks: Vec<Track>,
+    mode: ShuffleMode,
+  ) {
+    if tracks.is_empty() {
+      return;
+    }
+    // Different every time something's added, so it doesn't all go in the
+    // same few spots
+    let seed = self.seed ^ Rng::new(self.original.len() as u64).next_u64();
+    let mut rng = Rng::new(seed);
+    self.original.extend(tracks.iter().cloned());
+
+    let tracks = shuffle(tracks, mode, seed);
+    let blocks = match mode {
+      ShuffleMode::Album => group_by(tracks, album_key),
+      _ => tracks.into_iter().map(|track| vec![track]).collect(),
+    };
+    let spots = (0..=queue.len())
+      .filter(|i| {
+        mode != ShuffleMode::Album
+          || *i == 0
+          || *i == queue.len()
+          || album_key(&queue[i - 1]) != album_key(&queue[*i])
+      })
+      .collect::<Vec<_>>();
+    let mut placed = blocks
+      .into_iter()
+      .map(|block| (spots[rng.below(spots.len())], block))
+      .collect::<Vec<_>>();
+    // Stable, so blocks going in the same spot stay shuffled
+    placed.sort_by_key(|(at, _)| *at);
+
+    let mut placed = placed.into_iter().peekable();
+    for (i, track) in std::mem::take(queue).into_iter().enumerate() {
+      while let Some((_, block)) = placed.next_if(|(at, _)| *at == i) {
+        queue.extend(block);
+      }
+      queue.push_back(track);
+    }
+    queue.extend(placed.flat_map(|(_, block)| block));
+  }
+
+  /// Put whatever's left of the queue back in the order it was in before
+  /// shuffling.
+  ///
+  /// Tracks that have been played or removed since are left out, and ones
+  /// added since go on the end in the order they're in now.
+  pub fn restore(&self, queue: Vec<Track>) -> Vec<Track> {
+    // The same track can be in there more than once
+    let mut left = HashMap::<_, usize>::new();
+    for track in &queue {
+      *left.entry(key(track)).or_default() += 1;
+    }
+    let mut out = Vec::with_capacity(queue.len());
+    for track in &self.original {
+      if let Some(count) = left.get_mut(&key(track)).filter(|it| **it > 0) {
+        *count -= 1;
+        out.push(track.clone());
+      }
+    }
+    // The newcomers are whichever copies are left over at the back
+    let mut extra = Vec::new();
+    for track in queue.into_iter().rev() {
+      if let Some(count) = left.get_mut(&key(&track)).filter(|it| **it > 0) {
+        *count -= 1;
+        extra.push(track);
+      }
+    }
+    out.extend(extra.into_iter().rev());
+    out
+  }
+}
+
+/// Something to seed a new shuffle with.
+pub fn fresh_seed() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |it| it.as_nanos() as u64)
+}
+
+/// Put `tracks` in a new order. The same seed always gives the same order.
+pub fn shuffle(
+  mut tracks: Vec<Track>,
+  mode: ShuffleMode,
+  seed: u64,
+) -> Vec<Track> {
+  let mut rng = Rng::new(seed);
+  match mode {
+    ShuffleMode::Off => tracks,
+    ShuffleMode::Random => {
+      rng.shuffle(&mut tracks);
+      tracks
+    }
+    ShuffleMode::Balanced => {
+      // Spread the artists out, and each artist's albums out within that
+      let spread_out = spread(tracks, artist_key, &mut rng, |group, rng| {
+        spread(group, album_key, rng, |mut group, rng| {
+          rng.shuffle(&mut group);
+          group
+        })
+      });
+      separate(spread_out, artist_key)
+    }
+    ShuffleMode::Album => {
+      let mut albums = group_by(tracks, album_key);
+      rng.shuffle(&mut albums);
+      albums
+        .into_iter()
+        .flat_map(|mut album| {
+          // Stable, so tracks without numbers stay how they were queued
+          album.sort_by_key(|track| {
+            (track.meta.disc_number, track.meta.track_number)
+          });
+          album
+        })
+        .collect()
+    }
+  }
+}
+
+/// Give every group's tracks evenly spaced spots from 0 to 1, starting
+/// somewhere random, then play them in the order of their spots.
+///
+/// A group of 4 gets one somewhere in each quarter, so nothing bunches up
+/// however big the other groups are.
+fn spread<K: Hash + Eq>(
+  tracks: Vec<Track>,
+  key: impl Fn(&Track) -> K,
+  rng: &mut Rng,
+  order_group: impl Fn(Vec<Track>, &mut Rng) -> Vec<Track>,
+) -> Vec<Track> {
+  let mut placed = Vec::with_capacity(tracks.len());
+  for group in group_by(tracks, key) {
+    let group = order_group(group, rng);
+    let n = group.len() as f64;
+    let offset = rng.unit() / n;
+    for (i, track) in group.into_iter().enumerate() {
+      // A little wobble so groups the same size don't go in lockstep. It's
+      // small enough that the group stays in order
+      let jitter = (rng.unit() - 0.5) * 0.2 / n;
+      placed.push((offset + i as f64 / n + jitter, track));
+    }
+  }
+  placed.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+  placed.into_iter().map(|(_, track)| track).collect()
+}
+
+/// Go through `tracks` in order, but skip ahead whenever the next one is in
+/// the same group as the one before, unless there's no way to avoid that.
+///
+/// Spreading things out usually gets it right already, but it can leave two
+/// next to each other where two groups' spots wobble past each other.
+fn separate<K: Hash + Eq>(
+  tracks: Vec<Track>,
+  key: impl Fn(&Track) -> K,
+) -> Vec<Track> {
+  // Number the groups so they're cheap to compare
+  let mut ids = HashMap::new();
+  let groups = tracks
+    .iter()
+    .map(|track| {
+      let next = ids.len();
+      *ids.entry(key(track)).or_insert(next)
+    })
+    .collect::<Vec<_>>();
+  let mut left = vec![0; ids.len()];
+  for group in &groups {
+    left[*group] += 1;
+  }
+  // Biggest on top. Counts only ever go down, so anything that doesn't
+  // match `left` any more is out of date and can be skipped
+  let mut biggest = left
+    .iter()
+    .enumerate()
+    .map(|(group, count)| (*count, group))
+    .collect::<BinaryHeap<_>>();
+
+  let mut tracks = tracks.into_iter().map(Some).collect::<Vec<_>>();
+  let mut out = Vec::with_capacity(tracks.len());
+  // Everything before this has been taken
+  let mut first = 0;
+  let mut prev = None;
+  for remaining in (1..=tracks.len()).rev() {
+    let untaken = || (first..tracks.len()).filter(|i| tracks[*i].is_some());
+    while biggest.peek().is_some_and(|(count, g)| left[*g] != *count) {
+      biggest.pop();
+    }
+    // A group with more than half of what's left has to go now, or there
+    // won't be enough others left to go between the rest of it
+    let must = biggest
+      .peek()
+      .filter(|(count, g)| *count > remaining / 2 && prev != Some(*g))
+      .and_then(|(_, must)| untaken().find(|i| groups[*i] == *must));
+    let pick = must
+      .or_else(|| untaken().find(|i| prev != Some(groups[*i])))
+      .or_else(|| untaken().next())
+      .unwrap();
+
+    out.push(tracks[pick].take().unwrap());
+    let group = groups[pick];
+    left[group] -= 1;
+    if left[group] > 0 {
+      biggest.push((left[group], group));
+    }
+    prev = Some(group);
+    while first < tracks.len() && tracks[first].is_none() {
+      first += 1;
+    }
+  }
+  out
+}
+
+/// Groups in the order they first turn up, keeping the order inside each.
+fn group_by<K: Hash + Eq>(
+  tracks: Vec<Track>,
+  key: impl Fn(&Track) -> K,
+) -> Vec<Vec<Track>> {
+  let mut index = HashMap::new();
+  let mut groups = Vec::<Vec<Track>>::new();
+  for track in tracks {
+    let i = *index.entry(key(&track)).or_insert_with(|| {
+      groups.push(Vec::new());
+      groups.len() - 1
+    });
+    groups[i].push(track);
+  }
+  groups
+}
+
+fn artist_key(track: &Track) -> Option<String> {
+  let meta = &track.meta;
+  meta.album_artist.clone().or_else(|| meta.artist.clone())
+}
+
+/// Going by folder if we don't know the album, like `Track::is_same_album`
+fn album_key(track: &Track) -> (Option<String>, Option<PathBuf>) {
+  match &track.meta.album {
+    Some(album) => (Some(album.clone()), None),
+    None => (None, track.path.parent().map(Path::to_path_buf)),
+  }
+}
+
+/// Which track in which file, for telling copies apart from other tracks.
+fn key(track: &Track) -> (PathBuf, Option<u64>) {
+  let start = track.span.map(|span| span.start_secs.to_bits());
+  (track.path.clone(), start)
+}
+
+/// SplitMix64. Nothing fancy, but it's plenty for putting songs in order and
+/// it's the same everywhere for the same seed.
+struct Rng(u64);
+
+impl Rng {
+  fn new(seed: u64) -> Rng {
+    Rng(seed)
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+
+  /// From 0 up to but not including 1.
+  fn unit(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  /// From 0 up to but not including `n`.
+  fn below(&mut self, n: usize) -> usize {
+    (self.unit() * n as f64) as usize
+  }
+
+  /// Fisher-Yates.
+  fn shuffle<T>(&mut self, items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+      items.swap(i, self.below(i + 1));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::TrackMeta;
+
+  const MODES: [ShuffleMode; 3] = [
+    ShuffleMode::Random,
+    ShuffleMode::Balanced,
+    ShuffleMode::Album,
+  ];
+
+  fn track(artist: &str, album: &str, disc: u32, number: u32) -> Track {
+    Track {
+      path: PathBuf::from(format!(
+        "/music/{}/{}/{}-{:02}.flac",
+        artist, album, disc, number
+      )),
+      meta: TrackMeta {
+        title: format!("{} {}", album, number),
+        artist: Some(artist.to_owned()),
+        album: Some(album.to_owned()),
+        disc_number: Some(disc),
+        track_number: Some(number),
+        ..Default::default()
+      },
+      span: None,
+    }
+  }
+
+  /// A few artists with a few albums each, in a jumbled order.
+  fn library() -> Vec<Track> {
+    let mut tracks = Vec::new();
+    for (artist, albums, per_album) in
+      [("A", 3, 4), ("B", 1, 5), ("C", 2, 3), ("D", 1, 2)]
+    {
+      for album in 0..albums {
+        for number in (1..=per_album).rev() {
+          let disc = number % 2 + 1;
+          tracks.push(track(
+            artist,
+            &format!("{}{}", artist, album),
+            disc,
+            number,
+          ));
+        }
+      }
+    }
+    tracks
+  }
+
+  fn paths(tracks: &[Track]) -> Vec<&Path> {
+    tracks.iter().map(|track| track.path.as_path()).collect()
+  }
+
+  fn artist(track: &Track) -> &str {
+    track.meta.artist.as_deref().unwrap()
+  }
+
+  #[test]
+  fn same_seed_same_order() {
+    for mode in MODES {
+      let first = shuffle(library(), mode, 1234);
+      let again = shuffle(library(), mode, 1234);
+      let other = shuffle(library(), mode, 4321);
+      assert_eq!(paths(&first), paths(&again), "{:?}", mode);
+      assert_ne!(paths(&first), paths(&other), "{:?}", mode);
+
+      // Nothing lost or doubled up
+      let mut sorted = paths(&first);
+      sorted.sort();
+      let library = library();
+      let mut expected = paths(&library);
+      expected.sort();
+      assert_eq!(sorted, expected, "{:?}", mode);
+    }
+  }
+
+  #[test]
+  fn albums_play_through_in_order() {
+    for seed in 0..20 {
+      let shuffled = shuffle(library(), ShuffleMode::Album, seed);
+      let albums = group_by(shuffled.clone(), album_key);
+      // Each album is all in one go
+      let runs = shuffled
+        .windows(2)
+        .filter(|pair| album_key(&pair[0]) != album_key(&pair[1]))
+        .count();
+      assert_eq!(runs + 1, albums.len(), "seed {}", seed);
+      for album in albums {
+        let order = album
+          .iter()
+          .map(|track| (track.meta.disc_number, track.meta.track_number))
+          .collect::<Vec<_>>();
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(order, sorted, "seed {}", seed);
+      }
+    }
+  }
+
+  #[test]
+  fn balanced_keeps_artists_apart() {
+    // 12 by A out of 25 is as many as can be kept apart
+    for seed in 0..200 {
+      let shuffled = shuffle(library(), ShuffleMode::Balanced, seed);
+      for pair in shuffled.windows(2) {
+        assert_ne!(artist(&pair[0]), artist(&pair[1]), "seed {}", seed);
+      }
+    }
+
+    // When it can't be helped, it still only doubles up where it has to
+    let mut tracks =
+      (1..=5).map(|n| track("A", "A0", 1, n)).collect::<Vec<_>>();
+    tracks.push(track("B", "B0", 1, 1));
+    let shuffled = shuffle(tracks, ShuffleMode::Balanced, 7);
+    let doubled = shuffled
+      .windows(2)
+      .filter(|pair| artist(&pair[0]) == artist(&pair[1]))
+      .count();
+    assert_eq!(doubled, 3);
+  }
+
+  #[test]
+  fn restore_puts_back_what_is_left() {
+    let a = track("A", "A0", 1, 1);
+    let b = track("A", "A0", 1, 2);
+    let c = track("B", "B0", 1, 1);
+    let d = track("C", "C0", 1, 1);
+    let e = track("D", "D0", 1, 1);
+    // `a` is in there twice
+    let original = vec![a.clone(), b.clone(), a.clone(), c.clone()];
+    let (shuffled, state) =
+      ShuffleState::start(original.clone(), ShuffleMode::Random, 99);
+    let mut state = state.unwrap();
+    assert_eq!(paths(&state.original), paths(&original));
+
+    // One `a` gets played and `c` gets taken out, then `d` turns up from a
+    // scan and `e` gets put on the end by hand
+    let mut queue = shuffled.into_iter().collect::<VecDeque<_>>();
+    let played = queue.iter().position(|t| t.path == a.path).unwrap();
+    queue.remove(played);
+    queue.retain(|t| t.path != c.path);
+    state.add(&mut queue, vec![d.clone()], ShuffleMode::Random);
+    queue.push_back(e.clone());
+
+    // There's no telling which `a` got played, so it's the first one left
+    let restored = state.restore(queue.into_iter().collect());
+    assert_eq!(paths(&restored), paths(&[a, b, d, e]));
+  }
+
+  #[test]
+  fn added_tracks_get_shuffled_in() {
+    let (queue, state) = ShuffleState::start(library(), ShuffleMode::Random, 5);
+    let mut state = state.unwrap();
+    let mut queue = VecDeque::from(queue);
+    let before = queue.clone();
+    let added = (1..=10).map(|n| track("E", "E0", 1, n)).collect::<Vec<_>>();
+    state.add(&mut queue, added, ShuffleMode::Random);
+
+    assert_eq!(queue.len(), before.len() + 10);
+    // Not just stuck on the end
+    assert!(queue.iter().take(before.len()).any(|t| artist(t) == "E"));
+    // Everything that was there is still in the same order
+    let kept = queue
+      .iter()
+      .filter(|t| artist(t) != "E")
+      .collect::<Vec<_>>();
+    assert!(kept.iter().zip(&before).all(|(a, b)| a.path == b.path));
+    assert_eq!(state.original.len(), before.len() + 10);
+
+    // Whole albums go in between other albums
+    let (queue, state) = ShuffleState::start(library(), ShuffleMode::Album, 5);
+    let mut state = state.unwrap();
+    let mut queue = VecDeque::from(queue);
+    let albums = (0..3)
+      .flat_map(|album| {
+        (1..=3).map(move |n| track("E", &format!("E{}", album), 1, n))
+      })
+      .collect();
+    state.add(&mut queue, albums, ShuffleMode::Album);
+    let queue = queue.into_iter().collect::<Vec<_>>();
+    let runs = queue
+      .windows(2)
+      .filter(|pair| album_key(&pair[0]) != album_key(&pair[1]))
+      .count();
+    assert_eq!(runs + 1, group_by(queue, album_key).len());
+  }
+}
@@ -1,10 +1,129 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::errors::Error as SymphoniaError;
+
+use super::{open_format, TrackMeta};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+/// How much of the audio gets hashed for a `TrackLocator`. A few seconds of
+/// anything is plenty to tell it apart, and reading the whole file takes
+/// ages on big ones.
+const FINGERPRINT_BYTES: usize = 1 << 20;
 
 /// A definite track with a known location on disc.
 #[derive(Debug, Clone)]
 pub struct Track {
   pub path: PathBuf,
+  pub meta: TrackMeta,
+  /// If this is only part of the file, like from a CUE sheet
+  pub span: Option<TrackSpan>,
+}
+
+/// Where in a file a track is, in seconds so it doesn't depend on the
+/// sample rate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TrackSpan {
+  pub start_secs: f64,
+  /// None means it goes on until the end of the file
+  pub end_secs: Option<f64>,
+}
+
+/// Which frames of a file are the track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackBounds {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl TrackSpan {
+  pub fn bounds(&self, sample_rate: u32, num_frames: usize) -> TrackBounds {
+    let frame = |secs: f64| {
+      ((secs * sample_rate as f64).round() as usize).min(num_frames)
+    };
+    let start = frame(self.start_secs);
+    let end = self.end_secs.map_or(num_frames, frame).max(start);
+    TrackBounds { start, end }
+  }
+}
+
+impl TrackBounds {
+  pub fn len(&self) -> usize {
+    self.end - self.start
+  }
+}
+
+impl Track {
+  /// Where this is in a file with the given rate and length.
+  pub fn bounds(&self, sample_rate: u32, num_frames: usize) -> TrackBounds {
+    match self.span {
+      Some(span) => span.bounds(sample_rate, num_frames),
+      None => TrackBounds {
+        start: 0,
+        end: num_frames,
+      },
+    }
+  }
+
+  pub fn is_same_album(&self, other: &Track) -> bool {
+    match (&self.meta.album, &other.meta.album) {
+      (Some(here), Some(there)) => here == there,
+      // Go by folder if we don't know
+      _ => self.path.parent() == other.path.parent(),
+    }
+  }
 }
 
 /// Uniquely identifies a track on disc, via diagnostic information
-pub struct TrackLocator {}
+///
+/// This only looks at the audio itself, so it stays the same when the file
+/// is moved, renamed, or has its tags edited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrackLocator {
+  /// FNV-1a of the first `FINGERPRINT_BYTES` of the audio track's packets
+  pub fingerprint: u64,
+  /// In frames, if the container says
+  pub duration: Option<u64>,
+}
+
+impl TrackLocator {
+  /// Read the start of the audio and work it out.
+  ///
+  /// This doesn't decode anything, just hashes the packets as they are.
+  pub fn compute(path: &Path) -> eyre::Result<TrackLocator> {
+    let mut probed = open_format(path)?;
+    let Some(track) = probed.format.default_track() else {
+      eyre::bail!("{:?} doesn't have any audio in it", path);
+    };
+    let track_id = track.id;
+
+    let mut out = TrackLocator {
+      fingerprint: FNV_OFFSET,
+      duration: track.codec_params.n_frames,
+    };
+    let mut hashed = 0;
+    while hashed < FINGERPRINT_BYTES {
+      let packet = match probed.format.next_packet() {
+        Ok(it) => it,
+        Err(SymphoniaError::IoError(err))
+          if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+          break
+        }
+        Err(err) => return Err(err.into()),
+      };
+      if packet.track_id() != track_id {
+        continue;
+      }
+      let buf = packet.buf();
+      let buf = &buf[..buf.len().min(FINGERPRINT_BYTES - hashed)];
+      for byte in buf {
+        out.fingerprint ^= *byte as u64;
+        out.fingerprint = out.fingerprint.wrapping_mul(FNV_PRIME);
+      }
+      hashed += buf.len();
+    }
+    Ok(out)
+  }
+}
@@ -0,0 +1,669 @@
+//! Reading and writing playlists other players made.
+//!
+//! Supports extended M3U/M3U8, PLS and XSPF.
+//!
+//! Tracks from CUE sheets are only part of their file, so which part gets
+//! written in a way other players skip over.
+
+use std::{
+  fmt::Write as _,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use eyre::{bail, eyre, WrapErr};
+
+use crate::{
+  library::LibraryIndex,
+  model::{Playlist, PlaylistEntry, TrackSpan},
+};
+
+/// M3U line saying which part of the next file the entry is.
+const M3U_SPAN: &str = "#EXTDECOMPOSER-SPAN:";
+/// The same as an XSPF `<meta>`.
+const XSPF_SPAN: &str = "<meta rel=\"decomposer:span\">";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+  M3u,
+  M3u8,
+  Pls,
+  Xspf,
+}
+
+impl PlaylistFormat {
+  pub fn from_path(path: &Path) -> Option<PlaylistFormat> {
+    let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+    match ext.as_str() {
+      "m3u" => Some(PlaylistFormat::M3u),
+      "m3u8" => Some(PlaylistFormat::M3u8),
+      "pls" => Some(PlaylistFormat::Pls),
+      "xspf" => Some(PlaylistFormat::Xspf),
+      _ => None,
+    }
+  }
+}
+
+/// One entry as the file had it, before we go looking for it.
+#[derive(Debug, Clone, Default)]
+struct RawEntry {
+  location: String,
+  title: Option<String>,
+  artist: Option<String>,
+  span: Option<TrackSpan>,
+}
+
+/// A playlist that came in from a file.
+#[derive(Debug)]
+pub struct Imported {
+  pub playlist: Playlist,
+  /// Entries we couldn't find anywhere, as the file had them
+  pub unresolved: Vec<String>,
+}
+
+/// Read a playlist file and find its tracks in the library.
+pub fn import(path: &Path, library: &LibraryIndex) -> eyre::Result<Imported> {
+  let Some(format) = PlaylistFormat::from_path(path) else {
+    bail!("{:?} isn't a playlist format we know", path);
+  };
+  let bytes =
+    fs::read(path).wrap_err_with(|| format!("Could not read {:?}", path))?;
+  let src = match String::from_utf8(bytes) {
+    Ok(it) => it,
+    // Plain .m3u is traditionally Latin-1
+    Err(err) => err.into_bytes().iter().map(|b| *b as char).collect(),
+  };
+  let raw = match format {
+    PlaylistFormat::M3u | PlaylistFormat::M3u8 => parse_m3u(&src),
+    PlaylistFormat::Pls => parse_pls(&src),
+    PlaylistFormat::Xspf => parse_xspf(&src)?,
+  };
+
+  let base = path.parent().unwrap_or(Path::new(""));
+  let name = path
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().into_owned())
+    .unwrap_or_else(|| "Imported".to_owned());
+  let mut out = Imported {
+    playlist: Playlist {
+      name,
+      tracks: Vec::new(),
+    },
+    unresolved: Vec::new(),
+  };
+  for entry in raw {
+    match resolve(&entry, base, library) {
+      Some(path) => out
+        .playlist
+        .tracks
+        .push(library.playlist_entry(&path, entry.span)),
+      None => out.unresolved.push(entry.location),
+    }
+  }
+  Ok(out)
+}
+
+/// Write a playlist out in whatever format the extension says.
+pub fn export(
+  playlist: &Playlist,
+  path: &Path,
+  library: &LibraryIndex,
+) -> eyre::Result<()> {
+  let Some(format) = PlaylistFormat::from_path(path) else {
+    bail!("{:?} isn't a playlist format we know", path);
+  };
+  let src = match format {
+    PlaylistFormat::M3u | PlaylistFormat::M3u8 => write_m3u(playlist, library),
+    PlaylistFormat::Pls => write_pls(playlist, library),
+    PlaylistFormat::Xspf => write_xspf(playlist, library),
+  };
+  fs::write(path, src.as_bytes())
+    .wrap_err_with(|| format!("Could not write {:?}", path))
+}
+
+/// Find where an entry is on disc.
+///
+/// Tries the path itself first, then anything in the library that looks
+/// like it.
+fn resolve(
+  entry: &RawEntry,
+  base: &Path,
+  library: &LibraryIndex,
+) -> Option<PathBuf> {
+  let path = location_to_path(&entry.location, base);
+  if let Some(path) = &path {
+    if path.is_file() {
+      return Some(path.canonicalize().unwrap_or_else(|_| path.clone()));
+    }
+  }
+
+  let file_name = path
+    .as_ref()
+    .and_then(|path| path.file_name())
+    .map(|name| name.to_string_lossy().into_owned());
+  library.find_like(
+    entry.title.as_deref(),
+    entry.artist.as_deref(),
+    file_name.as_deref(),
+  )
+}
+
+/// Relative paths are relative to the playlist file.
+fn location_to_path(location: &str, base: &Path) -> Option<PathBuf> {
+  let location = location.trim();
+  if let Some(rest) = location.strip_prefix("file://") {
+    // file:///home/... or file://localhost/home/...
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    return Some(PathBuf::from(percent_decode(rest)));
+  }
+  if location.contains("://") {
+    // Streams and such; nothing we can play
+    return None;
+  }
+  // Windows players love backslashes
+  let location = if cfg!(windows) {
+    location.to_owned()
+  } else {
+    location.replace('\\', "/")
+  };
+  let path = PathBuf::from(location);
+  Some(if path.is_absolute() {
+    path
+  } else {
+    base.join(path)
+  })
+}
+
+fn parse_m3u(src: &str) -> Vec<RawEntry> {
+  let mut out = Vec::new();
+  let mut pending = RawEntry::default();
+  for line in src.lines() {
+    let line = line.trim_start_matches('\u{FEFF}').trim();
+    if line.is_empty() {
+      continue;
+    }
+    if let Some(info) = line.strip_prefix("#EXTINF:") {
+      // #EXTINF:123,Artist - Title
+      if let Some((_, name)) = info.split_once(',') {
+        match name.split_once(" - ") {
+          Some((artist, title)) => {
+            pending.artist = Some(artist.trim().to_owned());
+            pending.title = Some(title.trim().to_owned());
+          }
+          None => pending.title = Some(name.trim().to_owned()),
+        }
+      }
+      continue;
+    }
+    if let Some(span) = line.strip_prefix(M3U_SPAN) {
+      pending.span = parse_span(span);
+      continue;
+    }
+    if line.starts_with('#') {
+      continue;
+    }
+    pending.location = line.to_owned();
+    out.push(std::mem::take(&mut pending));
+  }
+  out
+}
+
+fn parse_pls(src: &str) -> Vec<RawEntry> {
+  // Entries are numbered and can come in any order
+  let mut out = Vec::<(u32, RawEntry)>::new();
+  for line in src.lines() {
+    let Some((key, value)) = line.trim().split_once('=') else {
+      continue;
+    };
+    let key = key.trim().to_ascii_lowercase();
+    let (field, number) = if let Some(n) = key.strip_prefix("file") {
+      ("file", n)
+    } else if let Some(n) = key.strip_prefix("title") {
+      ("title", n)
+    } else if let Some(n) = key.strip_prefix("span") {
+      ("span", n)
+    } else {
+      continue;
+    };
+    let Ok(number) = number.parse::<u32>() else {
+      continue;
+    };
+    let idx = match out.iter().position(|(n, _)| *n == number) {
+      Some(idx) => idx,
+      None => {
+        out.push((number, RawEntry::default()));
+        out.len() - 1
+      }
+    };
+    let entry = &mut out[idx].1;
+    match field {
+      "file" => entry.location = value.trim().to_owned(),
+      "span" => entry.span = parse_span(value),
+      _ => entry.title = Some(value.trim().to_owned()),
+    }
+  }
+  out.sort_by_key(|(n, _)| *n);
+  out
+    .into_iter()
+    .map(|(_, entry)| entry)
+    .filter(|entry| !entry.location.is_empty())
+    .collect()
+}
+
+/// Not a real XML parser, but XSPF is simple enough not to need one.
+fn parse_xspf(src: &str) -> eyre::Result<Vec<RawEntry>> {
+  if !src.contains("<playlist") {
+    bail!("doesn't look like XSPF");
+  }
+  let mut out = Vec::new();
+  let mut rest = src;
+  while let Some(start) = rest.find("<track>") {
+    let after = &rest[start + "<track>".len()..];
+    let end = after
+      .find("</track>")
+      .ok_or_else(|| eyre!("unclosed <track>"))?;
+    let body = &after[..end];
+    if let Some(location) = xml_element(body, "location") {
+      out.push(RawEntry {
+        location,
+        title: xml_element(body, "title"),
+        artist: xml_element(body, "creator"),
+        span: xml_between(body, XSPF_SPAN, "</meta>")
+          .and_then(|span| parse_span(&span)),
+      });
+    }
+    rest = &after[end..];
+  }
+  Ok(out)
+}
+
+fn xml_element(body: &str, name: &str) -> Option<String> {
+  xml_between(body, &format!("<{}>", name), &format!("</{}>", name))
+}
+
+fn xml_between(body: &str, open: &str, close: &str) -> Option<String> {
+  let start = body.find(open)? + open.len();
+  let end = body[start..].find(close)? + start;
+  Some(xml_unescape(body[start..end].trim()))
+}
+
+/// `start,end` in seconds, where the end can be left off.
+fn parse_span(src: &str) -> Option<TrackSpan> {
+  let (start, end) = match src.trim().split_once(',') {
+    Some((start, end)) => (start, Some(end.trim())),
+    None => (src.trim(), None),
+  };
+  Some(TrackSpan {
+    start_secs: start.trim().parse().ok()?,
+    end_secs: match end.filter(|end| !end.is_empty()) {
+      Some(end) => Some(end.parse().ok()?),
+      None => None,
+    },
+  })
+}
+
+fn format_span(span: &TrackSpan) -> String {
+  match span.end_secs {
+    Some(end) => format!("{},{}", span.start_secs, end),
+    None => span.start_secs.to_string(),
+  }
+}
+
+fn xml_unescape(s: &str) -> String {
+  s.replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&apos;", "'")
+    .replace("&amp;", "&")
+}
+
+fn xml_escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn percent_decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let hex = bytes
+      .get(i + 1..i + 3)
+      .and_then(|h| std::str::from_utf8(h).ok())
+      .and_then(|h| u8::from_str_radix(h, 16).ok());
+    match (bytes[i], hex) {
+      (b'%', Some(byte)) => {
+        out.push(byte);
+        i += 3;
+      }
+      (byte, _) => {
+        out.push(byte);
+        i += 1;
+      }
+    }
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+fn file_uri(path: &Path) -> String {
+  let mut out = String::from("file://");
+  for byte in path.to_string_lossy().bytes() {
+    match byte {
+      b'A'..=b'Z'
+      | b'a'..=b'z'
+      | b'0'..=b'9'
+      | b'/'
+      | b'-'
+      | b'_'
+      | b'.'
+      | b'~' => out.push(byte as char),
+      _ => {
+        let _ = write!(out, "%{:02X}", byte);
+      }
+    }
+  }
+  out
+}
+
+/// Title, artist and seconds, for the formats that want them.
+fn describe(
+  entry: &PlaylistEntry,
+  library: &LibraryIndex,
+) -> (String, Option<String>, Option<f64>) {
+  match library.meta(entry) {
+    Some(meta) => (
+      meta.title.clone(),
+      meta.display_artist().map(str::to_owned),
+      meta.duration_secs,
+    ),
+    None => (
+      entry
+        .path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default(),
+      None,
+      None,
+    ),
+  }
+}
+
+fn write_m3u(playlist: &Playlist, library: &LibraryIndex) -> String {
+  let mut out = String::from("#EXTM3U\n");
+  for entry in &playlist.tracks {
+    let (title, artist, secs) = describe(entry, library);
+    let secs = secs.map_or(-1, |secs| secs.round() as i64);
+    let name = match artist {
+      Some(artist) => format!("{} - {}", artist, title),
+      None => title,
+    };
+    let _ = writeln!(out, "#EXTINF:{},{}", secs, name);
+    if let Some(span) = &entry.span {
+      let _ = writeln!(out, "{}{}", M3U_SPAN, format_span(span));
+    }
+    let _ = writeln!(out, "{}", entry.path.display());
+  }
+  out
+}
+
+fn write_pls(playlist: &Playlist, library: &LibraryIndex) -> String {
+  let mut out = String::from("[playlist]\n");
+  for (i, entry) in playlist.tracks.iter().enumerate() {
+    let n = i + 1;
+    let (title, _, secs) = describe(entry, library);
+    let secs = secs.map_or(-1, |secs| secs.round() as i64);
+    let _ = writeln!(out, "File{}={}", n, entry.path.display());
+    let _ = writeln!(out, "Title{}={}", n, title);
+    let _ = writeln!(out, "Length{}={}", n, secs);
+    if let Some(span) = &entry.span {
+      let _ = writeln!(out, "Span{}={}", n, format_span(span));
+    }
+  }
+  let _ = writeln!(out, "NumberOfEntries={}", playlist.tracks.len());
+  out.push_str("Version=2\n");
+  out
+}
+
+fn write_xspf(playlist: &Playlist, library: &LibraryIndex) -> String {
+  let mut out = String::from(concat!(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+    "<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+  ));
+  let _ = writeln!(out, "  <title>{}</title>", xml_escape(&playlist.name));
+  out.push_str("  <trackList>\n");
+  for entry in &playlist.tracks {
+    let (title, artist, secs) = describe(entry, library);
+    out.push_str("    <track>\n");
+    let _ = writeln!(
+      out,
+      "      <location>{}</location>",
+      xml_escape(&file_uri(&entry.path))
+    );
+    let _ = writeln!(out, "      <title>{}</title>", xml_escape(&title));
+    if let Some(artist) = artist {
+      let _ = writeln!(out, "      <creator>{}</creator>", xml_escape(&artist));
+    }
+    if let Some(secs) = secs {
+      let _ = writeln!(
+        out,
+        "      <duration>{}</duration>",
+        (secs * 1000.0).round() as u64
+      );
+    }
+    if let Some(span) = &entry.span {
+      let _ = writeln!(out, "      {}{}</meta>", XSPF_SPAN, format_span(span));
+    }
+    out.push_str("    </track>\n");
+  }
+  out.push_str("  </trackList>\n</playlist>\n");
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn locations(raw: &[RawEntry]) -> Vec<&str> {
+    raw.iter().map(|entry| entry.location.as_str()).collect()
+  }
+
+  #[test]
+  fn m3u_takes_titles_from_extinf() {
+    let raw = parse_m3u(concat!(
+      "\u{FEFF}#EXTM3U\n",
+      "#EXTINF:123,Some Band - Some Song\n",
+      "01 Some Song.flac\n",
+      "\n",
+      "#EXTINF:-1,Just A Title\n",
+      "#EXTVLCOPT:something\n",
+      "02.mp3\n",
+      "03.ogg\n",
+    ));
+    assert_eq!(locations(&raw), ["01 Some Song.flac", "02.mp3", "03.ogg"]);
+    assert_eq!(raw[0].artist.as_deref(), Some("Some Band"));
+    assert_eq!(raw[0].title.as_deref(), Some("Some Song"));
+    assert_eq!(raw[1].artist, None);
+    assert_eq!(raw[1].title.as_deref(), Some("Just A Title"));
+    // The info only goes with the line right after it
+    assert_eq!(raw[2].title, None);
+  }
+
+  #[test]
+  #[cfg(not(windows))]
+  fn relative_paths_are_next_to_the_playlist() {
+    let base = Path::new("/lists");
+    assert_eq!(
+      location_to_path("Album/01.flac", base),
+      Some(PathBuf::from("/lists/Album/01.flac"))
+    );
+    assert_eq!(
+      location_to_path("..\\Music\\Album\\01.flac", base),
+      Some(PathBuf::from("/lists/../Music/Album/01.flac"))
+    );
+    assert_eq!(
+      location_to_path("/music/01.flac", base),
+      Some(PathBuf::from("/music/01.flac"))
+    );
+  }
+
+  #[test]
+  fn file_uris_get_percent_decoded() {
+    let base = Path::new("/lists");
+    assert_eq!(
+      location_to_path("file:///music/My%20Album/caf%C3%A9.flac", base),
+      Some(PathBuf::from("/music/My Album/café.flac"))
+    );
+    assert_eq!(
+      location_to_path("file://localhost/music/100%25.flac", base),
+      Some(PathBuf::from("/music/100%.flac"))
+    );
+    // Not a real escape, so it's left alone
+    assert_eq!(
+      location_to_path("file:///music/50%off.flac", base),
+      Some(PathBuf::from("/music/50%off.flac"))
+    );
+    assert_eq!(location_to_path("http://radio.example/live", base), None);
+  }
+
+  #[test]
+  fn pls_goes_by_entry_number() {
+    let raw = parse_pls(concat!(
+      "[playlist]\n",
+      "File2=second.flac\n",
+      "Title2=Second\n",
+      "file10=tenth.flac\n",
+      "File1=first.flac\n",
+      "Title1=First\n",
+      "Length1=200\n",
+      "Title3=Nowhere to be found\n",
+      "NumberOfEntries=3\n",
+      "Version=2\n",
+    ));
+    assert_eq!(locations(&raw), ["first.flac", "second.flac", "tenth.flac"]);
+    assert_eq!(raw[0].title.as_deref(), Some("First"));
+    assert_eq!(raw[1].title.as_deref(), Some("Second"));
+    assert_eq!(raw[2].title, None);
+  }
+
+  #[test]
+  fn xspf_unescapes_entities() {
+    let raw = parse_xspf(concat!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+      "<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+      "  <trackList>\n",
+      "    <track>\n",
+      "      <location>file:///music/Tom%20&amp;%20Jerry.flac</location>\n",
+      "      <title>Rock &amp; Roll &lt;Live&gt;</title>\n",
+      "      <creator>The &quot;Band&quot; &amp;amp; Co</creator>\n",
+      "    </track>\n",
+      "    <track><title>No location</title></track>\n",
+      "  </trackList>\n",
+      "</playlist>\n",
+    ))
+    .unwrap();
+    assert_eq!(locations(&raw), ["file:///music/Tom%20&%20Jerry.flac"]);
+    assert_eq!(raw[0].title.as_deref(), Some("Rock & Roll <Live>"));
+    // Only unescaped the once
+    assert_eq!(raw[0].artist.as_deref(), Some("The \"Band\" &amp; Co"));
+
+    assert!(parse_xspf("<html></html>").is_err());
+    assert!(parse_xspf("<playlist><track><location>x").is_err());
+  }
+
+  #[test]
+  fn what_gets_written_reads_back_the_same() {
+    let paths = [
+      "/music/Tom & Jerry/01 Café <Live>.flac",
+      "/music/100% plain.mp3",
+    ];
+    let playlist = Playlist {
+      name: "Mixed & Matched".to_owned(),
+      tracks: paths
+        .iter()
+        .map(|path| PlaylistEntry {
+          path: PathBuf::from(path),
+          locator: None,
+          span: None,
+        })
+        .collect(),
+    };
+    // Nothing's in the library, so titles come from the file names
+    let library = LibraryIndex::default();
+
+    let formats = [
+      (write_m3u(&playlist, &library), PlaylistFormat::M3u),
+      (write_pls(&playlist, &library), PlaylistFormat::Pls),
+      (write_xspf(&playlist, &library), PlaylistFormat::Xspf),
+    ];
+    for (src, format) in formats {
+      let raw = match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => parse_m3u(&src),
+        PlaylistFormat::Pls => parse_pls(&src),
+        PlaylistFormat::Xspf => parse_xspf(&src).unwrap(),
+      };
+      let read = raw
+        .iter()
+        .map(|entry| location_to_path(&entry.location, Path::new("/")))
+        .collect::<Vec<_>>();
+      let expected = paths
+        .iter()
+        .map(|path| Some(PathBuf::from(path)))
+        .collect::<Vec<_>>();
+      assert_eq!(read, expected, "{:?}", format);
+      assert_eq!(raw[0].title.as_deref(), Some("01 Café <Live>"));
+    }
+  }
+
+  #[test]
+  fn cue_tracks_keep_their_spans() {
+    let spans = [
+      None,
+      Some(TrackSpan {
+        start_secs: 0.0,
+        end_secs: Some(181.5),
+      }),
+      Some(TrackSpan {
+        start_secs: 181.5,
+        end_secs: None,
+      }),
+    ];
+    let playlist = Playlist {
+      name: "Live".to_owned(),
+      tracks: spans
+        .iter()
+        .map(|span| PlaylistEntry {
+          path: PathBuf::from("/music/Live/whole show.flac"),
+          locator: None,
+          span: *span,
+        })
+        .collect(),
+    };
+    let library = LibraryIndex::default();
+
+    let written = [
+      parse_m3u(&write_m3u(&playlist, &library)),
+      parse_pls(&write_pls(&playlist, &library)),
+      parse_xspf(&write_xspf(&playlist, &library)).unwrap(),
+    ];
+    for raw in written {
+      let read = raw.iter().map(|entry| entry.span).collect::<Vec<_>>();
+      assert_eq!(read, spans);
+    }
+
+    // Only goes with the entry right after it, like #EXTINF
+    let raw = parse_m3u(
+      "#EXTDECOMPOSER-SPAN:10,20.5
+a.flac
+b.flac
+",
+    );
+    let expected = TrackSpan {
+      start_secs: 10.0,
+      end_secs: Some(20.5),
+    };
+    assert_eq!(raw[0].span, Some(expected));
+    assert_eq!(raw[1].span, None);
+  }
+}
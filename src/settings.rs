@@ -9,10 +9,28 @@ use log::{info, warn};
 use ron::ser::PrettyConfig;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+  audio::{
+    CrossfadeSettings, DownmixMode, OutputDeviceChoice, ResampleQuality,
+    UpmixMode,
+  },
+  library::{LibraryRoot, ScanSettings},
+  model::{RepeatMode, ReplayGainSettings, ShuffleMode},
+};
+
 pub const CONFIG_LOCATION_KEY: &str = "config-location";
 
 pub const DEFAULT_CONFIG_LOCATION: &str = ".decomposer.ron";
 
+/// Lives in the same folder as the config.
+pub const LOUDNESS_CACHE_NAME: &str = ".decomposer-loudness.ron";
+/// Also lives in the same folder as the config.
+pub const LIBRARY_INDEX_NAME: &str = ".decomposer-library.ron";
+/// Also lives in the same folder as the config.
+pub const PLAYLISTS_NAME: &str = ".decomposer-playlists.ron";
+/// Also lives in the same folder as the config.
+pub const SESSION_NAME: &str = ".decomposer-session.ron";
+
 #[derive(Debug)]
 pub struct DecomposerConfig {
   cfg_location: PathBuf,
@@ -21,8 +39,35 @@ pub struct DecomposerConfig {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct DecomposerConfigSerde {
-  library_root: PathBuf,
+  /// Only in configs from before there could be more than one root. It gets
+  /// moved into `library_roots` when the config is opened
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  library_root: Option<PathBuf>,
+  #[serde(default)]
+  library_roots: Vec<LibraryRoot>,
   volume: f32,
+  #[serde(default)]
+  downmix: DownmixMode,
+  #[serde(default)]
+  resample_quality: ResampleQuality,
+  #[serde(default)]
+  crossfade: CrossfadeSettings,
+  #[serde(default)]
+  replay_gain: ReplayGainSettings,
+  /// None means whatever the system default is
+  #[serde(default)]
+  output_device: Option<OutputDeviceChoice>,
+  /// None means however many the device wants
+  #[serde(default)]
+  output_channels: Option<u16>,
+  #[serde(default)]
+  upmix: UpmixMode,
+  #[serde(default)]
+  scan: ScanSettings,
+  #[serde(default)]
+  repeat: RepeatMode,
+  #[serde(default)]
+  shuffle: ShuffleMode,
 }
 
 impl DecomposerConfig {
@@ -45,7 +90,7 @@ impl DecomposerConfig {
       }
     };
 
-    let cfg: DecomposerConfigSerde = 'ok: {
+    let mut cfg: DecomposerConfigSerde = 'ok: {
       let cfg_src = match fs::read_to_string(&path) {
         Ok(it) => it,
         Err(err) => {
@@ -70,6 +115,14 @@ impl DecomposerConfig {
       }
     };
 
+    if let Some(root) = cfg.library_root.take() {
+      info!(
+        "Moving the old library root {:?} into the list of roots",
+        &root
+      );
+      cfg.library_roots.insert(0, LibraryRoot::new(root));
+    }
+
     Ok(DecomposerConfig {
       cfg_location: path,
       inner: cfg,
@@ -98,17 +151,78 @@ impl DecomposerConfig {
     &self.cfg_location
   }
 
+  /// Where to put another file that goes next to the config.
+  pub fn sibling_location(&self, name: &str) -> PathBuf {
+    self.cfg_location.with_file_name(name)
+  }
+
   pub fn volume(&mut self) -> &mut f32 {
     &mut self.inner.volume
   }
 
-  pub fn library_root(&self) -> &Path {
-    &self.inner.library_root
+  pub fn library_roots(&self) -> &[LibraryRoot] {
+    &self.inner.library_roots
   }
 
   pub fn copy_volume(&self) -> f32 {
     self.inner.volume
   }
+
+  pub fn downmix(&self) -> &DownmixMode {
+    &self.inner.downmix
+  }
+
+  pub fn resample_quality(&self) -> ResampleQuality {
+    self.inner.resample_quality
+  }
+
+  pub fn crossfade(&self) -> &CrossfadeSettings {
+    &self.inner.crossfade
+  }
+
+  pub fn replay_gain(&self) -> &ReplayGainSettings {
+    &self.inner.replay_gain
+  }
+
+  pub fn output_device(&self) -> Option<&OutputDeviceChoice> {
+    self.inner.output_device.as_ref()
+  }
+
+  pub fn set_output_device(&mut self, device: Option<OutputDeviceChoice>) {
+    self.inner.output_device = device;
+  }
+
+  pub fn output_channels(&self) -> Option<u16> {
+    self.inner.output_channels
+  }
+
+  pub fn set_output_channels(&mut self, channels: Option<u16>) {
+    self.inner.output_channels = channels;
+  }
+
+  pub fn upmix(&self) -> UpmixMode {
+    self.inner.upmix
+  }
+
+  pub fn scan(&self) -> &ScanSettings {
+    &self.inner.scan
+  }
+
+  pub fn repeat(&self) -> RepeatMode {
+    self.inner.repeat
+  }
+
+  pub fn set_repeat(&mut self, repeat: RepeatMode) {
+    self.inner.repeat = repeat;
+  }
+
+  pub fn shuffle(&self) -> ShuffleMode {
+    self.inner.shuffle
+  }
+
+  pub fn set_shuffle(&mut self, shuffle: ShuffleMode) {
+    self.inner.shuffle = shuffle;
+  }
 }
 
 /// Try to return the default
@@ -124,8 +238,19 @@ fn default_config(ud: &UserDirs) -> eyre::Result<DecomposerConfigSerde> {
   let volume = 1.0;
 
   let out = DecomposerConfigSerde {
-    library_root: root,
+    library_root: None,
+    library_roots: vec![LibraryRoot::new(root)],
     volume,
+    downmix: DownmixMode::default(),
+    resample_quality: ResampleQuality::default(),
+    crossfade: CrossfadeSettings::default(),
+    replay_gain: ReplayGainSettings::default(),
+    output_device: None,
+    output_channels: None,
+    upmix: UpmixMode::default(),
+    scan: ScanSettings::default(),
+    repeat: RepeatMode::default(),
+    shuffle: ShuffleMode::default(),
   };
   warn!("Had to regenerate config from defaults: {:#?}", &out);
   Ok(out)
@@ -1,40 +1,114 @@
 use std::{
+  collections::HashSet,
+  ffi::OsStr,
   fs,
   path::{Path, PathBuf},
 };
 
-use log::warn;
+use log::{debug, warn};
 use symphonia::core::units::{Time, TimeStamp};
 
+use crate::library::ScanFilter;
+
 pub fn get_all_children<P: AsRef<Path>>(path: P) -> IterAllChildren {
+  let mut out = IterAllChildren {
+    root: "nope".into(),
+    to_explore: Vec::new(),
+    to_yield: Vec::new(),
+    filter: None,
+    options: WalkOptions::default(),
+    visited: HashSet::new(),
+  };
   match path.as_ref().canonicalize() {
-    Ok(path) => IterAllChildren {
-      root: path.to_path_buf(),
-      to_explore: vec![path.to_path_buf()],
-      to_yield: Vec::new(),
-    },
+    Ok(path) => {
+      if let Some(id) = fs::metadata(&path).ok().and_then(|m| dir_id(&path, &m))
+      {
+        out.visited.insert(id);
+      }
+      out.root = path.clone();
+      out.to_explore.push((path, 0));
+    }
     Err(ono) => {
       warn!(
         "Could not canonicalize {:?} for get_all_children: {}",
         path.as_ref(),
         ono
       );
-      IterAllChildren {
-        root: "nope".into(),
-        to_explore: Vec::new(),
-        to_yield: Vec::new(),
-      }
     }
   }
+  out
 }
 
+/// How `IterAllChildren` gets around.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkOptions {
+  /// Otherwise symlinks are left alone, files and folders both
+  pub follow_symlinks: bool,
+  /// Whether to look at things starting with a `.`
+  pub include_hidden: bool,
+  /// How many folders down from where it started to go. None is as far as
+  /// it goes
+  pub max_depth: Option<usize>,
+}
+
+impl Default for WalkOptions {
+  fn default() -> Self {
+    Self {
+      follow_symlinks: true,
+      include_hidden: false,
+      max_depth: None,
+    }
+  }
+}
+
+impl WalkOptions {
+  /// Whether a walk from `root` would ever get to `path`, going by the
+  /// names alone.
+  pub fn reaches(&self, root: &Path, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+      return false;
+    };
+    let folders = relative.components().count().saturating_sub(1);
+    if self.max_depth.is_some_and(|max| folders > max) {
+      return false;
+    }
+    self.include_hidden || !relative.iter().any(is_hidden)
+  }
+}
+
+/// Different on every platform, but the same for every way of getting to a
+/// folder.
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
 pub struct IterAllChildren {
   /// Mostly for printing information
   root: PathBuf,
-  /// directories to explore
-  to_explore: Vec<PathBuf>,
+  /// directories to explore, and how deep they are
+  to_explore: Vec<(PathBuf, usize)>,
   /// Children to yield
   to_yield: Vec<PathBuf>,
+  /// Everything gets through without one
+  filter: Option<ScanFilter>,
+  options: WalkOptions,
+  /// Every folder we've been in or are going to, so symlinks can't send us
+  /// round in circles
+  visited: HashSet<DirId>,
+}
+
+impl IterAllChildren {
+  /// Only go into folders and yield files the filter allows.
+  pub fn filtered(mut self, filter: ScanFilter) -> IterAllChildren {
+    self.filter = Some(filter);
+    self
+  }
+
+  pub fn with_options(mut self, options: WalkOptions) -> IterAllChildren {
+    self.options = options;
+    self
+  }
 }
 
 impl Iterator for IterAllChildren {
@@ -43,7 +117,10 @@ impl Iterator for IterAllChildren {
   fn next(&mut self) -> Option<Self::Item> {
     while self.to_yield.is_empty() {
       // If popping the explore is empty, exit
-      let explore = self.to_explore.pop()?;
+      let (explore, depth) = self.to_explore.pop()?;
+      if let Some(filter) = self.filter.as_mut() {
+        filter.enter_dir(&explore);
+      }
       let read_dir = match fs::read_dir(&explore) {
         Ok(it) => it,
         Err(err) => {
@@ -65,12 +142,50 @@ impl Iterator for IterAllChildren {
             continue;
           }
         };
-        let Ok(ty) = entry.file_type() else { continue };
+        if !self.options.include_hidden && is_hidden(&entry.file_name()) {
+          continue;
+        }
+        let Ok(mut ty) = entry.file_type() else {
+          continue;
+        };
 
         let full_path = explore.join(entry.path());
+        if ty.is_symlink() {
+          if !self.options.follow_symlinks {
+            continue;
+          }
+          ty = match fs::metadata(&full_path) {
+            Ok(it) => it.file_type(),
+            Err(err) => {
+              debug!("Skipping broken symlink {:?}: {}", &full_path, err);
+              continue;
+            }
+          };
+        }
+
+        let filter = self.filter.as_ref();
         if ty.is_dir() {
-          self.to_explore.push(full_path);
-        } else if ty.is_file() {
+          if self.options.max_depth.is_some_and(|max| depth >= max) {
+            continue;
+          }
+          if !filter.is_none_or(|f| f.allows_dir(&full_path)) {
+            continue;
+          }
+          let id = fs::metadata(&full_path)
+            .ok()
+            .and_then(|meta| dir_id(&full_path, &meta));
+          match id {
+            Some(id) if self.visited.insert(id) => {
+              self.to_explore.push((full_path, depth + 1));
+            }
+            Some(_) => {
+              debug!("Already been in {:?}, not going round again", &full_path)
+            }
+            None => warn!("Could not tell what folder {:?} is", &full_path),
+          }
+        } else if ty.is_file()
+          && filter.is_none_or(|f| f.allows_file(&full_path))
+        {
           self.to_yield.push(full_path);
         }
       }
@@ -80,6 +195,21 @@ impl Iterator for IterAllChildren {
   }
 }
 
+fn is_hidden(name: &OsStr) -> bool {
+  name.to_string_lossy().starts_with('.')
+}
+
+#[cfg(unix)]
+fn dir_id(_path: &Path, meta: &fs::Metadata) -> Option<DirId> {
+  use std::os::unix::fs::MetadataExt;
+  Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _meta: &fs::Metadata) -> Option<DirId> {
+  path.canonicalize().ok()
+}
+
 pub fn format_symphonia_time(time: Time) -> String {
   if time.seconds >= 60 * 60 {
     format!(
@@ -92,3 +222,102 @@ pub fn format_symphonia_time(time: Time) -> String {
     format!("{}:{:02}", time.seconds / 60, time.seconds % 60)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An empty folder of our own to make files in.
+  fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "decomposer-walk-{}-{}",
+      name,
+      std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.canonicalize().unwrap()
+  }
+
+  fn touch(root: &Path, path: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, "").unwrap();
+  }
+
+  fn walk(root: &Path, options: WalkOptions) -> Vec<String> {
+    let mut found = get_all_children(root)
+      .with_options(options)
+      .map(|path| {
+        let path = path.strip_prefix(root).unwrap();
+        path.to_string_lossy().into_owned()
+      })
+      .collect::<Vec<_>>();
+    found.sort();
+    found
+  }
+
+  #[test]
+  fn max_depth_counts_folders() {
+    let root = scratch("depth");
+    touch(&root, "a.flac");
+    touch(&root, "1/b.flac");
+    touch(&root, "1/2/c.flac");
+
+    let depth = |max_depth| WalkOptions {
+      max_depth,
+      ..Default::default()
+    };
+    assert_eq!(
+      walk(&root, depth(None)),
+      ["1/2/c.flac", "1/b.flac", "a.flac"]
+    );
+    assert_eq!(walk(&root, depth(Some(1))), ["1/b.flac", "a.flac"]);
+    assert_eq!(walk(&root, depth(Some(0))), ["a.flac"]);
+
+    assert!(depth(Some(1)).reaches(&root, &root.join("1/b.flac")));
+    assert!(!depth(Some(1)).reaches(&root, &root.join("1/2/c.flac")));
+    let _ = fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  fn hidden_things_are_skipped() {
+    let root = scratch("hidden");
+    touch(&root, "a.flac");
+    touch(&root, ".b.flac");
+    touch(&root, ".git/c.flac");
+
+    assert_eq!(walk(&root, WalkOptions::default()), ["a.flac"]);
+    let options = WalkOptions {
+      include_hidden: true,
+      ..Default::default()
+    };
+    assert_eq!(walk(&root, options), [".b.flac", ".git/c.flac", "a.flac"]);
+
+    let default = WalkOptions::default();
+    assert!(!default.reaches(&root, &root.join(".git/c.flac")));
+    assert!(options.reaches(&root, &root.join(".git/c.flac")));
+    let _ = fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn symlink_loops_only_go_round_once() {
+    let root = scratch("loop");
+    touch(&root, "Album/01.flac");
+    std::os::unix::fs::symlink(&root, root.join("Album/back up")).unwrap();
+    std::os::unix::fs::symlink(root.join("Album"), root.join("Again")).unwrap();
+
+    // Every folder gets walked once, whichever way it was got to first
+    let found = walk(&root, WalkOptions::default());
+    assert_eq!(found.len(), 1, "{:?}", found);
+    assert!(found[0].ends_with("01.flac"));
+
+    let options = WalkOptions {
+      follow_symlinks: false,
+      ..Default::default()
+    };
+    assert_eq!(walk(&root, options), ["Album/01.flac"]);
+    let _ = fs::remove_dir_all(&root);
+  }
+}